@@ -5,12 +5,12 @@ extern crate cas;
 #[macro_use]
 extern crate timeit;
 
-use cas::Kind;
-use cas::pool::ChunkSource;
+use cas::{Chunk, ChunkBuilder, Kind, Pipeline};
+use cas::pool::{ChunkSource, ChunkWrite};
 use cas::pool::{AdumpPool, FilePool, RamPool};
 use std::error;
 use std::fs::{self, File};
-use std::io::Read;
+use std::io::{self, Read};
 use std::path::Path;
 use std::result;
 
@@ -24,7 +24,7 @@ fn main() {
 
     let sec = timeit_loops!(1, {
         let mut pool = RamPool::new();
-        walk_tree(&mut pool, BASE).unwrap();
+        walk_tree(&mut pool, BASE, 0).unwrap();
     });
     println!("RamPool:: {}", sec);
 
@@ -32,7 +32,7 @@ fn main() {
     let sec = timeit_loops!(1, {
         FilePool::create("pool1").unwrap();
         let mut pool = FilePool::open("pool1").unwrap();
-        walk_tree(&mut pool, BASE).unwrap();
+        walk_tree(&mut pool, BASE, 0).unwrap();
     });
     println!("FilePool: {}", sec);
 
@@ -40,16 +40,28 @@ fn main() {
     let sec = timeit_loops!(1, {
         AdumpPool::new_builder("pool2").create().unwrap();
         let mut pool = AdumpPool::open("pool2").unwrap();
-        walk_tree(&mut pool, BASE).unwrap();
-        pool.flush().unwrap();
+        walk_tree(&mut pool, BASE, 0).unwrap();
     });
     println!("AdumpPool: {}", sec);
+
+    cleanup("pool3");
+    let sec = timeit_loops!(1, {
+        AdumpPool::new_builder("pool3").create().unwrap();
+        let mut pool = AdumpPool::open("pool3").unwrap();
+        walk_tree(&mut pool, BASE, 4).unwrap();
+    });
+    println!("AdumpPool (4 workers): {}", sec);
 }
 
-fn walk_tree<P: AsRef<Path>>(pool: &mut ChunkSource, tree: P) -> Result<()> {
-    let mut walk = Walker::new(pool);
-    walk.walk(tree.as_ref())?;
-    println!("Total:\n{:?}", walk.info);
+fn walk_tree<P: AsRef<Path>>(pool: &mut ChunkSource, tree: P, workers: usize) -> Result<()> {
+    let mut wr = pool.writer()?;
+    let info = {
+        let mut walk = Walker::new(&mut *wr, workers);
+        walk.walk(tree.as_ref())?;
+        walk.info
+    };
+    wr.commit()?;
+    println!("Total:\n{:?}", info);
     Ok(())
 }
 
@@ -61,7 +73,10 @@ fn cleanup<P: AsRef<Path>>(path: P) {
 }
 
 struct Walker<'a> {
-    pool: &'a mut ChunkSource,
+    pool: &'a mut ChunkWrite,
+
+    // If there are workers, chunks are built by this pipeline.
+    pipe: Option<Pipeline>,
 
     info: WalkInfo,
 }
@@ -77,9 +92,15 @@ struct WalkInfo {
 }
 
 impl<'a> Walker<'a> {
-    fn new(pool: &mut ChunkSource) -> Walker {
+    fn new(pool: &mut ChunkWrite, workers: usize) -> Walker {
+        let pipe = if workers > 0 {
+            Some(Pipeline::for_pool(workers, pool.reader()))
+        } else {
+            None
+        };
         Walker {
             pool: pool,
+            pipe: pipe,
             info: WalkInfo {
                 files: 0,
                 dirs: 0,
@@ -95,9 +116,12 @@ impl<'a> Walker<'a> {
     // don't keep any of this data, and the whole point here is to measure performance of the
     // pools.
     fn walk(&mut self, name: &Path) -> Result<()> {
-        self.pool.begin_writing()?;
         self.iwalk(name)?;
-        self.pool.flush()?;
+        if let Some(ref mut pipe) = self.pipe {
+            while let Some(ch) = pipe.pop() {
+                store(&mut *self.pool, &mut self.info, &ch?)?;
+            }
+        }
         Ok(())
     }
 
@@ -139,35 +163,39 @@ impl<'a> Walker<'a> {
     fn encode_file(&mut self, name: &Path) -> Result<()> {
         // print!("- {:?}", name);
         let mut f = File::open(name)?;
+        let alg = self.pool.reader().hash_alg();
+        let codec = self.pool.reader().codec();
+        let policy = self.pool.reader().compress_policy().clone();
 
         loop {
-            let mut buffer = vec![0u8; 256 * 1024];
-
-            let count = f.read(&mut buffer)?;
-            if count == 0 {
-                break;
-            }
-
-            buffer.truncate(count);
-            let ch = cas::Chunk::new_plain(Kind::new("blob").unwrap(), buffer);
-
-            self.info.chunks += 1;
-            // self.info.bytes += count as u64;
-
-            // let payload = match ch.zdata() {
-            // None => ch.data(),
-            // Some(zdata) => zdata,
-            // };
-            // self.info.bytes += payload.len() as u64;
-            //
-            if self.pool.contains_key(ch.oid())? {
-                self.info.dup_chunks += 1;
-                self.info.dup_bytes += count as u64;
-            } else {
-                self.pool.add(&ch)?;
-                self.info.chunks += 1;
-                self.info.bytes += count as u64;
-            }
+            let ch = match self.pipe {
+                Some(ref mut pipe) => {
+                    let mut data = Vec::with_capacity(256 * 1024);
+                    if (&mut f).take(256 * 1024).read_to_end(&mut data)? == 0 {
+                        break;
+                    }
+                    pipe.push(Kind::new("blob").unwrap(), data);
+                    while pipe.is_full() {
+                        let ch = pipe.pop().unwrap()?;
+                        store(&mut *self.pool, &mut self.info, &ch)?;
+                    }
+                    continue;
+                }
+                None => {
+                    let mut builder = ChunkBuilder::with_capacity(alg,
+                                                                  Kind::new("blob").unwrap(),
+                                                                  256 * 1024)
+                        .set_codec(codec)
+                        .set_policy(&policy);
+                    let count = io::copy(&mut (&mut f).take(256 * 1024), &mut builder)?;
+                    if count == 0 {
+                        break;
+                    }
+                    builder.finish()
+                }
+            };
+
+            store(&mut *self.pool, &mut self.info, &ch)?;
             // print!(".");
         }
 
@@ -177,3 +205,24 @@ impl<'a> Walker<'a> {
         Ok(())
     }
 }
+
+// Add a chunk to the pool, unless it is already present.
+fn store(pool: &mut ChunkWrite, info: &mut WalkInfo, ch: &Chunk) -> Result<()> {
+    let count = ch.data_len() as u64;
+
+    // let payload = match ch.zdata() {
+    // None => ch.data(),
+    // Some(zdata) => zdata,
+    // };
+    // info.bytes += payload.len() as u64;
+    //
+    if pool.reader().contains_key(ch.oid())? {
+        info.dup_chunks += 1;
+        info.dup_bytes += count;
+    } else {
+        pool.add(ch)?;
+        info.chunks += 1;
+        info.bytes += count;
+    }
+    Ok(())
+}
@@ -4,7 +4,7 @@ extern crate cas;
 
 // use cas::{Kind, Oid};
 use cas::pdump::HexDump;
-use cas::pool::ChunkSource;
+use cas::pool::ChunkRead;
 use cas::pool::adump::AdumpPool;
 
 fn main() {
@@ -0,0 +1,132 @@
+// Pool maintenance.
+
+extern crate cas;
+
+use cas::{Error, HashAlg};
+use cas::pool::{self, AdumpPool, CheckReport, ChunkRead, ChunkSource, FilePool, MigrateReport};
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let status = match args.get(0).map(|x| &x[..]) {
+        Some("check") if args.len() == 2 => check(&args[1]),
+        Some("salvage") if args.len() == 3 => salvage(&args[1], &args[2]),
+        Some("migrate") if args.len() == 4 && (args[3] == "file" || args[3] == "adump") => {
+            migrate(&args[1], &args[2], &args[3])
+        }
+        _ => {
+            usage();
+            2
+        }
+    };
+    process::exit(status);
+}
+
+fn usage() {
+    let _ = writeln!(io::stderr(), "usage: pool check <path>");
+    let _ = writeln!(io::stderr(), "       pool salvage <damaged> <new>");
+    let _ = writeln!(io::stderr(), "       pool migrate <src> <dest> file|adump");
+}
+
+// Check every chunk in the pool, printing any problems.  Exits with 1 if
+// there are any.
+fn check(path: &str) -> i32 {
+    let report = match pool::open_readonly(path).and_then(|pool| pool.check()) {
+        Ok(report) => report,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}: {}", path, e);
+            return 1;
+        }
+    };
+
+    println!("{}", report);
+    if report.is_clean() { 0 } else { 1 }
+}
+
+// Copy everything that can be recovered from a damaged adump pool into a
+// new pool.
+fn salvage(src: &str, dest: &str) -> i32 {
+    // The properties may be lost as well.
+    let alg = AdumpPool::hash_alg_of(src).unwrap_or(HashAlg::Sha1);
+
+    let result = AdumpPool::new_builder(dest)
+        .set_hash(alg)
+        .create()
+        .and_then(|_| AdumpPool::open(dest))
+        .and_then(|mut pool| AdumpPool::salvage(src, &mut pool));
+    match result {
+        Ok(report) => {
+            println!("{} files, {} chunks recovered, {} bad, {} bytes lost",
+                     report.files,
+                     report.chunks,
+                     report.bad,
+                     report.lost);
+            0
+        }
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}: {}", src, e);
+            1
+        }
+    }
+}
+
+// Copy every chunk of a pool into a pool of the given type, creating it
+// with the same uuid and settings, then check the copy.  If the
+// destination already exists, the copying carries on from an earlier
+// migration that was interrupted.
+fn migrate(src: &str, dest: &str, kind: &str) -> i32 {
+    match migrate_to(src, dest, kind) {
+        Ok((report, check)) => {
+            println!("{} chunks copied ({} large), {} already present, {} bytes",
+                     report.chunks,
+                     report.large,
+                     report.present,
+                     report.bytes);
+            println!("{}", check);
+            if check.is_clean() { 0 } else { 1 }
+        }
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{} -> {}: {}", src, dest, e);
+            1
+        }
+    }
+}
+
+fn migrate_to(src: &str, dest: &str, kind: &str) -> cas::Result<(MigrateReport, CheckReport)> {
+    let src = pool::open_readonly(src)?;
+    let mut dest = match pool::open(dest) {
+        Ok(dest) => dest,
+        Err(Error::NotAPool(_)) => create_like(&src, dest, kind)?,
+        Err(e) => return Err(e),
+    };
+    let report = pool::migrate(&src, &mut dest)?;
+    let check = dest.check()?;
+    Ok((report, check))
+}
+
+// Create a pool of the given type, with the same uuid and settings as
+// `src`, and open it.
+fn create_like(src: &ChunkRead, path: &str, kind: &str) -> cas::Result<Box<ChunkSource>> {
+    if kind == "file" {
+        FilePool::new_builder(path)
+            .set_uuid(src.uuid().clone())
+            .set_hash(src.hash_alg())
+            .set_codec(src.codec())
+            .set_policy(src.compress_policy().clone())
+            .set_max_chunk_size(src.max_chunk_size())
+            .create()?;
+        Ok(Box::new(FilePool::open(path)?))
+    } else {
+        AdumpPool::new_builder(path)
+            .set_uuid(src.uuid().clone())
+            .set_hash(src.hash_alg())
+            .set_codec(src.codec())
+            .set_policy(src.compress_policy().clone())
+            .set_max_chunk_size(src.max_chunk_size())
+            .create()?;
+        Ok(Box::new(AdumpPool::open(path)?))
+    }
+}
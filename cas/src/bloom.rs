@@ -0,0 +1,182 @@
+// A simple bloom filter.
+//
+// The filter has two parameters, one is the size, which generally should
+// be a power of two.  The other is the number of keys to store for each
+// element.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use oid::Oid;
+use Error;
+use Result;
+
+/// Something that can be added to a bloom filter.  It needs to have some
+/// number of keys available.  For simplicity, we'll just return distinct
+/// u32s, even though that is not the best way of getting the key data.
+pub trait BloomItem {
+    // Get the specific key (0-based).
+    fn get_key(&self, index: usize) -> u32;
+}
+
+/// The bytes of an Oid are already a cryptographic hash, so they can be
+/// used directly as keys.  Even SHA-1 gives 5 keys.
+impl BloomItem for Oid {
+    fn get_key(&self, index: usize) -> u32 {
+        let bytes = &self.as_bytes()[index * 4..index * 4 + 4];
+        (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 |
+        (bytes[3] as u32) << 24
+    }
+}
+
+pub struct Bloom {
+    bit_size: usize,
+    mask: usize,
+    nk: usize,
+    data: Vec<u32>,
+}
+
+impl Bloom {
+    /// Construct a new bloom filter, with `bit_size` bits.  There will be
+    /// room for 2**bit_size items.
+    pub fn new(bit_size: usize, nk: usize) -> Bloom {
+        assert!(bit_size > 5);
+        assert!(bit_size <= 32);
+        let mask = (1 << bit_size) - 1;
+        let data = vec![0u32; 1 << (bit_size - 5)];
+        Bloom {
+            bit_size: bit_size,
+            mask: mask,
+            data: data,
+            nk: nk,
+        }
+    }
+
+    /// Construct a bloom filter with room for `count` Oids, with a false
+    /// positive rate of well under 1%.
+    pub fn for_oids(count: usize) -> Bloom {
+        // 16 bits per item, with 4 keys, gives about 0.25% false
+        // positives.
+        let mut bit_size = 10;
+        while bit_size < 32 && (1 << bit_size) < count * 16 {
+            bit_size += 1;
+        }
+        Bloom::new(bit_size, 4)
+    }
+
+    /// Add the item to the blook filter.
+    pub fn add(&mut self, item: &BloomItem) {
+        for i in 0..self.nk {
+            let num = item.get_key(i) as usize & self.mask;
+            self.data[num >> 5] |= 1 << (num & 31);
+        }
+    }
+
+    /// Check if something is present in the bloom filter.  'false' is a
+    /// definitive answer, but 'true' can have false positives depending on
+    /// the parameters of the filter.
+    pub fn maybe_contains(&self, item: &BloomItem) -> bool {
+        for i in 0..self.nk {
+            let num = item.get_key(i) as usize & self.mask;
+            if (self.data[num >> 5] & (1 << (num & 31))) == 0 {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Load a filter saved with `save`.  As with the index files, `size`
+    /// must match the size given when it was saved, otherwise the filter
+    /// is considered stale, and `Error::InvalidIndex` is returned.
+    pub fn load<P: AsRef<Path>>(path: P, size: u64) -> Result<Bloom> {
+        let mut rd = BufReader::new(File::open(path)?);
+
+        let mut magic = vec![0u8; 8];
+        rd.read_exact(&mut magic)?;
+        if magic != b"ldumpblm" {
+            return Err(Error::InvalidIndex("bad bloom magic".to_owned()));
+        }
+        if rd.read_u32::<LittleEndian>()? != 2 {
+            return Err(Error::InvalidIndex("Bloom version mismatch".to_owned()));
+        }
+        if rd.read_u64::<LittleEndian>()? != size {
+            return Err(Error::InvalidIndex("Bloom size mismatch".to_owned()));
+        }
+
+        let bit_size = rd.read_u32::<LittleEndian>()? as usize;
+        let nk = rd.read_u32::<LittleEndian>()? as usize;
+        if bit_size <= 5 || bit_size > 32 || nk == 0 || nk > 5 {
+            return Err(Error::InvalidIndex("Invalid bloom parameters".to_owned()));
+        }
+
+        let mut bloom = Bloom::new(bit_size, nk);
+        for word in &mut bloom.data {
+            *word = rd.read_u32::<LittleEndian>()?;
+        }
+        Ok(bloom)
+    }
+
+    /// Write the filter to `path`, recording `size` to check against when
+    /// it is loaded.
+    pub fn save<P: AsRef<Path>>(&self, path: P, size: u64) -> Result<()> {
+        let path = path.as_ref();
+        let tmp_name = path.with_extension("bloom-tmp");
+        {
+            let mut ofd = BufWriter::new(File::create(&tmp_name)?);
+            ofd.write_all(b"ldumpblm")?;
+            ofd.write_u32::<LittleEndian>(2)?;
+            ofd.write_u64::<LittleEndian>(size)?;
+            ofd.write_u32::<LittleEndian>(self.bit_size as u32)?;
+            ofd.write_u32::<LittleEndian>(self.nk as u32)?;
+            for &word in &self.data {
+                ofd.write_u32::<LittleEndian>(word)?;
+            }
+        }
+        fs::rename(&tmp_name, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use oid::Oid;
+    use kind::Kind;
+    use tempdir::TempDir;
+    use Error;
+
+    fn make_oid(num: u32) -> Oid {
+        Oid::from_data(Kind::new("blob").unwrap(), format!("{}", num).as_bytes())
+    }
+
+    #[test]
+    fn oids() {
+        let mut bloom = Bloom::for_oids(1000);
+        for i in 0..1000 {
+            bloom.add(&make_oid(i));
+        }
+        for i in 0..1000 {
+            assert!(bloom.maybe_contains(&make_oid(i)));
+        }
+
+        // A few false positives are expected, but not many.
+        let false_pos = (1000..11000).filter(|&i| bloom.maybe_contains(&make_oid(i))).count();
+        assert!(false_pos < 100);
+
+        let tmp = TempDir::new("bloom").unwrap();
+        let name = tmp.path().join("test.bloom");
+        bloom.save(&name, 1234).unwrap();
+        match Bloom::load(&name, 1235) {
+            Err(Error::InvalidIndex(_)) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Loaded stale bloom filter"),
+        }
+
+        let bloom = Bloom::load(&name, 1234).unwrap();
+        for i in 0..1000 {
+            assert!(bloom.maybe_contains(&make_oid(i)));
+        }
+    }
+}
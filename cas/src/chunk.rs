@@ -1,18 +1,31 @@
 // Backup chunks.
 
+use std::io::{self, Write};
 use std::ops::Deref;
-use std::cell::{Ref, RefCell};
+use std::sync::{RwLock, RwLockReadGuard};
 
+use codec::{Codec, Compressor};
+use hash::{HashAlg, OidHasher};
 use kind::Kind;
 use oid::Oid;
-use zlib;
+use policy::CompressPolicy;
+use pool::ChunkWrite;
+use Error;
+use Result;
+
+/// The largest chunk, in bytes, that can be held in memory.  Pools store
+/// chunk lengths in 32 bits, with some values reserved.  Larger data must
+/// be stored with `ChunkWrite::add_large`.
+pub const MAX_CHUNK_SIZE: usize = 0x7ffffff;
 
 // A `Chunk` is a single unit of backup.  It has a 'kind' which is a
 // 4-byte identifier, and 0 or more bytes of data.  It is identified
-// by the SHA-1 hash of the kind followed by the data.  This structure
+// by the hash of the kind followed by the data.  This structure
 // is the in-memory representation of a Chunk, including some
 // complexity to allow the origin to be compressed or uncompressed,
-// and the other information to be computed lazily as needed.
+// and the other information to be computed lazily as needed.  The lazily
+// computed parts are behind locks, so a chunk can be shared between
+// threads.
 pub struct Chunk {
     kind: Kind,
     oid: Oid,
@@ -20,36 +33,87 @@ pub struct Chunk {
 
     // These are both optional, since one can be derived from the
     // other.  It is non-sensical to have neither present.
-    data: RefCell<Option<Vec<u8>>>,
-    zdata: RefCell<Compressed>,
+    data: RwLock<Option<Vec<u8>>>,
+    zdata: RwLock<Compressed>,
 }
 
 impl Chunk {
-    /// Construct a new chunk out of some uncompressed data.
+    /// Construct a new chunk out of some uncompressed data, identified by
+    /// its SHA-1 hash.
     pub fn new_plain(kind: Kind, data: Vec<u8>) -> Chunk {
-        let oid = Oid::from_data(kind, &data[..]);
+        Chunk::new_plain_alg(HashAlg::Sha1, kind, data)
+    }
+
+    /// Construct a new chunk out of some uncompressed data, using the given
+    /// hash algorithm to compute its Oid.  Panics if the data is larger
+    /// than `MAX_CHUNK_SIZE`.
+    pub fn new_plain_alg(alg: HashAlg, kind: Kind, data: Vec<u8>) -> Chunk {
+        match Chunk::try_new_plain_alg(alg, kind, data) {
+            Ok(chunk) => chunk,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Like `new_plain`, but returns `Error::ChunkTooLarge` if the data is
+    /// larger than `MAX_CHUNK_SIZE`.
+    pub fn try_new_plain(kind: Kind, data: Vec<u8>) -> Result<Chunk> {
+        Chunk::try_new_plain_alg(HashAlg::Sha1, kind, data)
+    }
+
+    /// Like `new_plain_alg`, but returns `Error::ChunkTooLarge` if the data
+    /// is larger than `MAX_CHUNK_SIZE`.
+    pub fn try_new_plain_alg(alg: HashAlg, kind: Kind, data: Vec<u8>) -> Result<Chunk> {
         let dlen = data.len();
-        assert!(dlen <= 0x7ffffff);
+        if dlen > MAX_CHUNK_SIZE {
+            return Err(Error::ChunkTooLarge(dlen as u64));
+        }
+        let oid = Oid::from_data_alg(alg, kind, &data[..]);
+        Ok(Chunk {
+            kind: kind,
+            oid: oid,
+            data: RwLock::new(Some(data)),
+            data_len: dlen as u32,
+            zdata: RwLock::new(Compressed::Untried),
+        })
+    }
+
+    /// Construct a new chunk out of uncompressed data whose Oid is already
+    /// known, such as one read from a pool, without hashing the data.  Use
+    /// `verify` to check that the Oid matches.
+    pub fn new_plain_with_oid(kind: Kind, oid: Oid, data: Vec<u8>) -> Chunk {
+        let dlen = data.len();
+        assert!(dlen <= MAX_CHUNK_SIZE);
         Chunk {
             kind: kind,
             oid: oid,
-            data: RefCell::new(Some(data)),
+            data: RwLock::new(Some(data)),
             data_len: dlen as u32,
-            zdata: RefCell::new(Compressed::Untried),
+            zdata: RwLock::new(Compressed::Untried),
         }
     }
 
-    /// Construct a new chunk out of the compressed representation of a
-    /// chunk.  The `data_len` must match the size of the 'zdata' when
-    /// it is decompressed, and the `oid` must match the SHA1 hash, per
-    /// the style of chunks described above.
+    /// Construct a new chunk out of the zlib compressed representation of
+    /// a chunk.  The `data_len` must match the size of the 'zdata' when
+    /// it is decompressed, and the `oid` must match the hash, per the
+    /// style of chunks described above.
     pub fn new_compressed(kind: Kind, oid: Oid, zdata: Vec<u8>, data_len: u32) -> Chunk {
+        Chunk::new_compressed_codec(Codec::Zlib, kind, oid, zdata, data_len)
+    }
+
+    /// Construct a new chunk out of data compressed with `codec`.
+    pub fn new_compressed_codec(codec: Codec,
+                                kind: Kind,
+                                oid: Oid,
+                                zdata: Vec<u8>,
+                                data_len: u32)
+                                -> Chunk {
+        assert!(codec != Codec::None);
         Chunk {
             kind: kind,
             oid: oid,
-            data: RefCell::new(None),
+            data: RwLock::new(None),
             data_len: data_len,
-            zdata: RefCell::new(Compressed::Compressed(zdata)),
+            zdata: RwLock::new(Compressed::Compressed(codec, zdata)),
         }
     }
 
@@ -69,91 +133,304 @@ impl Chunk {
     }
 
     /// Return a view of the compressed data within this chunk, if that
-    /// results in a smaller block of data.
+    /// results in a smaller block of data.  Unless the chunk is already
+    /// compressed, zlib is used.
     pub fn zdata<'a>(&'a self) -> Option<Data<'a>> {
+        self.zdata_with(Codec::Zlib)
+    }
+
+    /// Return a view of the compressed data within this chunk, compressing
+    /// it with `codec` if it hasn't already been compressed.  A chunk that
+    /// was already compressed (such as one read from a pool) keeps its
+    /// existing compression, use `zcodec` to find out which that is.
+    pub fn zdata_with<'a>(&'a self, codec: Codec) -> Option<Data<'a>> {
+        self.zdata_policy(codec, &CompressPolicy::default())
+    }
+
+    /// Like `zdata_with`, but only compress the chunk if `policy` allows
+    /// it.  As with `zdata_with`, the decision is remembered, so later
+    /// calls return the same result regardless of their arguments.
+    pub fn zdata_policy<'a>(&'a self,
+                            codec: Codec,
+                            policy: &CompressPolicy)
+                            -> Option<Data<'a>> {
         // If we already have knowledge of the compression result, just
         // return it.
         {
-            let cell = self.zdata.borrow();
+            let cell = self.zdata.read().unwrap();
             match *cell {
                 Compressed::Uncompressible => return None,
-                Compressed::Compressed(_) => return Some(Data::Cell(cell)),
+                Compressed::Compressed(_, _) => return Some(Data::Cell(cell)),
                 _ => (),
             }
         }
 
-        // If we get here, it means we haven't attempted compression.
-        let data = self.data.borrow();
+        // If we get here, it means we haven't attempted compression.  The
+        // lock on the data is always taken before the one on the
+        // compressed data.
+        let data = self.data.read().unwrap();
         let data = match *data {
             Some(ref payload) => payload,
             None => panic!("Constructed a chunk with no data"),
         };
 
-        *self.zdata.borrow_mut() = {
-            match zlib::deflate(&data[..]) {
-                None => Compressed::Uncompressible,
-                Some(buf) => Compressed::Compressed(buf),
-            }
+        let zdata = match policy.compress(codec, self.kind, &data[..]) {
+            None => Compressed::Uncompressible,
+            Some(buf) => Compressed::Compressed(codec, buf),
         };
 
+        // Another thread may have compressed it in the meantime, in which
+        // case, keep that result.
+        {
+            let mut cell = self.zdata.write().unwrap();
+            if let Compressed::Untried = *cell {
+                *cell = zdata;
+            }
+        }
+
         // And recurse to get the result.
-        self.zdata()
+        self.zdata_policy(codec, policy)
     }
 
-    /// Return a reference to the data.
+    /// Check that the data of this chunk hashes to its Oid.  Returns
+    /// `Error::HashMismatch` if it doesn't, or `Error::CorruptChunk` if the
+    /// data can't be decompressed.
+    pub fn verify(&self) -> Result<()> {
+        let actual = Oid::from_data_alg(self.oid.alg(), self.kind, &self.try_data()?[..]);
+        if actual != self.oid {
+            return Err(Error::HashMismatch(self.oid.clone(), actual));
+        }
+        Ok(())
+    }
+
+    /// Return the codec of the compressed data, if the chunk has been
+    /// compressed.  Returns `Codec::None` if compression hasn't been
+    /// tried, or didn't make the data smaller.
+    pub fn zcodec(&self) -> Codec {
+        match *self.zdata.read().unwrap() {
+            Compressed::Compressed(codec, _) => codec,
+            _ => Codec::None,
+        }
+    }
+
+    /// Return a reference to the data.  Panics if the compressed data is
+    /// corrupt, use `try_data` to handle that case.
     pub fn data<'a>(&'a self) -> Data<'a> {
-        self.force_data();
-        let cell = self.data.borrow();
+        match self.try_data() {
+            Ok(data) => data,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Return a reference to the data, or `Error::CorruptChunk` if the
+    /// compressed data can't be decompressed.
+    pub fn try_data<'a>(&'a self) -> Result<Data<'a>> {
+        self.force_data()?;
+        let cell = self.data.read().unwrap();
         match *cell {
-            // TODO: Ref::map() might make this easier some day.
-            Some(_) => return Data::VecCell(cell),
+            Some(_) => Ok(Data::VecCell(cell)),
             _ => unreachable!(),
         }
     }
 
-    /// Move the uncompressed data out of the chunk.
+    /// Move the uncompressed data out of the chunk.  Panics if the
+    /// compressed data is corrupt, use `try_into_bytes` to handle that
+    /// case.
     pub fn into_bytes(self) -> Vec<u8> {
-        self.force_data();
-        match self.data.into_inner() {
+        match self.try_into_bytes() {
+            Ok(data) => data,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Move the uncompressed data out of the chunk, or return
+    /// `Error::CorruptChunk` if the compressed data can't be decompressed.
+    pub fn try_into_bytes(self) -> Result<Vec<u8>> {
+        self.force_data()?;
+        match self.data.into_inner().unwrap() {
             None => unreachable!(),
-            Some(data) => data,
+            Some(data) => Ok(data),
         }
     }
 
     // Ensure that the data has been uncompressed.
-    fn force_data(&self) {
-        let mut cell = self.data.borrow_mut();
+    fn force_data(&self) -> Result<()> {
+        // Only take the write lock if the data is missing.  Once present,
+        // the data is never removed, so callers may be holding read locks
+        // on it.
+        if self.data.read().unwrap().is_some() {
+            return Ok(());
+        }
+
+        let mut cell = self.data.write().unwrap();
         match *cell {
             Some(_) => (),
             None => {
-                let zdata = self.zdata.borrow();
-                let zdata = match *zdata {
-                    Compressed::Compressed(ref buf) => buf,
+                let zdata = self.zdata.read().unwrap();
+                let (codec, zdata) = match *zdata {
+                    Compressed::Compressed(codec, ref buf) => (codec, buf),
                     _ => panic!("Improperly constructed chunk"),
                 };
 
-                *cell = match zlib::inflate(&zdata[..], self.data_len() as usize) {
-                    None => panic!("zlib unable to inflate"),
+                *cell = match codec.decompress(&zdata[..], self.data_len() as usize) {
+                    None => {
+                        return Err(Error::CorruptChunk(format!("{}: unable to decompress {} \
+                                                                data",
+                                                               self.oid.to_hex(),
+                                                               codec.name())))
+                    }
                     Some(buf) => Some(buf),
                 };
             }
         }
+        Ok(())
+    }
+}
+
+/// Build a chunk incrementally.  Data written to the builder is hashed and
+/// compressed as it arrives, so finishing the chunk doesn't need another
+/// pass over the data.
+pub struct ChunkBuilder {
+    kind: Kind,
+    hasher: OidHasher,
+    codec: Codec,
+    policy: CompressPolicy,
+    compressor: Compressor,
+    data: Vec<u8>,
+}
+
+impl ChunkBuilder {
+    /// Start building a chunk of the given kind, identified by its SHA-1
+    /// hash.
+    pub fn new(kind: Kind) -> ChunkBuilder {
+        ChunkBuilder::new_alg(HashAlg::Sha1, kind)
+    }
+
+    /// Start building a chunk, using the given hash algorithm.
+    pub fn new_alg(alg: HashAlg, kind: Kind) -> ChunkBuilder {
+        ChunkBuilder::with_capacity(alg, kind, 0)
+    }
+
+    /// Start building a chunk, reserving room for `capacity` bytes of
+    /// data.
+    pub fn with_capacity(alg: HashAlg, kind: Kind, capacity: usize) -> ChunkBuilder {
+        ChunkBuilder {
+            kind: kind,
+            hasher: OidHasher::new_alg(alg, kind),
+            codec: Codec::Zlib,
+            policy: CompressPolicy::default(),
+            compressor: Codec::Zlib.compressor(),
+            data: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Change the codec used to compress the chunk from the default of
+    /// zlib.  This must be done before any data is written.
+    pub fn set_codec(mut self, codec: Codec) -> Self {
+        assert!(self.data.is_empty());
+        self.codec = codec;
+        self.compressor = codec.compressor();
+        self.skip_compression_if_unwanted();
+        self
+    }
+
+    /// Apply a compression policy to the chunk being built.  This must be
+    /// done before any data is written.
+    pub fn set_policy(mut self, policy: &CompressPolicy) -> Self {
+        assert!(self.data.is_empty());
+        self.policy = policy.clone();
+        self.skip_compression_if_unwanted();
+        self
+    }
+
+    // If the policy doesn't want this kind compressed, don't bother
+    // running the compressor.
+    fn skip_compression_if_unwanted(&mut self) {
+        if !self.policy.wants(self.kind) {
+            self.compressor = Codec::None.compressor();
+        }
+    }
+
+    /// The number of bytes written so far.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Has nothing been written yet.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Finish the chunk.  Panics if more than `MAX_CHUNK_SIZE` bytes were
+    /// written.
+    pub fn finish(self) -> Chunk {
+        let dlen = self.data.len();
+        assert!(dlen <= MAX_CHUNK_SIZE);
+        let zdata = match self.compressor.finish() {
+            Some(ref buf) if !self.policy.is_worthwhile(dlen, buf.len()) => {
+                Compressed::Uncompressible
+            }
+            None => Compressed::Uncompressible,
+            Some(buf) => Compressed::Compressed(self.codec, buf),
+        };
+        Chunk {
+            kind: self.kind,
+            oid: self.hasher.finish(),
+            data: RwLock::new(Some(self.data)),
+            data_len: dlen as u32,
+            zdata: RwLock::new(zdata),
+        }
+    }
+
+    /// Finish the chunk, and add it to the pool being written by `pool`,
+    /// returning its Oid.  Returns `Error::ChunkTooLarge` if more than
+    /// `MAX_CHUNK_SIZE` bytes were written.
+    pub fn add_to(self, pool: &mut ChunkWrite) -> Result<Oid> {
+        if self.data.len() > MAX_CHUNK_SIZE {
+            return Err(Error::ChunkTooLarge(self.data.len() as u64));
+        }
+        let chunk = self.finish();
+        pool.add(&chunk)?;
+        Ok(chunk.oid)
+    }
+}
+
+impl Write for ChunkBuilder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.update(buf);
+        self.compressor.update(buf);
+        let before = self.data.len();
+        self.data.extend_from_slice(buf);
+
+        // Once the sample has arrived, give up compressing if it doesn't
+        // compress well.
+        if let Some(size) = self.policy.sample_size() {
+            if before < size && self.data.len() >= size &&
+               !self.policy.sample_passes(self.codec, &self.data[..size]) {
+                self.compressor = Codec::None.compressor();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
 
 pub enum Compressed {
     Untried,
     Uncompressible,
-    Compressed(Vec<u8>),
+    Compressed(Codec, Vec<u8>),
 }
 
 // Data from chunks may be coming out of either a direct vector, or a
 // vector inside of a box.  This wraps the return result when borrowing
 // data so that it can be up to the implementation to return the proper
-// type.
+// type.  The chunk's lock is held until this is dropped.
 pub enum Data<'a> {
-    Cell(Ref<'a, Compressed>),
-    VecCell(Ref<'a, Option<Vec<u8>>>),
+    Cell(RwLockReadGuard<'a, Compressed>),
+    VecCell(RwLockReadGuard<'a, Option<Vec<u8>>>),
 }
 
 // TODO: Implement index for this (if this helps).
@@ -165,7 +442,7 @@ impl<'b> Deref for Data<'b> {
         match *self {
             Data::Cell(ref v) => {
                 match **v {
-                    Compressed::Compressed(ref p) => &p[..],
+                    Compressed::Compressed(_, ref p) => &p[..],
                     _ => unreachable!(),
                 }
             }
@@ -183,8 +460,11 @@ impl<'b> Deref for Data<'b> {
 mod test {
     use super::*;
     use testutil::{boundary_sizes, make_random_string};
+    use codec::Codec;
     use kind::Kind;
+    use policy::CompressPolicy;
     use zlib;
+    use Error;
 
     fn single_chunk(index: u32) {
         let p1 = make_random_string(index, index);
@@ -223,4 +503,179 @@ mod test {
             single_chunk(size);
         }
     }
+
+    #[test]
+    fn codecs() {
+        let kind = Kind::new("blob").unwrap();
+        for &codec in &[Codec::Zlib, Codec::Zstd, Codec::Lz4] {
+            let p1 = make_random_string(4096, 1);
+            let c1 = Chunk::new_plain(kind, p1.clone().into_bytes());
+            let comp = c1.zdata_with(codec).unwrap().to_vec();
+            assert_eq!(c1.zcodec(), codec);
+
+            // Once compressed, the chunk keeps its codec.
+            assert_eq!(&c1.zdata().unwrap()[..], &comp[..]);
+
+            let c2 = Chunk::new_compressed_codec(codec, kind, c1.oid().clone(), comp,
+                                                 c1.data_len());
+            assert_eq!(c2.zcodec(), codec);
+            assert_eq!(&c2.data()[..], p1.as_bytes());
+        }
+    }
+
+    #[test]
+    fn builder() {
+        use std::io::Write;
+
+        let kind = Kind::new("blob").unwrap();
+        for size in boundary_sizes() {
+            let p1 = make_random_string(size, size);
+            let c1 = Chunk::new_plain(kind, p1.clone().into_bytes());
+
+            let mut b2 = ChunkBuilder::new(kind);
+            for piece in p1.as_bytes().chunks(1000) {
+                b2.write_all(piece).unwrap();
+            }
+            assert_eq!(b2.len(), p1.len());
+            let c2 = b2.finish();
+
+            assert_eq!(c1.oid(), c2.oid());
+            assert_eq!(c1.data_len(), c2.data_len());
+            assert_eq!(&c1.data()[..], &c2.data()[..]);
+            if let Some(ref comp) = c2.zdata() {
+                assert_eq!(&zlib::inflate(&comp[..], p1.len()).unwrap()[..], p1.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn policy() {
+        use std::io::Write;
+
+        let policy = CompressPolicy::new().skip_kind("IND*");
+        let text = make_random_string(4096, 1).into_bytes();
+        for &(name, compressed) in &[("blob", true), ("IND1", false)] {
+            let kind = Kind::new(name).unwrap();
+
+            let c1 = Chunk::new_plain(kind, text.clone());
+            assert_eq!(c1.zdata_policy(Codec::Zlib, &policy).is_some(), compressed);
+
+            let mut b2 = ChunkBuilder::new(kind).set_policy(&policy);
+            b2.write_all(&text).unwrap();
+            let c2 = b2.finish();
+            assert_eq!(c2.zdata().is_some(), compressed);
+            assert_eq!(c1.oid(), c2.oid());
+        }
+    }
+
+    #[test]
+    fn corrupt() {
+        let kind = Kind::new("blob").unwrap();
+        let p1 = make_random_string(4096, 1);
+        let c1 = Chunk::new_plain(kind, p1.clone().into_bytes());
+        for &codec in &[Codec::Zlib, Codec::Zstd, Codec::Lz4] {
+            // A truncated payload can't be fully decompressed.  Other
+            // damage may go unnoticed until the hash is checked.
+            let full = codec.compress(p1.as_bytes()).unwrap();
+            let comp = full[..full.len() / 2].to_vec();
+
+            let c2 = Chunk::new_compressed_codec(codec, kind, c1.oid().clone(), comp,
+                                                 c1.data_len());
+            match c2.try_data() {
+                Err(Error::CorruptChunk(ref msg)) => assert!(msg.contains(&c1.oid().to_hex())),
+                Err(e) => panic!("Unexpected error: {:?}", e),
+                Ok(_) => panic!("Corrupt chunk decompressed"),
+            }
+
+            // A wrong length is also corruption.
+            let c3 = Chunk::new_compressed_codec(codec, kind, c1.oid().clone(), full,
+                                                 c1.data_len() + 1);
+            assert!(c3.try_into_bytes().is_err());
+        }
+    }
+
+    #[test]
+    fn verify() {
+        let kind = Kind::new("blob").unwrap();
+        let p1 = make_random_string(4096, 1);
+        let c1 = Chunk::new_plain(kind, p1.clone().into_bytes());
+        c1.verify().unwrap();
+
+        let mut comp = c1.zdata().unwrap().to_vec();
+        let c2 = Chunk::new_compressed(kind, c1.oid().clone(), comp.clone(), c1.data_len());
+        c2.verify().unwrap();
+
+        // Claiming the wrong Oid.
+        let other = Chunk::new_plain(kind, b"other".to_vec());
+        let c3 = Chunk::new_compressed(kind, other.oid().clone(), comp.clone(), c1.data_len());
+        match c3.verify() {
+            Err(Error::HashMismatch(ref expect, ref got)) => {
+                assert_eq!(expect, other.oid());
+                assert_eq!(got, c1.oid());
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        // The kind is part of the hash.
+        let c4 = Chunk::new_compressed(Kind::new("blub").unwrap(), c1.oid().clone(),
+                                       comp.clone(), c1.data_len());
+        assert!(c4.verify().is_err());
+
+        // lz4 has no checksum of its own, so damage to it can decompress
+        // without error.
+        let mut lz = Codec::Lz4.compress(p1.as_bytes()).unwrap();
+        let mid = lz.len() / 2;
+        lz[mid] ^= 0x55;
+        let c6 = Chunk::new_compressed_codec(Codec::Lz4, kind, c1.oid().clone(), lz,
+                                             c1.data_len());
+        assert!(c6.verify().is_err());
+
+        // And data that won't decompress.
+        comp.truncate(10);
+        let c5 = Chunk::new_compressed(kind, c1.oid().clone(), comp, c1.data_len());
+        match c5.verify() {
+            Err(Error::CorruptChunk(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn too_large() {
+        let kind = Kind::new("blob").unwrap();
+        Chunk::try_new_plain(kind, vec![0u8; MAX_CHUNK_SIZE]).unwrap();
+        match Chunk::try_new_plain(kind, vec![0u8; MAX_CHUNK_SIZE + 1]) {
+            Err(Error::ChunkTooLarge(size)) => assert_eq!(size, MAX_CHUNK_SIZE as u64 + 1),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Oversized chunk was constructed"),
+        }
+    }
+
+    #[test]
+    fn shared() {
+        use std::sync::Arc;
+        use std::thread;
+
+        fn is_send_sync<T: Send + Sync>() {}
+        is_send_sync::<Chunk>();
+
+        let kind = Kind::new("blob").unwrap();
+        let p1 = make_random_string(65536, 1);
+        let c1 = Chunk::new_plain(kind, p1.clone().into_bytes());
+        let comp = c1.zdata().unwrap().to_vec();
+        let c2 = Arc::new(Chunk::new_compressed(kind, c1.oid().clone(), comp, c1.data_len()));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let c2 = c2.clone();
+                let p1 = p1.clone();
+                thread::spawn(move || {
+                    assert_eq!(&c2.data()[..], p1.as_bytes());
+                    c2.verify().unwrap();
+                })
+            })
+            .collect();
+        for th in threads {
+            th.join().unwrap();
+        }
+    }
 }
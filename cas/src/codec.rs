@@ -0,0 +1,253 @@
+// Compression codecs.
+
+//! Chunks may be stored compressed with any of several codecs.  Each pool
+//! has a default codec used for new chunks, but the codec is recorded with
+//! every stored chunk, so a pool can contain a mix of them.  Pools that
+//! don't record a codec are zlib.
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use lz4;
+use std::io::prelude::*;
+use std::io::Cursor;
+use zlib;
+use zstd;
+
+use Error;
+use Result;
+
+// The compression level used for zstd.  Level 1 is both faster than zlib's
+// fast mode, and compresses better.
+const ZSTD_LEVEL: i32 = 1;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Codec {
+    /// Data is stored uncompressed.
+    None,
+    Zlib,
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    /// The name used for this codec in property files.
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Zlib => "zlib",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+        }
+    }
+
+    /// Look up a codec by the name returned by `name()`.
+    pub fn from_name(name: &str) -> Result<Codec> {
+        match name {
+            "none" => Ok(Codec::None),
+            "zlib" => Ok(Codec::Zlib),
+            "zstd" => Ok(Codec::Zstd),
+            "lz4" => Ok(Codec::Lz4),
+            _ => Err(Error::UnknownCodec(name.to_owned())),
+        }
+    }
+
+    /// The numeric identifier used for this codec in binary headers.
+    pub fn id(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zlib => 1,
+            Codec::Zstd => 2,
+            Codec::Lz4 => 3,
+        }
+    }
+
+    /// Look up a codec by the identifier returned by `id()`.
+    pub fn from_id(id: u8) -> Result<Codec> {
+        match id {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Zlib),
+            2 => Ok(Codec::Zstd),
+            3 => Ok(Codec::Lz4),
+            _ => Err(Error::UnknownCodec(format!("id {}", id))),
+        }
+    }
+
+    /// Attempt to compress a single block of data.  Returns the data if it
+    /// is compressible, otherwise, returns None.
+    pub fn compress(self, buf: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Codec::None => None,
+            Codec::Zlib => zlib::deflate(buf),
+            _ => {
+                let mut comp = self.compressor();
+                comp.update(buf);
+                comp.finish()
+            }
+        }
+    }
+
+    /// Decompress the given buffer.  Returns None if there was some kind
+    /// of error doing the decompression, or if the data doesn't decompress
+    /// to exactly `size_hint` bytes.
+    pub fn decompress(self, buf: &[u8], size_hint: usize) -> Option<Vec<u8>> {
+        // A damaged stream could decompress to any size, so only read one
+        // byte past what is expected, which is enough to tell that it is
+        // too long.
+        let limit = size_hint as u64 + 1;
+        let mut res = Vec::with_capacity(size_hint);
+        let ok = match self {
+            Codec::None => {
+                res.extend_from_slice(buf);
+                true
+            }
+            Codec::Zlib => return zlib::inflate(buf, size_hint),
+            Codec::Zstd => {
+                match zstd::stream::Decoder::new(Cursor::new(buf)) {
+                    Ok(dec) => dec.take(limit).read_to_end(&mut res).is_ok(),
+                    Err(_) => false,
+                }
+            }
+            Codec::Lz4 => {
+                match lz4::Decoder::new(Cursor::new(buf)) {
+                    Ok(dec) => dec.take(limit).read_to_end(&mut res).is_ok(),
+                    Err(_) => false,
+                }
+            }
+        };
+        if ok && res.len() == size_hint {
+            Some(res)
+        } else {
+            None
+        }
+    }
+
+    /// Construct a streaming compressor for this codec.
+    pub fn compressor(self) -> Compressor {
+        // Setting up an encoder writing to a Vec can only fail if the
+        // codec library can't allocate its state.
+        let enc = match self {
+            Codec::None => Encoder::None,
+            Codec::Zlib => Encoder::Zlib(ZlibEncoder::new(Vec::new(), Compression::Fast)),
+            Codec::Zstd => Encoder::Zstd(zstd::stream::Encoder::new(Vec::new(), ZSTD_LEVEL)
+                .unwrap()),
+            Codec::Lz4 => Encoder::Lz4(lz4::EncoderBuilder::new().build(Vec::new()).unwrap()),
+        };
+        Compressor {
+            enc: enc,
+            len: 0,
+        }
+    }
+}
+
+/// A streaming version of `Codec::compress`.  Data is compressed as it is
+/// given, and `finish` returns the compressed data if it is smaller than
+/// the input.
+pub struct Compressor {
+    enc: Encoder,
+    len: usize,
+}
+
+enum Encoder {
+    None,
+    Zlib(ZlibEncoder<Vec<u8>>),
+    Zstd(zstd::stream::Encoder<Vec<u8>>),
+    Lz4(lz4::Encoder<Vec<u8>>),
+}
+
+impl Compressor {
+    /// Compress another piece of the input.
+    pub fn update(&mut self, buf: &[u8]) {
+        // Writes to a Vec can't fail.
+        match self.enc {
+            Encoder::None => (),
+            Encoder::Zlib(ref mut enc) => enc.write_all(buf).unwrap(),
+            Encoder::Zstd(ref mut enc) => enc.write_all(buf).unwrap(),
+            Encoder::Lz4(ref mut enc) => enc.write_all(buf).unwrap(),
+        }
+        self.len += buf.len();
+    }
+
+    /// Finish compression, returning the data if it was compressible.
+    pub fn finish(self) -> Option<Vec<u8>> {
+        let res = match self.enc {
+            Encoder::None => return None,
+            Encoder::Zlib(enc) => enc.finish().unwrap(),
+            Encoder::Zstd(enc) => enc.finish().unwrap(),
+            Encoder::Lz4(enc) => {
+                let (res, status) = enc.finish();
+                status.unwrap();
+                res
+            }
+        };
+        if res.len() < self.len {
+            Some(res)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::{boundary_sizes, make_random_string};
+
+    static CODECS: &'static [Codec] = &[Codec::None, Codec::Zlib, Codec::Zstd, Codec::Lz4];
+
+    #[test]
+    fn roundtrip() {
+        for &codec in CODECS {
+            for size in boundary_sizes() {
+                let text = make_random_string(size, size).into_bytes();
+                match codec.compress(&text) {
+                    None => (),
+                    Some(ztext) => {
+                        assert!(ztext.len() < text.len());
+                        assert_eq!(codec.decompress(&ztext, text.len()).unwrap(), text);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn streamed() {
+        for &codec in CODECS {
+            for size in boundary_sizes() {
+                let text = make_random_string(size, size).into_bytes();
+                let mut comp = codec.compressor();
+                for piece in text.chunks(1000) {
+                    comp.update(piece);
+                }
+                match comp.finish() {
+                    None => (),
+                    Some(ztext) => {
+                        assert_eq!(codec.decompress(&ztext, text.len()).unwrap(), text)
+                    }
+                }
+            }
+        }
+    }
+
+    // Data that decompresses to more than expected is refused.
+    #[test]
+    fn too_long() {
+        let text = vec![0u8; 1024 * 1024];
+        for &codec in CODECS {
+            let ztext = codec.compress(&text).unwrap_or_else(|| text.clone());
+            assert!(codec.decompress(&ztext, 1000).is_none());
+            assert!(codec.decompress(&ztext, text.len() - 1).is_none());
+            assert_eq!(codec.decompress(&ztext, text.len()).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn names() {
+        for &codec in CODECS {
+            assert_eq!(Codec::from_name(codec.name()).unwrap(), codec);
+            assert_eq!(Codec::from_id(codec.id()).unwrap(), codec);
+        }
+        assert!(Codec::from_name("bzip2").is_err());
+        assert!(Codec::from_id(99).is_err());
+    }
+}
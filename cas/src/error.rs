@@ -9,6 +9,8 @@ use std::string::FromUtf8Error;
 use rusqlite;
 use uuid;
 
+use oid::Oid;
+
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
@@ -19,13 +21,26 @@ pub enum Error {
     CorruptChunk(String),
     CorruptPool(String),
     PropertyError(String),
+    UnknownHash(String),
+    WrongHash(String),
+    UnknownCodec(String),
+    /// The data of a chunk doesn't hash to its Oid.  Holds the expected
+    /// and the actual Oid.
+    HashMismatch(Oid, Oid),
+    /// A chunk is larger than `MAX_CHUNK_SIZE`, and can only be handled
+    /// as a stream.  Holds the size of the chunk.
+    ChunkTooLarge(u64),
     Utf8Error(FromUtf8Error),
     ParseBoolError(ParseBoolError),
     ParseIntError(ParseIntError),
     NonAsciiKind,
     BadKindLength,
     MissingChunk,
-    NotAPool,
+    /// There is no pool of any known type at a path.  Holds a description
+    /// of what was found there.
+    NotAPool(String),
+    /// The pool was opened read-only, and can't be written to.
+    ReadOnly,
 }
 
 impl Error {
@@ -85,12 +100,20 @@ impl fmt::Display for Error {
             Error::NonAsciiKind => write!(f, "Non ascii Kind"),
             Error::BadKindLength => write!(f, "Invalid Kind length (!= 4)"),
             Error::MissingChunk => write!(f, "Missing chunk"),
-            Error::NotAPool => write!(f, "Not a storage pool"),
+            Error::NotAPool(ref msg) => write!(f, "Not a storage pool: {:?}", msg),
+            Error::ReadOnly => write!(f, "Pool is opened read-only"),
             Error::InvalidIndex(ref msg) => write!(f, "Invalid index file: {:?}", msg),
             Error::PathError(ref msg) => write!(f, "Path error: {:?}", msg),
             Error::CorruptChunk(ref msg) => write!(f, "Corrupt chunk: {:?}", msg),
             Error::CorruptPool(ref msg) => write!(f, "Corrupt pool: {:?}", msg),
             Error::PropertyError(ref msg) => write!(f, "Property parse error: {:?}", msg),
+            Error::UnknownHash(ref msg) => write!(f, "Unknown hash algorithm: {:?}", msg),
+            Error::WrongHash(ref msg) => write!(f, "Wrong hash algorithm: {:?}", msg),
+            Error::UnknownCodec(ref msg) => write!(f, "Unknown compression codec: {:?}", msg),
+            Error::HashMismatch(ref expect, ref got) => {
+                write!(f, "Hash mismatch: expected {}, got {}", expect.to_hex(), got.to_hex())
+            }
+            Error::ChunkTooLarge(size) => write!(f, "Chunk too large: {} bytes", size),
         }
     }
 }
@@ -107,12 +130,18 @@ impl error::Error for Error {
             Error::NonAsciiKind => "Non ascii Kind",
             Error::BadKindLength => "Invalid Kind length (!= 4)",
             Error::MissingChunk => "Missing Chunk",
-            Error::NotAPool => "Not a storage pool",
+            Error::NotAPool(_) => "Not a storage pool",
+            Error::ReadOnly => "Pool is read-only",
             Error::InvalidIndex(_) => "Invalid index file",
             Error::PathError(_) => "Invalid Path name",
             Error::CorruptChunk(_) => "Corrupt chunk",
             Error::CorruptPool(_) => "Corrupt pool",
             Error::PropertyError(_) => "Property parse error",
+            Error::UnknownHash(_) => "Unknown hash algorithm",
+            Error::WrongHash(_) => "Wrong hash algorithm",
+            Error::UnknownCodec(_) => "Unknown compression codec",
+            Error::HashMismatch(_, _) => "Chunk hash mismatch",
+            Error::ChunkTooLarge(_) => "Chunk too large",
         }
     }
 
@@ -121,12 +150,18 @@ impl error::Error for Error {
             Error::NonAsciiKind => None,
             Error::BadKindLength => None,
             Error::MissingChunk => None,
-            Error::NotAPool => None,
+            Error::NotAPool(_) => None,
+            Error::ReadOnly => None,
             Error::InvalidIndex(_) => None,
             Error::PathError(_) => None,
             Error::CorruptChunk(_) => None,
             Error::CorruptPool(_) => None,
             Error::PropertyError(_) => None,
+            Error::UnknownHash(_) => None,
+            Error::WrongHash(_) => None,
+            Error::UnknownCodec(_) => None,
+            Error::HashMismatch(_, _) => None,
+            Error::ChunkTooLarge(_) => None,
             Error::Io(ref err) => err.cause(),
             Error::Sql(ref err) => err.cause(),
             Error::Uuid(_) => None,
@@ -0,0 +1,210 @@
+// Hash algorithms.
+
+//! Hash algorithms used to compute object ids.
+//!
+//! A pool is created with a single hash algorithm, which is used for every
+//! `Oid` stored in it.  SHA-1 is the historical default, and is what is
+//! assumed for pools that don't record an algorithm.
+
+use blake2_rfc::blake2b::Blake2b;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::io::{self, Write};
+
+use Error;
+use Kind;
+use Oid;
+use Result;
+
+/// The largest hash size, in bytes, of any supported algorithm.
+pub const MAX_HASH_SIZE: usize = 32;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub enum HashAlg {
+    Sha1,
+    Sha256,
+    Blake2b256,
+}
+
+impl HashAlg {
+    /// The size, in bytes, of the hashes generated by this algorithm.
+    pub fn size(self) -> usize {
+        match self {
+            HashAlg::Sha1 => 20,
+            HashAlg::Sha256 => 32,
+            HashAlg::Blake2b256 => 32,
+        }
+    }
+
+    /// The name used for this algorithm in property files.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlg::Sha1 => "sha1",
+            HashAlg::Sha256 => "sha256",
+            HashAlg::Blake2b256 => "blake2b-256",
+        }
+    }
+
+    /// Look up an algorithm by the name returned by `name()`.
+    pub fn from_name(name: &str) -> Result<HashAlg> {
+        match name {
+            "sha1" => Ok(HashAlg::Sha1),
+            "sha256" => Ok(HashAlg::Sha256),
+            "blake2b-256" => Ok(HashAlg::Blake2b256),
+            _ => Err(Error::UnknownHash(name.to_owned())),
+        }
+    }
+
+    /// The numeric identifier used for this algorithm in binary headers.
+    pub fn id(self) -> u8 {
+        match self {
+            HashAlg::Sha1 => 1,
+            HashAlg::Sha256 => 2,
+            HashAlg::Blake2b256 => 3,
+        }
+    }
+
+    /// Look up an algorithm by the identifier returned by `id()`.
+    pub fn from_id(id: u8) -> Result<HashAlg> {
+        match id {
+            1 => Ok(HashAlg::Sha1),
+            2 => Ok(HashAlg::Sha256),
+            3 => Ok(HashAlg::Blake2b256),
+            _ => Err(Error::UnknownHash(format!("id {}", id))),
+        }
+    }
+}
+
+/// A hashing context for one of the supported algorithms.
+enum Context {
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Blake2b256(Blake2b),
+}
+
+impl Context {
+    fn new(alg: HashAlg) -> Context {
+        match alg {
+            HashAlg::Sha1 => Context::Sha1(Sha1::new()),
+            HashAlg::Sha256 => Context::Sha256(Sha256::default()),
+            HashAlg::Blake2b256 => Context::Blake2b256(Blake2b::new(32)),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match *self {
+            Context::Sha1(ref mut core) => core.update(data),
+            Context::Sha256(ref mut core) => core.input(data),
+            Context::Blake2b256(ref mut core) => core.update(data),
+        }
+    }
+
+    // Finish the hash, writing the result into `out`, which must be
+    // exactly the size of the hash.
+    fn finish(self, out: &mut [u8]) {
+        match self {
+            Context::Sha1(core) => out.copy_from_slice(&core.digest().bytes()),
+            Context::Sha256(core) => out.copy_from_slice(&core.result()[..]),
+            Context::Blake2b256(core) => out.copy_from_slice(core.finalize().as_bytes()),
+        }
+    }
+}
+
+/// Incrementally compute the Oid of a chunk.  The kind is hashed when the
+/// hasher is created, and the data can then be given in any number of
+/// pieces, either through `update` or as an `io::Write`.
+pub struct OidHasher {
+    alg: HashAlg,
+    ctx: Context,
+}
+
+impl OidHasher {
+    /// Start hashing a chunk of the given kind, with SHA-1.
+    pub fn new(kind: Kind) -> OidHasher {
+        OidHasher::new_alg(HashAlg::Sha1, kind)
+    }
+
+    /// Start hashing a chunk of the given kind, with the given algorithm.
+    pub fn new_alg(alg: HashAlg, kind: Kind) -> OidHasher {
+        let mut ctx = Context::new(alg);
+        ctx.update(&kind.bytes());
+        OidHasher {
+            alg: alg,
+            ctx: ctx,
+        }
+    }
+
+    /// Add more data to the chunk being hashed.
+    pub fn update(&mut self, data: &[u8]) {
+        self.ctx.update(data);
+    }
+
+    /// Finish hashing, returning the Oid of the chunk.
+    pub fn finish(self) -> Oid {
+        let size = self.alg.size();
+        let mut buf = [0u8; MAX_HASH_SIZE];
+        self.ctx.finish(&mut buf[..size]);
+        Oid::from_raw_alg(self.alg, &buf[..size])
+    }
+}
+
+impl Write for OidHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use rustc_serialize::hex::ToHex;
+    use {Kind, Oid};
+
+    fn hash_of(alg: HashAlg, data: &[u8]) -> String {
+        let mut ctx = Context::new(alg);
+        ctx.update(data);
+        let mut out = vec![0u8; alg.size()];
+        ctx.finish(&mut out);
+        out.to_hex()
+    }
+
+    #[test]
+    fn test_context() {
+        assert_eq!(hash_of(HashAlg::Sha1, b"A"),
+                   "6dcd4ce23d88e2ee9568ba546c007c63d9131c1b");
+        assert_eq!(hash_of(HashAlg::Sha256, b"A"),
+                   "559aead08264d5795d3909718cdd05abd49572e84fe55590eef31a88a08fdffd");
+        assert_eq!(hash_of(HashAlg::Blake2b256, b"A"),
+                   "a3a0081351bb785d0758ddf68076a95ffd3f10b88bbc9911e9fea4d793c06414");
+    }
+
+    #[test]
+    fn test_hasher() {
+        let kind = Kind::new("blob").unwrap();
+        let data = b"Some data that will be written in pieces";
+
+        for &alg in &[HashAlg::Sha1, HashAlg::Sha256, HashAlg::Blake2b256] {
+            let mut hasher = OidHasher::new_alg(alg, kind);
+            for piece in data.chunks(7) {
+                hasher.write_all(piece).unwrap();
+            }
+            assert_eq!(hasher.finish(), Oid::from_data_alg(alg, kind, data));
+        }
+    }
+
+    #[test]
+    fn test_names() {
+        for &alg in &[HashAlg::Sha1, HashAlg::Sha256, HashAlg::Blake2b256] {
+            assert_eq!(HashAlg::from_name(alg.name()).unwrap(), alg);
+            assert_eq!(HashAlg::from_id(alg.id()).unwrap(), alg);
+        }
+        assert!(HashAlg::from_name("md5").is_err());
+        assert!(HashAlg::from_id(0).is_err());
+    }
+}
@@ -4,10 +4,15 @@
 // #![allow(dead_code)]
 
 extern crate byteorder;
-extern crate libc;
 extern crate regex;
 extern crate rustc_serialize;
 extern crate flate2;
+extern crate lz4;
+extern crate memmap;
+extern crate zstd;
+extern crate sha1;
+extern crate sha2;
+extern crate blake2_rfc;
 extern crate rusqlite;
 extern crate uuid;
 
@@ -17,19 +22,31 @@ extern crate rand;
 #[cfg(test)]
 extern crate tempdir;
 
+pub use bloom::{Bloom, BloomItem};
+pub use codec::Codec;
 pub use error::Error;
+pub use hash::{HashAlg, OidHasher};
 pub use kind::Kind;
 pub use oid::Oid;
+pub use pipeline::Pipeline;
+pub use policy::CompressPolicy;
 pub use chunk::Chunk;
+pub use chunk::ChunkBuilder;
 pub use chunk::Data;
+pub use chunk::MAX_CHUNK_SIZE;
 
 use std::result;
 
 pub type Result<T> = result::Result<T, Error>;
 
+mod bloom;
+mod codec;
 mod error;
+mod hash;
 mod kind;
 mod oid;
+mod pipeline;
+mod policy;
 pub mod chunk;
 pub mod pdump;
 pub mod pool;
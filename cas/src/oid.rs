@@ -3,20 +3,27 @@
 //! Object IDs.
 //!
 //! Every object in the pool is identified by an object-id (OID) which is
-//! the SHA-1 hash of the `Kind` followed by the payload itself.
+//! the hash of the `Kind` followed by the payload itself.  The hash is
+//! SHA-1, unless the pool was created with a different `HashAlg`.
 
 use std::fmt::{self, Formatter, Debug};
-use std::mem;
 use std::ops::Index;
 use std::result;
-// use std::slice::bytes;
+use hash::{HashAlg, OidHasher, MAX_HASH_SIZE};
 use kind::Kind;
 
 use rustc_serialize::hex::{ToHex, FromHex};
 
+/// An object id.  The width of the id depends on the hash algorithm used
+/// to compute it.  Only the first `alg.size()` bytes of `bytes` are
+/// meaningful, the rest are always zero, so that ids of the same algorithm
+/// compare as their hash bytes do.
 // TODO: Derive our own Debug and Hash.
 #[derive(PartialEq, PartialOrd, Eq, Ord, Clone, Hash)]
-pub struct Oid(pub [u8; 20]);
+pub struct Oid {
+    bytes: [u8; MAX_HASH_SIZE],
+    alg: HashAlg,
+}
 
 impl Debug for Oid {
     fn fmt(&self, fmt: &mut Formatter) -> result::Result<(), fmt::Error> {
@@ -24,109 +31,65 @@ impl Debug for Oid {
     }
 }
 
-// Simple binding to the crypto library from OpenSSL.
-mod openssl {
-    use libc::{c_int, c_uint, c_uchar, c_void, size_t, uint32_t};
-    #[cfg(test)]
-    use std::mem;
-
-    // Despite the type name in the SSL header, these are expected to all
-    // be 32-bit values.
-    #[repr(C)]
-    pub struct ShaCtx {
-        _h0: uint32_t,
-        _h1: uint32_t,
-        _h2: uint32_t,
-        _h3: uint32_t,
-        _h4: uint32_t,
-        _nl: uint32_t,
-        _nh: uint32_t,
-        _data: [uint32_t; 16],
-        _num: c_uint,
-    }
-
-    #[link(name = "crypto")]
-    extern "C" {
-        pub fn SHA1_Init(c: *mut ShaCtx) -> c_int;
-        pub fn SHA1_Update(c: *mut ShaCtx, data: *const c_void, len: size_t) -> c_int;
-        pub fn SHA1_Final(md: *mut c_uchar, c: *mut ShaCtx) -> c_int;
-    }
-
-    #[test]
-    fn context_size() {
-        assert_eq!(mem::size_of::<ShaCtx>(), 96);
-    }
-}
-
-struct Context {
-    core: openssl::ShaCtx,
-}
-
-impl Context {
-    fn new() -> Context {
-        unsafe {
-            let mut result: Context = mem::uninitialized();
-            openssl::SHA1_Init(&mut result.core);
-            result
-        }
-    }
-
-    fn update(&mut self, data: &[u8]) {
-        unsafe {
-            openssl::SHA1_Update(&mut self.core,
-                                 data.as_ptr() as *const ::libc::c_void,
-                                 data.len() as ::libc::size_t);
-        }
-    }
-
-    fn finish(&mut self) -> Oid {
-        unsafe {
-            let mut result: Oid = mem::uninitialized();
-            openssl::SHA1_Final(&mut result.0[0], &mut self.core);
-            result
-        }
-    }
-}
-
 #[test]
-fn test_context() {
-    let mut buf = Context::new();
-    buf.update(&[65u8]);
-    let id = buf.finish();
-    assert_eq!(id.to_hex(), "6dcd4ce23d88e2ee9568ba546c007c63d9131c1b");
+fn test_from_data() {
+    let kind = Kind::new("blob").unwrap();
+    assert_eq!(Oid::from_data(kind, b"1").to_hex(),
+               "749914fd3452fc10d36ed33cbbd8be1ab72b71a6");
+    assert_eq!(Oid::from_data_alg(HashAlg::Sha256, kind, b"1").to_hex(),
+               "8ba0d06bc5a88966b1f681d9cab28709781ad7c450802d0e477132d8919e0cbf");
+    assert_eq!(Oid::from_data_alg(HashAlg::Blake2b256, kind, b"1").to_hex(),
+               "73993426bbb42c140c63d79f62c263841ac6bc2149343c78a4b9479d6dbd4296");
 }
 
 impl Oid {
     pub fn to_hex(&self) -> String {
-        self.0[..].to_hex()
+        self.as_bytes().to_hex()
     }
 
+    /// Decode a hex SHA-1 Oid.
     pub fn from_hex(text: &str) -> Option<Oid> {
-        if text.len() != 40 {
+        Oid::from_hex_alg(HashAlg::Sha1, text)
+    }
+
+    /// Decode a hex Oid of the given hash algorithm.
+    pub fn from_hex_alg(alg: HashAlg, text: &str) -> Option<Oid> {
+        if text.len() != 2 * alg.size() {
             return None;
         }
 
-        text.from_hex().ok().map(|x| Oid::from_raw(&x[..]))
+        text.from_hex().ok().map(|x| Oid::from_raw_alg(alg, &x[..]))
     }
 
+    /// Build a SHA-1 Oid out of its raw bytes.
     pub fn from_raw(bytes: &[u8]) -> Oid {
-        if bytes.len() != 20 {
+        Oid::from_raw_alg(HashAlg::Sha1, bytes)
+    }
+
+    /// Build an Oid of the given hash algorithm out of its raw bytes.
+    pub fn from_raw_alg(alg: HashAlg, bytes: &[u8]) -> Oid {
+        if bytes.len() != alg.size() {
             panic!("OID is incorrect length");
         }
 
-        let mut result: Oid = unsafe { mem::uninitialized() };
-        // bytes::copy_memory(bytes, &mut result.0[..]);
-        for i in 0..20 {
-            result.0[i] = bytes[i];
-        }
+        let mut result = Oid {
+            bytes: [0u8; MAX_HASH_SIZE],
+            alg: alg,
+        };
+        result.bytes[..bytes.len()].copy_from_slice(bytes);
         result
     }
 
+    /// Compute the SHA-1 Oid of a chunk with the given kind and data.
     pub fn from_data(kind: Kind, data: &[u8]) -> Oid {
-        let mut ctx = Context::new();
-        ctx.update(&kind.bytes());
-        ctx.update(data);
-        ctx.finish()
+        Oid::from_data_alg(HashAlg::Sha1, kind, data)
+    }
+
+    /// Compute the Oid of a chunk using the given hash algorithm.
+    pub fn from_data_alg(alg: HashAlg, kind: Kind, data: &[u8]) -> Oid {
+        let mut hasher = OidHasher::new_alg(alg, kind);
+        hasher.update(data);
+        hasher.finish()
     }
 
     // Generate an OID from an integer.
@@ -134,9 +97,24 @@ impl Oid {
         Self::from_data(Kind::new("blob").unwrap(), format!("{}", num).as_bytes())
     }
 
-    // Simple accessor to get the size.
-    pub fn size() -> usize {
-        20
+    /// The hash algorithm used to compute this Oid.
+    pub fn alg(&self) -> HashAlg {
+        self.alg
+    }
+
+    /// The size, in bytes, of this Oid.
+    pub fn len(&self) -> usize {
+        self.alg.size()
+    }
+
+    /// Oids are never empty, but this goes along with `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The raw bytes of this Oid.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.alg.size()]
     }
 }
 
@@ -144,7 +122,7 @@ impl Oid {
 impl Index<usize> for Oid {
     type Output = u8;
     fn index(&self, index: usize) -> &u8 {
-        &self.0[index]
+        &self.as_bytes()[index]
     }
 }
 
@@ -154,10 +132,10 @@ impl Oid {
     // larger or smaller than the given one.
     fn tweak(&self, adjust: i16, stop: u8) -> Oid {
         let mut result = (*self).clone();
-        let mut pos = 19;
+        let mut pos = self.len() - 1;
         loop {
-            let tmp = (result.0[pos] as i16 + adjust) as u8;
-            result.0[pos] = tmp;
+            let tmp = (result.bytes[pos] as i16 + adjust) as u8;
+            result.bytes[pos] = tmp;
             if tmp == stop {
                 if pos == 0 {
                     break;
@@ -182,7 +160,8 @@ impl Oid {
     // is rather meaningless (and would break use of the Oid in a test),
     // but is useful when generating Oids quickly based on randomness.
     pub fn as_mut_bytes(&mut self) -> &mut [u8] {
-        &mut self.0
+        let len = self.len();
+        &mut self.bytes[..len]
     }
 }
 
@@ -0,0 +1,230 @@
+// Parallel chunk construction.
+
+//! Building a chunk is dominated by hashing and compressing its data.  A
+//! `Pipeline` hands that work to a set of worker threads, and returns the
+//! finished chunks in the order their data was given.  The caller can then
+//! add them to a pool from a single thread, with the same result as if
+//! they had been built one at a time.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use chunk::Chunk;
+use codec::Codec;
+use hash::HashAlg;
+use kind::Kind;
+use policy::CompressPolicy;
+use pool::ChunkRead;
+use Result;
+
+// A chunk to be built, and its position in the sequence.
+struct Job {
+    seq: u64,
+    kind: Kind,
+    data: Vec<u8>,
+}
+
+pub struct Pipeline {
+    // The work queue, shared by all of the workers.  Dropping the sender
+    // tells the workers to exit.
+    work: Option<Sender<Job>>,
+    done: Receiver<(u64, Result<Chunk>)>,
+    workers: Vec<JoinHandle<()>>,
+
+    // Chunks that have been finished ahead of ones pushed before them.
+    ready: BTreeMap<u64, Result<Chunk>>,
+
+    // The sequence numbers of the next chunk to be pushed and popped.
+    next_in: u64,
+    next_out: u64,
+
+    // How many chunks can be outstanding before the pipeline is full.
+    depth: usize,
+}
+
+impl Pipeline {
+    /// Start `count` worker threads, building chunks hashed with `alg`,
+    /// and compressed with `codec`, according to `policy`.
+    pub fn new(count: usize, alg: HashAlg, codec: Codec, policy: &CompressPolicy) -> Pipeline {
+        assert!(count > 0);
+
+        let (work_tx, work_rx) = mpsc::channel::<Job>();
+        let (done_tx, done_rx) = mpsc::channel();
+        let work_rx = Arc::new(Mutex::new(work_rx));
+
+        let workers = (0..count)
+            .map(|_| {
+                let work_rx = work_rx.clone();
+                let done_tx = done_tx.clone();
+                let policy = policy.clone();
+                thread::spawn(move || {
+                    loop {
+                        // The lock is only held while waiting for a job.
+                        let job = match work_rx.lock().unwrap().recv() {
+                            Ok(job) => job,
+                            Err(_) => break,
+                        };
+
+                        // Data that is too large is returned as an error,
+                        // rather than taking the worker down with it.
+                        let chunk = Chunk::try_new_plain_alg(alg, job.kind, job.data);
+                        if let Ok(ref chunk) = chunk {
+                            let _ = chunk.zdata_policy(codec, &policy);
+                        }
+                        if done_tx.send((job.seq, chunk)).is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        Pipeline {
+            work: Some(work_tx),
+            done: done_rx,
+            workers: workers,
+            ready: BTreeMap::new(),
+            next_in: 0,
+            next_out: 0,
+            depth: 2 * count,
+        }
+    }
+
+    /// Start `count` worker threads, building chunks the way `pool` wants
+    /// them.
+    pub fn for_pool(count: usize, pool: &ChunkRead) -> Pipeline {
+        Pipeline::new(count, pool.hash_alg(), pool.codec(), pool.compress_policy())
+    }
+
+    /// The number of chunks that have been pushed, but not yet popped.
+    pub fn pending(&self) -> usize {
+        (self.next_in - self.next_out) as usize
+    }
+
+    /// Is there enough work outstanding that results should be popped
+    /// before pushing more?  The pipeline doesn't enforce this, but
+    /// memory use grows with the number of pending chunks.
+    pub fn is_full(&self) -> bool {
+        self.pending() >= self.depth
+    }
+
+    /// Queue up a chunk to be built from `data`.
+    pub fn push(&mut self, kind: Kind, data: Vec<u8>) {
+        let job = Job {
+            seq: self.next_in,
+            kind: kind,
+            data: data,
+        };
+        self.next_in += 1;
+        self.work.as_ref().unwrap().send(job).expect("pipeline workers have exited");
+    }
+
+    /// Return the next chunk, in the order the data was pushed, waiting for
+    /// it to be built if necessary.  Returns None if there are no chunks
+    /// pending, and `Error::ChunkTooLarge` in place of a chunk whose data
+    /// was too large.
+    pub fn pop(&mut self) -> Option<Result<Chunk>> {
+        if self.pending() == 0 {
+            return None;
+        }
+
+        loop {
+            if let Some(chunk) = self.ready.remove(&self.next_out) {
+                self.next_out += 1;
+                return Some(chunk);
+            }
+
+            let (seq, chunk) = self.done.recv().expect("pipeline worker failed");
+            self.ready.insert(seq, chunk);
+        }
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        // Closing the work queue causes the workers to exit once it is
+        // empty.
+        self.work = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chunk::{Chunk, ChunkBuilder, MAX_CHUNK_SIZE};
+    use codec::Codec;
+    use hash::HashAlg;
+    use kind::Kind;
+    use policy::CompressPolicy;
+    use std::io::Write;
+    use testutil::{boundary_sizes, make_random_string};
+    use Error;
+
+    #[test]
+    fn ordered() {
+        let kind = Kind::new("blob").unwrap();
+        let policy = CompressPolicy::new().set_sample(1024);
+        let texts: Vec<_> = boundary_sizes()
+            .into_iter()
+            .map(|size| make_random_string(size, size).into_bytes())
+            .collect();
+
+        for &workers in &[1, 4] {
+            let mut pipe = Pipeline::new(workers, HashAlg::Sha256, Codec::Zstd, &policy);
+            assert!(pipe.pop().is_none());
+
+            let mut chunks = vec![];
+            for text in &texts {
+                pipe.push(kind, text.clone());
+                while pipe.is_full() {
+                    chunks.push(pipe.pop().unwrap().unwrap());
+                }
+            }
+            while let Some(chunk) = pipe.pop() {
+                chunks.push(chunk.unwrap());
+            }
+            assert_eq!(pipe.pending(), 0);
+
+            // The result must be the same as building them serially.
+            assert_eq!(chunks.len(), texts.len());
+            for (chunk, text) in chunks.iter().zip(&texts) {
+                let mut builder = ChunkBuilder::new_alg(HashAlg::Sha256, kind)
+                    .set_codec(Codec::Zstd)
+                    .set_policy(&policy);
+                builder.write_all(text).unwrap();
+                let expect: Chunk = builder.finish();
+
+                assert_eq!(chunk.oid(), expect.oid());
+                assert_eq!(&chunk.data()[..], &text[..]);
+                assert_eq!(chunk.zcodec(), expect.zcodec());
+            }
+        }
+    }
+    // Data too large for a chunk gives an error in its place, without
+    // disturbing the chunks around it.
+    #[test]
+    fn too_large() {
+        let kind = Kind::new("blob").unwrap();
+        let policy = CompressPolicy::new();
+        let mut pipe = Pipeline::new(2, HashAlg::Sha1, Codec::Zlib, &policy);
+
+        let text = make_random_string(1000, 1).into_bytes();
+        pipe.push(kind, text.clone());
+        pipe.push(kind, vec![0; MAX_CHUNK_SIZE + 1]);
+        pipe.push(kind, text.clone());
+
+        assert_eq!(&pipe.pop().unwrap().unwrap().data()[..], &text[..]);
+        match pipe.pop().unwrap() {
+            Err(Error::ChunkTooLarge(len)) => assert_eq!(len, MAX_CHUNK_SIZE as u64 + 1),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Built a chunk that is too large"),
+        }
+        assert_eq!(&pipe.pop().unwrap().unwrap().data()[..], &text[..]);
+        assert!(pipe.pop().is_none());
+    }
+}
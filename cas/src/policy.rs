@@ -0,0 +1,252 @@
+// Compression policies.
+
+//! A compression policy decides which chunks are worth compressing at all.
+//! Some kinds of chunks, such as indirect blocks full of hashes, never
+//! compress, and trying to compress data that is already compressed (media
+//! files, archives) just wastes CPU.  A pool holds a policy that is
+//! applied to every chunk added to it.
+
+use codec::Codec;
+use kind::Kind;
+
+use Error;
+use Result;
+
+// The property keys used to store a policy.
+const SKIP_KEY: &'static str = "compress-skip";
+const MIN_SAVING_KEY: &'static str = "compress-min-saving";
+const SAMPLE_KEY: &'static str = "compress-sample";
+
+/// The default policy compresses everything, and keeps the result if it
+/// is any smaller.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct CompressPolicy {
+    // Kinds that are never compressed.  A pattern ending in '*' matches
+    // any kind starting with the rest of the pattern.
+    skip: Vec<String>,
+
+    // The percentage of the size that compression must save for the
+    // compressed form to be kept.
+    min_saving: u32,
+
+    // If non-zero, data larger than this is first compressed for this
+    // many bytes, and if that doesn't meet `min_saving`, compression of
+    // the rest is not attempted.
+    sample: usize,
+}
+
+impl CompressPolicy {
+    pub fn new() -> CompressPolicy {
+        CompressPolicy::default()
+    }
+
+    /// Never compress chunks whose kind matches `pattern`.  The pattern is
+    /// either a kind, such as "blob", or a prefix followed by '*', such as
+    /// "IND*".
+    pub fn skip_kind(mut self, pattern: &str) -> Self {
+        self.skip.push(pattern.to_owned());
+        self
+    }
+
+    /// Only keep compressed data that is at least `percent` percent
+    /// smaller than the original.
+    pub fn set_min_saving(mut self, percent: u32) -> Self {
+        assert!(percent < 100);
+        self.min_saving = percent;
+        self
+    }
+
+    /// Decide if data is worth compressing by first compressing a sample of
+    /// `size` bytes of it.  Zero disables sampling.
+    pub fn set_sample(mut self, size: usize) -> Self {
+        self.sample = size;
+        self
+    }
+
+    /// Is compression of chunks of this kind allowed?
+    pub fn wants(&self, kind: Kind) -> bool {
+        let text = kind.to_string();
+        !self.skip.iter().any(|pat| {
+            if pat.ends_with('*') {
+                text.starts_with(&pat[..pat.len() - 1])
+            } else {
+                text == *pat
+            }
+        })
+    }
+
+    /// Is compressing `len` bytes down to `zlen` bytes enough of a saving
+    /// to keep the compressed form?
+    pub fn is_worthwhile(&self, len: usize, zlen: usize) -> bool {
+        zlen < len && (len - zlen) as u64 * 100 >= len as u64 * self.min_saving as u64
+    }
+
+    /// The sample size, if sampling is enabled.
+    pub fn sample_size(&self) -> Option<usize> {
+        if self.sample > 0 {
+            Some(self.sample)
+        } else {
+            None
+        }
+    }
+
+    /// Does the sample (which should be `sample_size()` bytes from the
+    /// start of the data) compress well enough to be worth compressing the
+    /// rest?
+    pub fn sample_passes(&self, codec: Codec, sample: &[u8]) -> bool {
+        match codec.compress(sample) {
+            None => false,
+            Some(zsample) => self.is_worthwhile(sample.len(), zsample.len()),
+        }
+    }
+
+    /// Compress `data`, which belongs to a chunk of the given `kind`,
+    /// according to this policy.  Returns None if the data shouldn't be
+    /// stored compressed.
+    pub fn compress(&self, codec: Codec, kind: Kind, data: &[u8]) -> Option<Vec<u8>> {
+        if codec == Codec::None || !self.wants(kind) {
+            return None;
+        }
+
+        if let Some(size) = self.sample_size() {
+            if data.len() > size && !self.sample_passes(codec, &data[..size]) {
+                return None;
+            }
+        }
+
+        match codec.compress(data) {
+            Some(zdata) if self.is_worthwhile(data.len(), zdata.len()) => Some(zdata),
+            _ => None,
+        }
+    }
+
+    /// Return the properties describing this policy.  Only settings that
+    /// differ from the default are included.
+    pub fn to_props(&self) -> Vec<(&'static str, String)> {
+        let mut result = vec![];
+        if !self.skip.is_empty() {
+            result.push((SKIP_KEY, self.skip.join(",")));
+        }
+        if self.min_saving != 0 {
+            result.push((MIN_SAVING_KEY, self.min_saving.to_string()));
+        }
+        if self.sample != 0 {
+            result.push((SAMPLE_KEY, self.sample.to_string()));
+        }
+        result
+    }
+
+    /// Reconstruct a policy from the properties written by `to_props`.
+    /// The `lookup` function returns the value of a single property, if it
+    /// is present.
+    pub fn from_props<F>(lookup: F) -> Result<CompressPolicy>
+        where F: Fn(&str) -> Result<Option<String>>
+    {
+        let mut policy = CompressPolicy::new();
+        if let Some(skip) = lookup(SKIP_KEY)? {
+            for pat in skip.split(',') {
+                if pat.is_empty() {
+                    return Err(Error::PropertyError(format!("Invalid {}: {:?}", SKIP_KEY, skip)));
+                }
+                policy = policy.skip_kind(pat);
+            }
+        }
+        if let Some(min_saving) = lookup(MIN_SAVING_KEY)? {
+            let min_saving = min_saving.parse::<u32>()?;
+            if min_saving >= 100 {
+                return Err(Error::PropertyError(format!("Invalid {}: {}",
+                                                        MIN_SAVING_KEY,
+                                                        min_saving)));
+            }
+            policy.min_saving = min_saving;
+        }
+        if let Some(sample) = lookup(SAMPLE_KEY)? {
+            policy.sample = sample.parse::<usize>()?;
+        }
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec::Codec;
+    use kind::Kind;
+    use std::collections::BTreeMap;
+    use testutil::make_random_string;
+
+    fn kind(text: &str) -> Kind {
+        Kind::new(text).unwrap()
+    }
+
+    #[test]
+    fn kinds() {
+        let policy = CompressPolicy::new().skip_kind("IND*").skip_kind("blob");
+        assert!(!policy.wants(kind("blob")));
+        assert!(!policy.wants(kind("IND0")));
+        assert!(!policy.wants(kind("IND3")));
+        assert!(policy.wants(kind("DIR0")));
+        assert!(policy.wants(kind("back")));
+        assert!(policy.wants(kind("blo ")));
+
+        let text = make_random_string(4096, 1).into_bytes();
+        assert!(policy.compress(Codec::Zlib, kind("blob"), &text).is_none());
+        assert!(policy.compress(Codec::Zlib, kind("back"), &text).is_some());
+        assert!(policy.compress(Codec::None, kind("back"), &text).is_none());
+    }
+
+    #[test]
+    fn saving() {
+        assert!(CompressPolicy::new().is_worthwhile(100, 99));
+        assert!(!CompressPolicy::new().is_worthwhile(100, 100));
+
+        let policy = CompressPolicy::new().set_min_saving(25);
+        assert!(policy.is_worthwhile(100, 75));
+        assert!(!policy.is_worthwhile(100, 76));
+
+        // Random words compress somewhat, but not by 90%.
+        let text = make_random_string(4096, 2).into_bytes();
+        assert!(CompressPolicy::new().compress(Codec::Zlib, kind("blob"), &text).is_some());
+        let policy = CompressPolicy::new().set_min_saving(90);
+        assert!(policy.compress(Codec::Zlib, kind("blob"), &text).is_none());
+    }
+
+    #[test]
+    fn sampling() {
+        // A sample of incompressible data in front of text that compresses
+        // well stops the whole thing from being compressed.
+        let mut data = vec![];
+        let mut x: u32 = 12345;
+        for _ in 0..1024 {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            data.push((x >> 16) as u8);
+        }
+        data.extend(make_random_string(65536, 3).into_bytes());
+
+        assert!(CompressPolicy::new().compress(Codec::Zlib, kind("blob"), &data).is_some());
+        let policy = CompressPolicy::new().set_sample(1024);
+        assert!(policy.compress(Codec::Zlib, kind("blob"), &data).is_none());
+    }
+
+    #[test]
+    fn props() {
+        let policies = [CompressPolicy::new(),
+                        CompressPolicy::new().skip_kind("IND*"),
+                        CompressPolicy::new().skip_kind("IND*").skip_kind("dir "),
+                        CompressPolicy::new().set_min_saving(10).set_sample(65536)];
+        for policy in &policies {
+            let props: BTreeMap<_, _> = policy.to_props().into_iter().collect();
+            let policy2 = CompressPolicy::from_props(|key| Ok(props.get(key).cloned())).unwrap();
+            assert_eq!(policy, &policy2);
+        }
+
+        assert!(CompressPolicy::from_props(|key| {
+                Ok(if key == MIN_SAVING_KEY {
+                    Some("100".to_owned())
+                } else {
+                    None
+                })
+            })
+            .is_err());
+    }
+}
@@ -2,11 +2,14 @@
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use Chunk;
+use MAX_CHUNK_SIZE;
+use Codec;
 use Error;
+use HashAlg;
 use Kind;
 use Oid;
 use Result;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 
 // Each chunk contains a header
 //  offset  length  field
@@ -18,26 +21,94 @@ use std::io::{Read, Write};
 //      48     clen data
 //            0-15  padding
 //
+// This header (revision 1.1) is only usable for SHA-1 chunks that are
+// either uncompressed or zlib compressed.  Other chunks use a longer header
+// (revision 1.2, with a different magic), so that legacy readers will
+// reject them rather than misinterpret them.
+//  offset  length  field
+//       0      16  chunk-magic
+//      16       4  compressed length, amount stored in file.
+//      20       4  uncompress length, or -1 for not compressed
+//      24       4  kind
+//      28       1  hash algorithm id
+//      29       1  compression codec id, 0 if not compressed
+//      30       2  reserved, zero
+//      32      32  hash of type + uncompressed-data, zero padded
+//      64     clen data
+//            0-15  padding
+//
 // The numbers are always represented in little endian, and the whole
 // chunk is padded to a multiple of 16 bytes.
 
+static MAGIC_V11: &'static [u8] = b"adump-pool-v1.1\n";
+static MAGIC_V12: &'static [u8] = b"adump-pool-v1.2\n";
+
+/// Does `buf` start with one of the magic numbers a chunk header starts
+/// with?
+pub fn is_magic(buf: &[u8]) -> bool {
+    buf.len() >= 16 && (&buf[..16] == MAGIC_V11 || &buf[..16] == MAGIC_V12)
+}
+
+/// The number of bytes taken in the stream by the chunk whose header
+/// starts `buf`, including the header and padding.  Returns None if `buf`
+/// doesn't hold the start of a header, or the length in it is too large
+/// to be valid.
+pub fn stored_size(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 20 || !is_magic(buf) {
+        return None;
+    }
+    let clen = (&buf[16..20]).read_u32::<LittleEndian>().unwrap() as usize;
+    if clen > MAX_CHUNK_SIZE {
+        return None;
+    }
+    let hsize = if &buf[..16] == MAGIC_V11 { 48 } else { 64 };
+    Some(hsize + ((clen + 15) & !15))
+}
+
+// Can a chunk with this hash and codec be written with the legacy header?
+fn is_legacy(alg: HashAlg, codec: Codec) -> bool {
+    alg == HashAlg::Sha1 && (codec == Codec::Zlib || codec == Codec::None)
+}
+
+/// The size of the header written before a chunk using the given hash,
+/// whose payload is compressed with `codec` (or `Codec::None` if it is
+/// stored uncompressed).
+pub fn header_size(alg: HashAlg, codec: Codec) -> u32 {
+    if is_legacy(alg, codec) { 48 } else { 64 }
+}
+
 pub trait ChunkWrite {
-    fn write_chunk(&mut self, chunk: &Chunk) -> Result<()>;
+    /// Write a chunk to the stream, compressing it with `codec` if it
+    /// hasn't already been compressed.
+    fn write_chunk(&mut self, chunk: &Chunk, codec: Codec) -> Result<()>;
 }
 
 impl<T: Write> ChunkWrite for T {
-    fn write_chunk(&mut self, chunk: &Chunk) -> Result<()> {
-        let (clen, ulen, payload) = match chunk.zdata() {
+    fn write_chunk(&mut self, chunk: &Chunk, codec: Codec) -> Result<()> {
+        let (clen, ulen, payload) = match chunk.zdata_with(codec) {
             Some(zdata) => (zdata.len() as u32, chunk.data_len(), zdata),
             None => (chunk.data_len(), 0xFFFF_FFFF, chunk.data()),
         };
 
-        let mut header = Vec::with_capacity(48);
-        header.write_all(b"adump-pool-v1.1\n")?;
+        let alg = chunk.oid().alg();
+        let zcodec = chunk.zcodec();
+        let hsize = header_size(alg, zcodec) as usize;
+        let mut header = Vec::with_capacity(hsize);
+        let legacy = is_legacy(alg, zcodec);
+        if legacy {
+            header.write_all(MAGIC_V11)?;
+        } else {
+            header.write_all(MAGIC_V12)?;
+        }
         header.write_u32::<LittleEndian>(clen)?;
         header.write_u32::<LittleEndian>(ulen)?;
         header.write_all(&chunk.kind().bytes())?;
-        header.write_all(&chunk.oid().0)?;
+        if !legacy {
+            header.write_all(&[alg.id(), zcodec.id(), 0, 0])?;
+        }
+        header.write_all(chunk.oid().as_bytes())?;
+        let pad = hsize - header.len();
+        header.write_all(&vec![0u8; pad])?;
 
         self.write_all(&header)?;
         self.write_all(&payload)?;
@@ -52,51 +123,120 @@ impl<T: Write> ChunkWrite for T {
 }
 
 pub trait ChunkRead {
-    // Read a chunk from the stream.
+    // Read a chunk from the stream.  Uncompressed data is hashed, so the
+    // chunk has the Oid of the data actually read, even if that doesn't
+    // match the header.
     fn read_chunk(&mut self) -> Result<Chunk>;
+
+    // Read a chunk from the stream, keeping the Oid from its header.  The
+    // data isn't hashed, damage to it is only noticed by `Chunk::verify`.
+    // This is for checking pools, which needs to know which chunk is
+    // damaged.
+    fn read_chunk_unverified(&mut self) -> Result<Chunk>;
 }
 
 impl<T: Read> ChunkRead for T {
     fn read_chunk(&mut self) -> Result<Chunk> {
-        let mut header = vec![0u8; 48];
-        self.read_exact(&mut header)?;
+        read_chunk_from(self, true)
+    }
+
+    fn read_chunk_unverified(&mut self) -> Result<Chunk> {
+        read_chunk_from(self, false)
+    }
+}
 
-        let mut header = &header[..];
+fn read_chunk_from<R: Read + ?Sized>(rd: &mut R, rehash: bool) -> Result<Chunk> {
+    let mut magic = vec![0u8; 16];
+    rd.read_exact(&mut magic)?;
+    let wide = if magic == MAGIC_V11 {
+        false
+    } else if magic == MAGIC_V12 {
+        true
+    } else {
+        return Err(Error::CorruptChunk("Invalid magic".to_owned()));
+    };
 
-        let mut magic = vec![0u8; 16];
-        header.read_exact(&mut magic)?;
-        if magic != b"adump-pool-v1.1\n" {
-            return Err(Error::CorruptChunk("Invalid magic".to_owned()));
-        }
-        let clen = header.read_u32::<LittleEndian>()?;
-        let ulen = header.read_u32::<LittleEndian>()?;
+    let mut header = vec![0u8; if wide { 48 } else { 32 }];
+    rd.read_exact(&mut header)?;
 
-        let mut kind = vec![0u8; 4];
-        header.read_exact(&mut kind)?;
-        let kind = String::from_utf8(kind)?;
-        let kind = Kind::new(&kind)?;
+    let mut header = &header[..];
 
-        let mut oid = vec![0u8; 20];
-        header.read_exact(&mut oid)?;
-        let oid = Oid::from_raw(&oid);
+    let clen = header.read_u32::<LittleEndian>()?;
+    let ulen = header.read_u32::<LittleEndian>()?;
 
-        let mut payload = vec![0u8; clen as usize];
-        if clen > 0 {
-            self.read_exact(&mut payload)?;
-        }
+    // Lengths this large can only come from a damaged header, and
+    // shouldn't be used to size a buffer.
+    if clen as usize > MAX_CHUNK_SIZE ||
+       (ulen != 0xFFFF_FFFF && ulen as usize > MAX_CHUNK_SIZE) {
+        return Err(Error::CorruptChunk(format!("Invalid chunk length {}/{}", clen, ulen)));
+    }
 
-        let pad_len = 15 & ((-(clen as i32)) as u32);
-        if pad_len > 0 {
-            let mut pad = vec![0; pad_len as usize];
-            self.read_exact(&mut pad)?;
-        }
+    let mut kind = vec![0u8; 4];
+    header.read_exact(&mut kind)?;
+    let kind = String::from_utf8(kind)?;
+    let kind = Kind::new(&kind)?;
 
-        if ulen == 0xFFFF_FFFF {
-            Ok(Chunk::new_plain(kind, payload))
-        } else {
-            Ok(Chunk::new_compressed(kind, oid, payload, ulen))
+    let (alg, codec) = if wide {
+        let mut info = vec![0u8; 4];
+        header.read_exact(&mut info)?;
+        (HashAlg::from_id(info[0])?, Codec::from_id(info[1])?)
+    } else {
+        (HashAlg::Sha1, Codec::Zlib)
+    };
+
+    let mut oid = vec![0u8; alg.size()];
+    header.read_exact(&mut oid)?;
+    let oid = Oid::from_raw_alg(alg, &oid);
+
+    let mut payload = vec![0u8; clen as usize];
+    if clen > 0 {
+        rd.read_exact(&mut payload)?;
+    }
+
+    let pad_len = 15 & ((-(clen as i32)) as u32);
+    if pad_len > 0 {
+        let mut pad = vec![0; pad_len as usize];
+        rd.read_exact(&mut pad)?;
+    }
+
+    if ulen == 0xFFFF_FFFF && rehash {
+        Ok(Chunk::new_plain_alg(alg, kind, payload))
+    } else if ulen == 0xFFFF_FFFF {
+        Ok(Chunk::new_plain_with_oid(kind, oid, payload))
+    } else if codec == Codec::None {
+        Err(Error::CorruptChunk("Compressed chunk with no codec".to_owned()))
+    } else {
+        Ok(Chunk::new_compressed_codec(codec, kind, oid, payload, ulen))
+    }
+}
+
+/// A reader that keeps track of how far into the stream it has read, so
+/// that the offset of each chunk read from it is known.
+pub struct CountingReader<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> CountingReader<R> {
+        CountingReader {
+            inner: inner,
+            pos: 0,
         }
     }
+
+    /// The number of bytes read so far.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.pos += count as u64;
+        Ok(count)
+    }
 }
 
 #[cfg(test)]
@@ -105,6 +245,7 @@ mod test {
     use super::*;
     use tempdir::TempDir;
     use testutil;
+    use {Codec, HashAlg};
 
     #[test]
     fn test_write() {
@@ -116,7 +257,7 @@ mod test {
 
             for size in testutil::boundary_sizes() {
                 let ch = testutil::make_random_chunk(size, size);
-                fd.write_chunk(&ch).unwrap();
+                fd.write_chunk(&ch, Codec::Zlib).unwrap();
             }
         }
 
@@ -133,4 +274,79 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_write_codecs() {
+        let tmp = TempDir::new("testfile").unwrap();
+        let name = tmp.path().join("sample.data");
+        let codecs = [Codec::None, Codec::Zlib, Codec::Zstd, Codec::Lz4];
+
+        {
+            let mut fd = File::create(&name).unwrap();
+
+            for (i, size) in testutil::boundary_sizes().into_iter().enumerate() {
+                let ch = testutil::make_random_chunk(size, size);
+                fd.write_chunk(&ch, codecs[i % codecs.len()]).unwrap();
+            }
+        }
+
+        {
+            let mut fd = File::open(&name).unwrap();
+
+            for (i, size) in testutil::boundary_sizes().into_iter().enumerate() {
+                let ch1 = testutil::make_random_chunk(size, size);
+                let ch2 = fd.read_chunk().unwrap();
+                assert_eq!(ch1.oid(), ch2.oid());
+                assert_eq!(&ch1.data()[..], &ch2.data()[..]);
+                let zcodec = ch2.zcodec();
+                assert!(zcodec == Codec::None || zcodec == codecs[i % codecs.len()]);
+            }
+        }
+    }
+
+    // Damaged plain data gets the Oid of what was read, unless the header
+    // is trusted.
+    #[test]
+    fn test_damaged_plain() {
+        let ch = testutil::make_uncompressible_chunk(256, 1);
+        let mut buf = vec![];
+        buf.write_chunk(&ch, Codec::None).unwrap();
+        assert!(ch.zdata().is_none());
+        buf[48 + 10] ^= 0xff;
+
+        let ch2 = (&buf[..]).read_chunk().unwrap();
+        assert!(ch2.oid() != ch.oid());
+        ch2.verify().unwrap();
+
+        let ch3 = (&buf[..]).read_chunk_unverified().unwrap();
+        assert_eq!(ch3.oid(), ch.oid());
+        assert!(ch3.verify().is_err());
+    }
+
+    #[test]
+    fn test_write_wide() {
+        let tmp = TempDir::new("testfile").unwrap();
+        let name = tmp.path().join("sample.data");
+
+        {
+            let mut fd = File::create(&name).unwrap();
+
+            for size in testutil::boundary_sizes() {
+                let ch = testutil::make_hashed_random_chunk(HashAlg::Sha256, size, size);
+                fd.write_chunk(&ch, Codec::Zlib).unwrap();
+            }
+        }
+
+        {
+            let mut fd = File::open(&name).unwrap();
+
+            for size in testutil::boundary_sizes() {
+                let ch1 = testutil::make_hashed_random_chunk(HashAlg::Sha256, size, size);
+                let ch2 = fd.read_chunk().unwrap();
+                assert_eq!(ch1.oid(), ch2.oid());
+                assert_eq!(ch1.kind(), ch2.kind());
+                assert_eq!(&ch1.data()[..], &ch2.data()[..]);
+            }
+        }
+    }
 }
@@ -0,0 +1,253 @@
+//! Removing chunks that are no longer needed from an adump pool.
+
+use Codec;
+use Error;
+use HashAlg;
+use Oid;
+use Result;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use super::chunkio::{ChunkRead, ChunkWrite, CountingReader};
+use super::index::{IndexUpdate, MasterIndex, PairIndex};
+use super::{forgotten_path, large, write_oids, write_size, AdumpPool};
+
+// Each pool file holding chunks that aren't live, or that are duplicates of
+// chunks kept in an earlier file, is copied, with only the chunks to keep,
+// to a new file whose name doesn't end in '.data', so it is never mistaken
+// for a pool file.  Its index is written, and then the copy is renamed over
+// the original.  The chunks are copied exactly as stored, so the file is
+// readable by anything that could read the original.
+//
+// The indices record the size of the file they were made for, so if this
+// is interrupted, an index that doesn't match its file is regenerated when
+// the pool is next opened, as is the master index.  The pool can be in a
+// state between the old and the new one, but every chunk that was live is
+// in it.  A file with no chunks left has its index and bloom filter removed
+// before the data.
+
+/// The result of compacting a pool.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CompactReport {
+    /// The number of pool files that were rewritten.
+    pub rewritten: u64,
+    /// The number of pool files removed, as nothing in them was kept.
+    pub removed: u64,
+    /// The number of chunks removed from the pool files.
+    pub chunks: u64,
+    /// The number of bytes the pool files shrank by.
+    pub bytes: u64,
+    /// The number of large chunks removed.
+    pub large: u64,
+}
+
+impl AdumpPool {
+    /// Remove every chunk not in `live` from the pool, along with any
+    /// duplicate copies of chunks.  `live` would normally be every chunk
+    /// reachable from the backups in the pool, and must include the
+    /// backups themselves.  Forgotten backups that are removed are dropped
+    /// from the pool's list of them.  Nothing else may be using the pool
+    /// while it is compacted.  Returns `Error::ReadOnly` if the pool was
+    /// opened read-only.
+    pub fn compact(&mut self, live: &HashSet<Oid>) -> Result<CompactReport> {
+        if self.readonly {
+            return Err(Error::ReadOnly);
+        }
+
+        let names: Vec<PathBuf> = self.cfiles.iter().map(|cf| cf.name.clone()).collect();
+        // The files are replaced underneath the open descriptors, so close
+        // them, and open the pool again when done, whether or not this
+        // succeeded.
+        self.cfiles.clear();
+        self.master = MasterIndex::empty();
+        self.master_files.clear();
+
+        let base = self.base.clone();
+        let result = compact_all(&base, self.alg, &names, live);
+        *self = AdumpPool::open_with(&base, false)?;
+        let report = result?;
+
+        // Forgotten backups that have been removed no longer need to be
+        // listed.
+        let gone: Vec<Oid> = self.forgotten
+            .iter()
+            .filter(|oid| !self.contains_small(oid) && !self.large.contains(oid))
+            .cloned()
+            .collect();
+        if !gone.is_empty() {
+            for oid in &gone {
+                self.forgotten.remove(oid);
+            }
+            write_oids(&forgotten_path(&base), &self.forgotten)?;
+        }
+        Ok(report)
+    }
+}
+
+fn compact_all(base: &Path,
+               alg: HashAlg,
+               names: &[PathBuf],
+               live: &HashSet<Oid>)
+               -> Result<CompactReport> {
+    let mut report = CompactReport::default();
+    let mut seen = HashSet::new();
+    for name in names {
+        compact_file(name, alg, live, &mut seen, &mut report)?;
+    }
+
+    for oid in large::list(base, alg)? {
+        if !live.contains(&oid) {
+            large::remove(base, &oid)?;
+            report.large += 1;
+        }
+    }
+    Ok(report)
+}
+
+// Compact a single pool file, adding the chunks kept to `seen`.
+fn compact_file(name: &Path,
+                alg: HashAlg,
+                live: &HashSet<Oid>,
+                seen: &mut HashSet<Oid>,
+                report: &mut CompactReport)
+                -> Result<()> {
+    let size = name.metadata()?.len();
+    let corrupt = |pos: u64, e: Error| {
+        Error::CorruptPool(format!("{:?} at offset {}: {}", name, pos, e))
+    };
+
+    // Decide which chunks to keep, before changing anything.
+    let mut keep = vec![];
+    {
+        let mut rd = CountingReader::new(BufReader::new(File::open(name)?));
+        while rd.position() < size {
+            let pos = rd.position();
+            let chunk = rd.read_chunk().map_err(|e| corrupt(pos, e))?;
+            let oid = chunk.oid();
+            keep.push(live.contains(oid) && seen.insert(oid.clone()));
+        }
+    }
+    let dropped = keep.iter().filter(|&&k| !k).count() as u64;
+    if dropped == 0 {
+        return Ok(());
+    }
+    report.chunks += dropped;
+
+    if dropped == keep.len() as u64 {
+        for ext in &["idx", "bloom", "data"] {
+            match fs::remove_file(name.with_extension(ext)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                other => other?,
+            }
+        }
+        report.removed += 1;
+        report.bytes += size;
+        return Ok(());
+    }
+
+    let tmp_name = name.with_extension("compact");
+    let mut index = PairIndex::empty();
+    let mut new_size = 0;
+    {
+        let mut rd = CountingReader::new(BufReader::new(File::open(name)?));
+        let mut fd = BufWriter::new(File::create(&tmp_name)?);
+        for &k in &keep {
+            let pos = rd.position();
+            let chunk = rd.read_chunk().map_err(|e| corrupt(pos, e))?;
+            if !k {
+                continue;
+            }
+
+            // Writing with no codec leaves compressed chunks as they are,
+            // and doesn't compress the others.
+            fd.write_chunk(&chunk, Codec::None)?;
+            index.insert(chunk.oid().to_owned(), new_size, chunk.kind());
+            new_size += write_size(&chunk, Codec::None) as u64;
+        }
+        fd.flush()?;
+        fd.get_ref().sync_all()?;
+    }
+
+    index.save(name.with_extension("idx"), new_size)?;
+    fs::rename(&tmp_name, name)?;
+
+    report.rewritten += 1;
+    report.bytes += size - new_size;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use Kind;
+    use pool::{AdumpPool, ChunkRead, ChunkSource};
+    use std::collections::HashSet;
+    use std::fs;
+    use tempdir::TempDir;
+    use testutil;
+
+    #[test]
+    fn test_compact() {
+        let tmp = TempDir::new("adump").unwrap();
+        let name = tmp.path().join("blort");
+        AdumpPool::new_builder(&name)
+            .set_limit(16 * 1024)
+            .set_master_index(true)
+            .create()
+            .unwrap();
+
+        let mut pool = AdumpPool::open(&name).unwrap();
+        let chunks: Vec<_> = (0..200).map(|i| testutil::make_random_chunk(1000, i)).collect();
+        let large = {
+            let mut wr = pool.writer().unwrap();
+            for chunk in &chunks {
+                wr.add(chunk).unwrap();
+            }
+            let data = testutil::make_random_string(1000, 2000).into_bytes();
+            let large = wr.add_large(Kind::new("blob").unwrap(), &mut &data[..]).unwrap();
+            wr.commit().unwrap();
+            large
+        };
+
+        // A duplicate, which only the first copy of is kept.
+        pool.write_chunk(&chunks[0]).unwrap();
+        pool.flush().unwrap();
+
+        // Every third of the first 150 chunks is live, which leaves the
+        // last few files with nothing in them.
+        let is_live = |i: usize| i < 150 && i % 3 == 0;
+        let live: HashSet<_> = chunks.iter()
+            .enumerate()
+            .filter(|&(i, _)| is_live(i))
+            .map(|(_, chunk)| chunk.oid().clone())
+            .collect();
+        let files = pool.cfiles.len();
+        let report = pool.compact(&live).unwrap();
+        assert_eq!(report.chunks, 151);
+        assert_eq!(report.large, 1);
+        assert!(report.removed > 0);
+        assert_eq!(report.rewritten + report.removed, files as u64);
+        assert_eq!(pool.cfiles.len(), files - report.removed as usize);
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert_eq!(pool.contains_key(chunk.oid()).unwrap(), is_live(i));
+        }
+        assert!(!pool.contains_key(&large).unwrap());
+        assert!(pool.check().unwrap().is_clean());
+
+        // Nothing is left behind, and the pool reopens with indices that
+        // match the files.
+        for ent in fs::read_dir(&name).unwrap() {
+            let path = ent.unwrap().path();
+            assert!(path.extension().map_or(true, |ext| ext != "compact"));
+        }
+        drop(pool);
+        let pool = AdumpPool::open(&name).unwrap();
+        for (i, chunk) in chunks.iter().enumerate() {
+            if is_live(i) {
+                assert_eq!(&pool.find(chunk.oid()).unwrap().data()[..], &chunk.data()[..]);
+            }
+        }
+        assert!(pool.check().unwrap().is_clean());
+    }
+}
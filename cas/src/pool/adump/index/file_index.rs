@@ -2,14 +2,15 @@
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use Error;
+use HashAlg;
 use Kind;
 use Oid;
 use Result;
 use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::io::{BufReader, BufWriter, Read, Write};
-use std::path::{Path, PathBuf};
-use super::{Index, /* IndexUpdate, */ IndexInfo, IterItem};
+use std::path::Path;
+use super::{compute_top, tmpify, Index, /* IndexUpdate, */ IndexInfo, IterItem};
 
 // Represents the in-memory format for a single index file.  There is a
 // tradeoff here between load time (reading and decoding the file, or using
@@ -17,11 +18,18 @@ use super::{Index, /* IndexUpdate, */ IndexInfo, IterItem};
 // to compare these other than two try both approaches, and benchmark the
 // results.
 //
-// This FileIndex uses the byteorder crate to read and decode the data.
+// There are two versions of the file.  Version 4 has 32-bit offsets, and
+// is what legacy programs read.  Version 5 is identical, except that the
+// size of the pool file and the offsets are 64 bits.  It is only written
+// for pool files too large for version 4.
+//
+// This FileIndex uses the byteorder crate to read and decode the data.  The
+// `MmapIndex` takes the other approach, and is used instead when the file
+// can be mapped.
 #[allow(dead_code)]
 pub struct FileIndex {
     top: Vec<u32>,
-    offsets: Vec<u32>,
+    offsets: Vec<u64>,
     oids: Vec<Oid>,
     kind_names: Vec<Kind>,
     kinds: Vec<u8>,
@@ -29,8 +37,9 @@ pub struct FileIndex {
 
 impl FileIndex {
     /// Try loading the given named index file, returning it if it is
-    /// valid.
-    pub fn load<P: AsRef<Path>>(path: P, size: u32) -> Result<FileIndex> {
+    /// valid.  The index doesn't record the width of the hashes it
+    /// contains, so this must be given as the pool's hash algorithm.
+    pub fn load<P: AsRef<Path>>(path: P, size: u64, alg: HashAlg) -> Result<FileIndex> {
         let f = File::open(path)?;
         let mut rd = BufReader::new(f);
 
@@ -40,19 +49,23 @@ impl FileIndex {
             return Err(Error::InvalidIndex("bad magic".to_owned()));
         }
 
-        let version = rd.read_u32::<LittleEndian>()?;
-        if version != 4 {
-            return Err(Error::InvalidIndex("Version mismatch".to_owned()));
-        }
+        let wide = match rd.read_u32::<LittleEndian>()? {
+            4 => false,
+            5 => true,
+            _ => return Err(Error::InvalidIndex("Version mismatch".to_owned())),
+        };
 
-        let file_size = rd.read_u32::<LittleEndian>()?;
+        let file_size = if wide {
+            rd.read_u64::<LittleEndian>()?
+        } else {
+            rd.read_u32::<LittleEndian>()? as u64
+        };
         if file_size != size {
             return Err(Error::InvalidIndex("Index size mismatch".to_owned()));
         }
-        // TODO: Process this
         // The file_size is the number of bytes in the pool file.  If this
         // differs, it indicates that this index doesn't match the file,
-        // and should be regenerated.
+        // and the pool regenerates it.
 
         let mut top = Vec::with_capacity(256);
         for _ in 0..256 {
@@ -61,16 +74,20 @@ impl FileIndex {
 
         let size = *top.last().unwrap() as usize;
 
-        let mut oid_buf = vec![0u8; 20];
+        let mut oid_buf = vec![0u8; alg.size()];
         let mut oids = Vec::with_capacity(size);
         for _ in 0..size {
             rd.read_exact(&mut oid_buf)?;
-            oids.push(Oid::from_raw(&oid_buf));
+            oids.push(Oid::from_raw_alg(alg, &oid_buf));
         }
 
         let mut offsets = Vec::with_capacity(size);
         for _ in 0..size {
-            offsets.push(rd.read_u32::<LittleEndian>()?);
+            offsets.push(if wide {
+                rd.read_u64::<LittleEndian>()?
+            } else {
+                rd.read_u32::<LittleEndian>()? as u64
+            });
         }
 
         let kind_count = rd.read_u32::<LittleEndian>()? as usize;
@@ -105,13 +122,15 @@ impl FileIndex {
         }
     }
 
-    /// Save an index from something that can be iterated over.
-    pub fn save<'a, P: AsRef<Path>, I>(path: P, size: u32, index: I) -> Result<()>
-        where I: IntoIterator<Item = IterItem<'a>>
+    /// Save an index from something that can be iterated over.  The older
+    /// version is written if the pool file is small enough for it.
+    pub fn save<P: AsRef<Path>, I>(path: P, size: u64, index: I) -> Result<()>
+        where I: IntoIterator<Item = IterItem>
     {
-        let mut nodes: Vec<IterItem<'a>> = index.into_iter().collect();
-        nodes.sort_by_key(|n| n.oid);
+        let mut nodes: Vec<IterItem> = index.into_iter().collect();
+        nodes.sort_by(|a, b| a.oid.cmp(&b.oid));
         let nodes = nodes;
+        let wide = size > u32::max_value() as u64;
 
         let tmp_name = tmpify(path.as_ref())?;
         println!("tmp: {:?} -> {:?}", tmp_name, path.as_ref());
@@ -120,23 +139,33 @@ impl FileIndex {
             let mut ofd = BufWriter::new(ofd);
 
             ofd.write_all(b"ldumpidx")?;
-            ofd.write_u32::<LittleEndian>(4)?;
-            ofd.write_u32::<LittleEndian>(size)?;
+            if wide {
+                ofd.write_u32::<LittleEndian>(5)?;
+                ofd.write_u64::<LittleEndian>(size)?;
+            } else {
+                ofd.write_u32::<LittleEndian>(4)?;
+                ofd.write_u32::<LittleEndian>(size as u32)?;
+            }
 
             // Write the top-level index.
-            let top = compute_top(&nodes);
+            let top = compute_top(nodes.iter().map(|n| &n.oid));
             for elt in top {
                 ofd.write_u32::<LittleEndian>(elt)?;
             }
 
             // Write out the hashes themselves.
             for n in &nodes {
-                ofd.write_all(&n.oid.0)?;
+                ofd.write_all(n.oid.as_bytes())?;
             }
 
             // Write out the offset table.
+            // Offsets are within the pool file, so fit if its size does.
             for n in &nodes {
-                ofd.write_u32::<LittleEndian>(n.offset)?;
+                if wide {
+                    ofd.write_u64::<LittleEndian>(n.offset)?;
+                } else {
+                    ofd.write_u32::<LittleEndian>(n.offset as u32)?;
+                }
             }
 
             // Compute the kind map.
@@ -176,7 +205,7 @@ impl FileIndex {
 
     /// Scan this index for a given hash.
     fn find(&self, key: &Oid) -> Option<usize> {
-        let first_byte = key.0[0] as usize;
+        let first_byte = key[0] as usize;
 
         let low = if first_byte > 0 {
             self.top[first_byte - 1] as usize
@@ -211,7 +240,7 @@ impl Index for FileIndex {
 }
 
 impl<'a> IntoIterator for &'a FileIndex {
-    type Item = IterItem<'a>;
+    type Item = IterItem;
     type IntoIter = Iter<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
@@ -228,7 +257,7 @@ pub struct Iter<'a> {
 }
 
 impl<'a> Iterator for Iter<'a> {
-    type Item = IterItem<'a>;
+    type Item = IterItem;
     fn next(&mut self) -> Option<Self::Item> {
         if self.pos >= self.parent.len() {
             None
@@ -237,50 +266,10 @@ impl<'a> Iterator for Iter<'a> {
             self.pos = pos + 1;
 
             Some(IterItem {
-                oid: &self.parent.oids[pos],
+                oid: self.parent.oids[pos].clone(),
                 kind: self.parent.kind_names[self.parent.kinds[pos] as usize],
                 offset: self.parent.offsets[pos],
             })
         }
     }
 }
-
-fn compute_top<'a>(nodes: &[IterItem<'a>]) -> Vec<u32> {
-    let mut top = Vec::with_capacity(256);
-
-    let mut iter = nodes.iter().enumerate().peekable();
-    for first in 0..256 {
-        // Scan until we hit a value that is too large.
-        loop {
-            match iter.peek() {
-                None => break,
-                Some(&(_, key)) => {
-                    if key.oid[0] as usize > first {
-                        break;
-                    }
-                    iter.next();
-                }
-            }
-        }
-        let index = match iter.peek() {
-            None => nodes.len(),
-            Some(&(n, _)) => n,
-        };
-        top.push(index as u32);
-    }
-    top
-}
-
-// Given a filename, generate another with a ".tmp" suffix, if possible.
-fn tmpify(path: &Path) -> Result<PathBuf> {
-    let base = path.file_name()
-        .ok_or_else(|| Error::PathError(format!("path does not have a filename {:?}", path)));
-    let base = base?;
-
-    let base = base.to_str()
-        .ok_or_else(|| Error::PathError(format!("path isn't valid UTF-8 {:?}", path)));
-    let base = base?;
-
-    let tmp = format!("{}.tmp", base);
-    Ok(path.with_file_name(&tmp))
-}
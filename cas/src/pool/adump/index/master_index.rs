@@ -0,0 +1,419 @@
+//! A MasterIndex is a single index covering many pool files.
+//!
+//! The per-file indices each have to be loaded, and probed in turn, so
+//! both the time to open a pool and to look up a chunk grow with the
+//! number of files.  The master index merges the indices of the files that
+//! are no longer being written to.  Each entry additionally records which
+//! file the chunk is in (by the number in its name), and the index records
+//! the size of each file it covers, so that files changed since can be
+//! detected.
+//!
+//! The file is laid out like the per-file index, with an additional table
+//! of the files, and a table of file numbers:
+//!     magic "ldumpmst", version (2)
+//!     file count, then a number (u32) and size (u64) for each file
+//!     top-level table (256 u32s)
+//!     oids
+//!     file numbers (u32 each)
+//!     offsets (u64 each)
+//!     kind map and kinds, as in the per-file index
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use Error;
+use HashAlg;
+use Kind;
+use memmap::{Mmap, Protection};
+use Oid;
+use Result;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::str;
+use super::{compute_top, tmpify, IndexInfo, IterItem};
+
+const TOP_SIZE: usize = 256 * 4;
+
+// As with the per-file indices, a loaded master index is searched in place,
+// by mapping the file into memory, rather than reading all of it on every
+// open.  A newly built index is kept in memory in the same layout, so both
+// are searched the same way, and saving one just writes it out.
+pub struct MasterIndex {
+    data: Data,
+    alg: HashAlg,
+    // The number and size of each file covered.
+    files: Vec<(u32, u64)>,
+    count: usize,
+    top_pos: usize,
+    oids_pos: usize,
+    nums_pos: usize,
+    offsets_pos: usize,
+    kinds_pos: usize,
+    kind_names: Vec<Kind>,
+}
+
+enum Data {
+    Built(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Data {
+    fn bytes(&self) -> &[u8] {
+        match *self {
+            Data::Built(ref data) => &data[..],
+            // The master index is only ever replaced by renaming a new file
+            // over it, and never modified in place, so the contents won't
+            // change under the mapping.
+            Data::Mapped(ref map) => unsafe { map.as_slice() },
+        }
+    }
+}
+
+impl MasterIndex {
+    /// Construct an empty index, covering no files.
+    pub fn empty() -> MasterIndex {
+        MasterIndex::build(vec![], vec![])
+    }
+
+    /// Build an index covering `files` (number and size of each), from the
+    /// entries of their indices, each given with its file number.  If a
+    /// chunk is in more than one file, the lowest numbered one is used.
+    pub fn build<I>(files: Vec<(u32, u64)>, entries: I) -> MasterIndex
+        where I: IntoIterator<Item = (u32, IterItem)>
+    {
+        let mut nodes: Vec<(u32, IterItem)> = entries.into_iter().collect();
+        nodes.sort_by(|a, b| (&a.1.oid, a.0).cmp(&(&b.1.oid, b.0)));
+
+        let mut oids: Vec<Oid> = Vec::with_capacity(nodes.len());
+        let mut nums = Vec::with_capacity(nodes.len());
+        let mut offsets = Vec::with_capacity(nodes.len());
+        let mut kind_names = vec![];
+        let mut kind_map = BTreeMap::new();
+        let mut kinds = Vec::with_capacity(nodes.len());
+        for (num, n) in nodes {
+            if oids.last() == Some(&n.oid) {
+                continue;
+            }
+            if !kind_map.contains_key(&n.kind) {
+                kind_map.insert(n.kind, kind_names.len() as u8);
+                kind_names.push(n.kind);
+            }
+            oids.push(n.oid);
+            nums.push(num);
+            offsets.push(n.offset);
+            kinds.push(kind_map[&n.kind]);
+        }
+
+        let alg = oids.first().map_or(HashAlg::Sha1, |oid| oid.alg());
+        let mut data = vec![];
+        encode(&mut data, &files, &oids, &nums, &offsets, &kind_names, &kinds)
+            .expect("Writing to a Vec can't fail");
+        MasterIndex::parse(Data::Built(data), alg).expect("Built master index is invalid")
+    }
+
+    /// Try loading the given master index file.  As with the per-file
+    /// index, the width of the hashes must be given.  Only the header and
+    /// the table of files are read, the rest of the file is read as the
+    /// lookups touch it.
+    pub fn load<P: AsRef<Path>>(path: P, alg: HashAlg) -> Result<MasterIndex> {
+        let map = Mmap::open_path(path, Protection::Read)?;
+        MasterIndex::parse(Data::Mapped(map), alg)
+    }
+
+    // Check the layout of an index, and find where each part of it is.
+    fn parse(data: Data, alg: HashAlg) -> Result<MasterIndex> {
+        let (files, count, top_pos, nums_pos, offsets_pos, kinds_pos, kind_names) = {
+            let buf = data.bytes();
+            if buf.len() < 16 || &buf[0..8] != b"ldumpmst" {
+                return Err(Error::InvalidIndex("bad magic".to_owned()));
+            }
+            if LittleEndian::read_u32(&buf[8..12]) != 2 {
+                return Err(Error::InvalidIndex("Version mismatch".to_owned()));
+            }
+
+            let file_count = LittleEndian::read_u32(&buf[12..16]) as usize;
+            let top_pos = 16 + file_count * 12;
+            if buf.len() < top_pos + TOP_SIZE {
+                return Err(Error::InvalidIndex("Index truncated".to_owned()));
+            }
+            let mut files = Vec::with_capacity(file_count);
+            for i in 0..file_count {
+                let pos = 16 + i * 12;
+                files.push((LittleEndian::read_u32(&buf[pos..pos + 4]),
+                            LittleEndian::read_u64(&buf[pos + 4..pos + 12])));
+            }
+
+            // The lookups rely on the top-level table being in order.
+            let mut prior = 0;
+            for i in 0..256 {
+                let pos = top_pos + 4 * i;
+                let elt = LittleEndian::read_u32(&buf[pos..pos + 4]);
+                if elt < prior {
+                    return Err(Error::InvalidIndex("Invalid top-level table".to_owned()));
+                }
+                prior = elt;
+            }
+            let count = prior as usize;
+
+            let nums_pos = top_pos + TOP_SIZE + count * alg.size();
+            let offsets_pos = nums_pos + count * 4;
+            let kind_count_pos = offsets_pos + count * 8;
+            if buf.len() < kind_count_pos + 4 {
+                return Err(Error::InvalidIndex("Index truncated".to_owned()));
+            }
+            let kind_count =
+                LittleEndian::read_u32(&buf[kind_count_pos..kind_count_pos + 4]) as usize;
+            let kinds_pos = kind_count_pos + 4 + kind_count * 4;
+            if buf.len() != kinds_pos + count {
+                return Err(Error::InvalidIndex("Index size is incorrect".to_owned()));
+            }
+
+            let mut kind_names = Vec::with_capacity(kind_count);
+            for i in 0..kind_count {
+                let pos = kind_count_pos + 4 + 4 * i;
+                let text = str::from_utf8(&buf[pos..pos + 4])
+                    .map_err(|_| Error::InvalidIndex("Invalid kind".to_owned()))?;
+                kind_names.push(Kind::new(text)?);
+            }
+
+            (files, count, top_pos, nums_pos, offsets_pos, kinds_pos, kind_names)
+        };
+
+        Ok(MasterIndex {
+            data: data,
+            alg: alg,
+            files: files,
+            count: count,
+            top_pos: top_pos,
+            oids_pos: top_pos + TOP_SIZE,
+            nums_pos: nums_pos,
+            offsets_pos: offsets_pos,
+            kinds_pos: kinds_pos,
+            kind_names: kind_names,
+        })
+    }
+
+    /// Write this index out.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let tmp_name = tmpify(path.as_ref())?;
+        {
+            let mut ofd = File::create(&tmp_name)?;
+            ofd.write_all(self.data.bytes())?;
+        }
+
+        fs::rename(tmp_name, path.as_ref())?;
+        Ok(())
+    }
+
+    /// Does this index cover the file with the given number, and is that
+    /// file still the same size?
+    pub fn covers(&self, num: u32, size: u64) -> bool {
+        self.files.contains(&(num, size))
+    }
+
+    /// The number and size of each file covered.
+    pub fn files(&self) -> &[(u32, u64)] {
+        &self.files
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Look up a chunk, returning the number of the file holding it, and
+    /// where in that file it is.
+    pub fn get(&self, key: &Oid) -> Option<(u32, IndexInfo)> {
+        self.find(key).and_then(|pos| self.entry(pos))
+    }
+
+    pub fn contains_key(&self, key: &Oid) -> bool {
+        self.find(key).is_some()
+    }
+
+    fn read_u32(&self, pos: usize) -> u32 {
+        LittleEndian::read_u32(&self.data.bytes()[pos..pos + 4])
+    }
+
+    fn oid_bytes(&self, num: usize) -> &[u8] {
+        let size = self.alg.size();
+        let pos = self.oids_pos + num * size;
+        &self.data.bytes()[pos..pos + size]
+    }
+
+    // The file number and location of the entry at `num`, or None if its
+    // kind isn't one the index has a name for.
+    fn entry(&self, num: usize) -> Option<(u32, IndexInfo)> {
+        let data = self.data.bytes();
+        let pos = self.offsets_pos + num * 8;
+        let offset = LittleEndian::read_u64(&data[pos..pos + 8]);
+        self.kind_names.get(data[self.kinds_pos + num] as usize).map(|&kind| {
+            (self.read_u32(self.nums_pos + num * 4),
+             IndexInfo {
+                offset: offset,
+                kind: kind,
+            })
+        })
+    }
+
+    fn find(&self, key: &Oid) -> Option<usize> {
+        if key.alg() != self.alg {
+            return None;
+        }
+
+        let first_byte = key[0] as usize;
+        let mut low = if first_byte > 0 {
+            self.read_u32(self.top_pos + 4 * (first_byte - 1)) as usize
+        } else {
+            0
+        };
+        let mut high = self.read_u32(self.top_pos + 4 * first_byte) as usize;
+
+        let key = key.as_bytes();
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.oid_bytes(mid).cmp(key) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    /// Iterate over the entries, along with the number of the file each is
+    /// in.
+    pub fn iter(&self) -> Iter {
+        Iter {
+            parent: self,
+            pos: 0,
+        }
+    }
+}
+
+// Write out the contents of an index, in the layout described above.
+fn encode<W: Write>(out: &mut W,
+                    files: &[(u32, u64)],
+                    oids: &[Oid],
+                    nums: &[u32],
+                    offsets: &[u64],
+                    kind_names: &[Kind],
+                    kinds: &[u8])
+                    -> io::Result<()> {
+    out.write_all(b"ldumpmst")?;
+    out.write_u32::<LittleEndian>(2)?;
+
+    out.write_u32::<LittleEndian>(files.len() as u32)?;
+    for &(num, size) in files {
+        out.write_u32::<LittleEndian>(num)?;
+        out.write_u64::<LittleEndian>(size)?;
+    }
+
+    for elt in compute_top(oids) {
+        out.write_u32::<LittleEndian>(elt)?;
+    }
+    for oid in oids {
+        out.write_all(oid.as_bytes())?;
+    }
+    for &num in nums {
+        out.write_u32::<LittleEndian>(num)?;
+    }
+    for &offset in offsets {
+        out.write_u64::<LittleEndian>(offset)?;
+    }
+
+    out.write_u32::<LittleEndian>(kind_names.len() as u32)?;
+    for &k in kind_names {
+        out.write_u32::<LittleEndian>(k.0)?;
+    }
+    out.write_all(kinds)?;
+    Ok(())
+}
+
+pub struct Iter<'a> {
+    parent: &'a MasterIndex,
+    pos: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (u32, IterItem);
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pos < self.parent.len() {
+            let pos = self.pos;
+            self.pos = pos + 1;
+
+            // Entries with an invalid kind are skipped, as they can't be
+            // looked up either.
+            if let Some((num, info)) = self.parent.entry(pos) {
+                return Some((num,
+                             IterItem {
+                    oid: Oid::from_raw_alg(self.parent.alg, self.parent.oid_bytes(pos)),
+                    kind: info.kind,
+                    offset: info.offset,
+                }));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {HashAlg, Kind, Oid};
+    use super::*;
+    use super::super::{IterItem, RamIndex};
+    use std::fs::{self, OpenOptions};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_master() {
+        let kind = Kind::new("blob").unwrap();
+        let mut ram0 = RamIndex::new();
+        let mut ram1 = RamIndex::new();
+        for i in 0..1000 {
+            ram0.insert(Oid::from_u32(i), i as u64 * 16, kind);
+            ram1.insert(Oid::from_u32(i + 900), i as u64 * 32, kind);
+        }
+
+        let entries = (&ram0).into_iter()
+            .map(|x| (0, x))
+            .chain((&ram1).into_iter().map(|x| (3, x)));
+        let master = MasterIndex::build(vec![(0, 16000), (3, 32000)], entries);
+        assert_eq!(master.len(), 1900);
+
+        let tmp = TempDir::new("master").unwrap();
+        let name = tmp.path().join("pool-master.idx");
+        master.save(&name).unwrap();
+        let master = MasterIndex::load(&name, HashAlg::Sha1).unwrap();
+
+        assert!(master.covers(0, 16000));
+        assert!(!master.covers(3, 32016));
+        for i in 0..1900 {
+            let (num, info) = master.get(&Oid::from_u32(i)).unwrap();
+            // Chunks in both files are found in the first.
+            if i < 1000 {
+                assert_eq!((num, info.offset), (0, i as u64 * 16));
+            } else {
+                assert_eq!((num, info.offset), (3, (i - 900) as u64 * 32));
+            }
+            assert_eq!(info.kind, kind);
+        }
+        assert!(!master.contains_key(&Oid::from_u32(1900)));
+
+        let items: Vec<(u32, IterItem)> = master.iter().collect();
+        assert_eq!(items.len(), 1900);
+
+        // A damaged file is rejected when loaded, rather than when searched.
+        let len = fs::metadata(&name).unwrap().len();
+        OpenOptions::new().write(true).open(&name).unwrap().set_len(len - 1).unwrap();
+        match MasterIndex::load(&name, HashAlg::Sha1) {
+            Err(Error::InvalidIndex(_)) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Loaded truncated master index"),
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! An MmapIndex searches an index file in place, by mapping it into
+//! memory.
+//!
+//! Loading a `FileIndex` has to read and decode the entire file, which for
+//! a large pool takes both time, and memory proportional to the number of
+//! chunks.  Mapping the file only validates the header, and the pages of
+//! the file are read as the lookups touch them.
+
+use byteorder::{ByteOrder, LittleEndian};
+use Error;
+use HashAlg;
+use Kind;
+use memmap::{Mmap, Protection};
+use Oid;
+use Result;
+use std::cmp::Ordering;
+use std::path::Path;
+use std::str;
+use super::{Index, IndexInfo, IterItem};
+
+// The layout of the index file is:
+//   offset  field
+//        0  magic "ldumpidx"
+//        8  version
+//       12  size of the pool file, u32 (version 4) or u64 (version 5)
+//    16/20  top-level table, 256 u32s
+//           oids, `count` of them
+//           offsets, `count` u32s (version 4) or u64s (version 5)
+//           number of kinds, then each kind
+//           kinds, `count` bytes
+const TOP_SIZE: usize = 256 * 4;
+
+pub struct MmapIndex {
+    map: Mmap,
+    alg: HashAlg,
+    count: usize,
+    // Are the offsets 64 bits?
+    wide: bool,
+    top_pos: usize,
+    oids_pos: usize,
+    offsets_pos: usize,
+    kinds_pos: usize,
+    kind_names: Vec<Kind>,
+}
+
+impl MmapIndex {
+    /// Map the given index file, checking that it is valid, and matches a
+    /// pool file of `size` bytes.
+    pub fn load<P: AsRef<Path>>(path: P, size: u64, alg: HashAlg) -> Result<MmapIndex> {
+        let map = Mmap::open_path(path, Protection::Read)?;
+
+        let (count, wide, top_pos, offsets_pos, kinds_pos, kind_names) = {
+            // Index files are only ever replaced by renaming a new file over
+            // them, and never modified in place, so the contents won't
+            // change under the mapping.
+            let data = unsafe { map.as_slice() };
+            if data.len() < 20 + TOP_SIZE || &data[0..8] != b"ldumpidx" {
+                return Err(Error::InvalidIndex("bad magic".to_owned()));
+            }
+            let (wide, file_size, top_pos) = match LittleEndian::read_u32(&data[8..12]) {
+                4 => (false, LittleEndian::read_u32(&data[12..16]) as u64, 16),
+                5 => (true, LittleEndian::read_u64(&data[12..20]), 20),
+                _ => return Err(Error::InvalidIndex("Version mismatch".to_owned())),
+            };
+            if file_size != size {
+                return Err(Error::InvalidIndex("Index size mismatch".to_owned()));
+            }
+
+            // The lookups rely on the top-level table being in order.
+            let mut prior = 0;
+            for i in 0..256 {
+                let pos = top_pos + 4 * i;
+                let elt = LittleEndian::read_u32(&data[pos..pos + 4]);
+                if elt < prior {
+                    return Err(Error::InvalidIndex("Invalid top-level table".to_owned()));
+                }
+                prior = elt;
+            }
+            let count = prior as usize;
+
+            let offsets_pos = top_pos + TOP_SIZE + count * alg.size();
+            let kind_count_pos = offsets_pos + count * if wide { 8 } else { 4 };
+            if data.len() < kind_count_pos + 4 {
+                return Err(Error::InvalidIndex("Index truncated".to_owned()));
+            }
+            let kind_count =
+                LittleEndian::read_u32(&data[kind_count_pos..kind_count_pos + 4]) as usize;
+            let kinds_pos = kind_count_pos + 4 + kind_count * 4;
+            if data.len() != kinds_pos + count {
+                return Err(Error::InvalidIndex("Index size is incorrect".to_owned()));
+            }
+
+            let mut kind_names = Vec::with_capacity(kind_count);
+            for i in 0..kind_count {
+                let pos = kind_count_pos + 4 + 4 * i;
+                let text = str::from_utf8(&data[pos..pos + 4])
+                    .map_err(|_| Error::InvalidIndex("Invalid kind".to_owned()))?;
+                kind_names.push(Kind::new(text)?);
+            }
+
+            (count, wide, top_pos, offsets_pos, kinds_pos, kind_names)
+        };
+
+        Ok(MmapIndex {
+            map: map,
+            alg: alg,
+            count: count,
+            wide: wide,
+            top_pos: top_pos,
+            oids_pos: top_pos + TOP_SIZE,
+            offsets_pos: offsets_pos,
+            kinds_pos: kinds_pos,
+            kind_names: kind_names,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn data(&self) -> &[u8] {
+        // See `load` for why this is safe.
+        unsafe { self.map.as_slice() }
+    }
+
+    fn read_u32(&self, pos: usize) -> u32 {
+        LittleEndian::read_u32(&self.data()[pos..pos + 4])
+    }
+
+    fn oid_bytes(&self, num: usize) -> &[u8] {
+        let size = self.alg.size();
+        let pos = self.oids_pos + num * size;
+        &self.data()[pos..pos + size]
+    }
+
+    fn info(&self, num: usize) -> IndexInfo {
+        let offset = if self.wide {
+            let pos = self.offsets_pos + num * 8;
+            LittleEndian::read_u64(&self.data()[pos..pos + 8])
+        } else {
+            self.read_u32(self.offsets_pos + num * 4) as u64
+        };
+        IndexInfo {
+            offset: offset,
+            kind: self.kind_names[self.data()[self.kinds_pos + num] as usize],
+        }
+    }
+
+    /// Binary search the file for a given hash, within the range the
+    /// top-level table gives for its first byte.
+    fn find(&self, key: &Oid) -> Option<usize> {
+        if key.alg() != self.alg {
+            return None;
+        }
+
+        let first_byte = key[0] as usize;
+        let mut low = if first_byte > 0 {
+            self.read_u32(self.top_pos + 4 * (first_byte - 1)) as usize
+        } else {
+            0
+        };
+        let mut high = self.read_u32(self.top_pos + 4 * first_byte) as usize;
+
+        let key = key.as_bytes();
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.oid_bytes(mid).cmp(key) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    pub fn iter(&self) -> Iter {
+        self.into_iter()
+    }
+}
+
+impl Index for MmapIndex {
+    fn contains_key(&self, key: &Oid) -> bool {
+        self.find(key).is_some()
+    }
+
+    fn get(&self, key: &Oid) -> Option<IndexInfo> {
+        self.find(key).map(|num| self.info(num))
+    }
+}
+
+impl<'a> IntoIterator for &'a MmapIndex {
+    type Item = IterItem;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter {
+            parent: self,
+            pos: 0,
+        }
+    }
+}
+
+pub struct Iter<'a> {
+    parent: &'a MmapIndex,
+    pos: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = IterItem;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.parent.len() {
+            None
+        } else {
+            let pos = self.pos;
+            self.pos = pos + 1;
+
+            let info = self.parent.info(pos);
+            Some(IterItem {
+                oid: Oid::from_raw_alg(self.parent.alg, self.parent.oid_bytes(pos)),
+                kind: info.kind,
+                offset: info.offset,
+            })
+        }
+    }
+}
@@ -1,7 +1,7 @@
 //! The hash index.
 //!
 //! Each backup file contains one or more chunks, which are identified by
-//! their sha1 hash.  To allow us to find chunks, we manage an index of
+//! their hash.  To allow us to find chunks, we manage an index of
 //! every chunk within the file.  This index is written to a separate file,
 //! and loaded into memory.  The file being written can also have additions
 //! made to its index in memory that are later written out.
@@ -9,9 +9,15 @@
 //! The types exposed here are the `Index` and `IndexUpdate` trait which
 //! are types that can be searched and updated respectively.  The
 //! `PairIndex` combines an index loaded from a file with an index in ram.
+//! The file is normally searched in place by an `MmapIndex`, with the
+//! `FileIndex` reading it in when it can't be mapped.
+//! The `MasterIndex` covers several files at once.
 
+use Error;
 use Kind;
 use Oid;
+use Result;
+use std::path::{Path, PathBuf};
 
 pub trait Index {
     fn contains_key(&self, key: &Oid) -> bool;
@@ -20,21 +26,22 @@ pub trait Index {
 
 #[derive(Debug, Clone)]
 pub struct IndexInfo {
-    pub offset: u32,
+    pub offset: u64,
     pub kind: Kind,
 }
 
 pub trait IndexUpdate {
     // Like a map insert, but panics if the key is already present.
-    fn insert(&mut self, key: Oid, offset: u32, kind: Kind);
+    fn insert(&mut self, key: Oid, offset: u64, kind: Kind);
 }
 
-/// All of the indices can be iterated, producing an IterItem.
+/// All of the indices can be iterated, producing an IterItem.  Not every
+/// index holds `Oid`s in memory to borrow, so the item holds a copy.
 #[derive(Debug)]
-pub struct IterItem<'a> {
-    pub oid: &'a Oid,
+pub struct IterItem {
+    pub oid: Oid,
     pub kind: Kind,
-    pub offset: u32,
+    pub offset: u64,
 }
 
 mod ram_index;
@@ -43,14 +50,49 @@ pub use self::ram_index::RamIndex;
 mod file_index;
 pub use self::file_index::FileIndex;
 
+mod mmap_index;
+pub use self::mmap_index::MmapIndex;
+
 mod pair_index;
 pub use self::pair_index::PairIndex;
 
+mod master_index;
+pub use self::master_index::MasterIndex;
+
+// Compute the top-level table of an index file from the sorted Oids.
+// Entry `n` is the number of Oids whose first byte is at most `n`.
+fn compute_top<'a, I>(oids: I) -> Vec<u32>
+    where I: IntoIterator<Item = &'a Oid>
+{
+    let mut top = vec![0u32; 256];
+    for oid in oids {
+        top[oid[0] as usize] += 1;
+    }
+    for first in 1..256 {
+        top[first] += top[first - 1];
+    }
+    top
+}
+
+// Given a filename, generate another with a ".tmp" suffix, if possible.
+fn tmpify(path: &Path) -> Result<PathBuf> {
+    let base = path.file_name()
+        .ok_or_else(|| Error::PathError(format!("path does not have a filename {:?}", path)));
+    let base = base?;
+
+    let base = base.to_str()
+        .ok_or_else(|| Error::PathError(format!("path isn't valid UTF-8 {:?}", path)));
+    let base = base?;
+
+    let tmp = format!("{}.tmp", base);
+    Ok(path.with_file_name(&tmp))
+}
+
 #[cfg(test)]
 mod test {
     use Error;
     use std::collections::BTreeMap;
-    use {Kind, Oid};
+    use {HashAlg, Kind, Oid};
     use super::*;
     use tempdir::TempDir;
 
@@ -81,7 +123,7 @@ mod test {
                 panic!("Test error, duplicate: {}", num);
             }
             let kind = self.kinds[num as usize % self.kinds.len()];
-            index.insert(Oid::from_u32(num), num, kind);
+            index.insert(Oid::from_u32(num), num as u64, kind);
             self.nodes.insert(num, kind);
         }
 
@@ -94,7 +136,7 @@ mod test {
                 match index.get(&oid) {
                     None => panic!("Couldn't find key"),
                     Some(info) => {
-                        assert_eq!(info.offset, num);
+                        assert_eq!(info.offset, num as u64);
                         assert_eq!(info.kind, kind);
                     }
                 }
@@ -132,20 +174,20 @@ mod test {
         track.check(&r1);
 
         let name1 = tmp.path().join("r1.idx");
-        FileIndex::save(&name1, COUNT, &r1).unwrap();
+        FileIndex::save(&name1, COUNT as u64, &r1).unwrap();
 
-        match PairIndex::load(&name1, COUNT - 1) {
+        match PairIndex::load(&name1, COUNT as u64 - 1, HashAlg::Sha1) {
             Err(Error::InvalidIndex(_)) => (),
             Err(e) => panic!("Unexpected error: {:?}", e),
             Ok(_) => panic!("Shouldn't be able to load index with size incorrect"),
         }
 
-        match PairIndex::load(&tmp.path().join("r1.bad"), COUNT) {
+        match PairIndex::load(&tmp.path().join("r1.bad"), COUNT as u64, HashAlg::Sha1) {
             Err(_) => (),
             Ok(_) => panic!("Shouldn't be able to load non-existant index"),
         }
 
-        let mut r2 = PairIndex::load(&name1, COUNT).unwrap();
+        let mut r2 = PairIndex::load(&name1, COUNT as u64, HashAlg::Sha1).unwrap();
         track.check(&r2);
 
         // Add some more.
@@ -155,15 +197,92 @@ mod test {
         track.check(&r2);
 
         let name2 = tmp.path().join("r2.idx");
-        FileIndex::save(&name2, 2 * COUNT, &r2).unwrap();
+        FileIndex::save(&name2, 2 * COUNT as u64, &r2).unwrap();
 
-        let r3 = PairIndex::load(&name2, 2 * COUNT).unwrap();
+        let r3 = PairIndex::load(&name2, 2 * COUNT as u64, HashAlg::Sha1).unwrap();
         track.check(&r3);
 
         // Print out the path, which will prevent it from being removed.
         // println!("Path: {:?}", tmp.into_path());
     }
 
+    #[test]
+    fn test_mmap() {
+        let tmp = TempDir::new("testindex").unwrap();
+
+        let mut track = Tracker::new();
+        let mut r1 = RamIndex::new();
+        for ofs in 0..5000 {
+            track.add(&mut r1, ofs);
+        }
+
+        let name = tmp.path().join("r1.idx");
+        FileIndex::save(&name, 5000, &r1).unwrap();
+
+        let mapped = MmapIndex::load(&name, 5000, HashAlg::Sha1).unwrap();
+        track.check(&mapped);
+        let read = FileIndex::load(&name, 5000, HashAlg::Sha1).unwrap();
+        for (a, b) in mapped.iter().zip(read.iter()) {
+            assert_eq!((a.oid, a.kind, a.offset), (b.oid, b.kind, b.offset));
+        }
+        assert_eq!(mapped.len(), read.len());
+
+        match MmapIndex::load(&name, 5001, HashAlg::Sha1) {
+            Err(Error::InvalidIndex(_)) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Shouldn't be able to load index with size incorrect"),
+        }
+        match MmapIndex::load(&name, 5000, HashAlg::Sha256) {
+            Err(Error::InvalidIndex(_)) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Shouldn't be able to load index with the wrong hash"),
+        }
+    }
+
+    #[test]
+    fn test_wide() {
+        use std::fs::File;
+        use std::io::Read;
+        use std::path::Path;
+
+        let tmp = TempDir::new("testindex").unwrap();
+        let kind = Kind::new("blob").unwrap();
+        let base = 5 << 30;
+
+        let mut r1 = RamIndex::new();
+        for num in 0..1000 {
+            r1.insert(Oid::from_u32(num), base + num as u64 * 16, kind);
+        }
+
+        // Only files too large for the older format use the new one.
+        let version = |name: &Path| {
+            let mut buf = vec![0u8; 12];
+            File::open(name).unwrap().read_exact(&mut buf).unwrap();
+            buf[8]
+        };
+        let small = tmp.path().join("small.idx");
+        FileIndex::save(&small, 1 << 20, &RamIndex::new()).unwrap();
+        assert_eq!(version(&small), 4);
+
+        let name = tmp.path().join("wide.idx");
+        FileIndex::save(&name, base + 16000, &r1).unwrap();
+        assert_eq!(version(&name), 5);
+
+        let mapped = MmapIndex::load(&name, base + 16000, HashAlg::Sha1).unwrap();
+        let read = FileIndex::load(&name, base + 16000, HashAlg::Sha1).unwrap();
+        for num in 0..1000 {
+            let oid = Oid::from_u32(num);
+            assert_eq!(mapped.get(&oid).unwrap().offset, base + num as u64 * 16);
+            assert_eq!(read.get(&oid).unwrap().offset, base + num as u64 * 16);
+        }
+
+        match PairIndex::load(&name, 16000, HashAlg::Sha1) {
+            Err(Error::InvalidIndex(_)) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Shouldn't be able to load index with size incorrect"),
+        }
+    }
+
     #[test]
     fn test_empty() {
         let fi = FileIndex::empty();
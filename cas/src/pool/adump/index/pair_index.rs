@@ -1,37 +1,52 @@
 //! A PairIndex combines a FileIndex with a RamIndex to allow in-memory
 //! updates to file data, that can then be written out.
 
+use Error;
+use HashAlg;
 use Kind;
 use Oid;
 use Result;
 use std::iter::Chain;
 use std::path::Path;
 use super::{Index, IndexUpdate, IndexInfo, IterItem};
-use super::{ram_index, RamIndex, file_index, FileIndex};
+use super::{ram_index, RamIndex, FileIndex, MmapIndex};
 
 /// A PairIndex combines a possibly loaded index with a ram index allowing
 /// for update.  The whole pair can then be written to a new index file,
 /// and loaded later.
 pub struct PairIndex {
-    file: FileIndex,
+    file: Loaded,
     ram: RamIndex,
 }
 
+// The index from the file is mapped into memory if possible, otherwise, it
+// is read in.
+enum Loaded {
+    Mapped(MmapIndex),
+    Read(FileIndex),
+}
+
 impl PairIndex {
-    pub fn load<P: AsRef<Path>>(path: P, size: u32) -> Result<PairIndex> {
+    pub fn load<P: AsRef<Path>>(path: P, size: u64, alg: HashAlg) -> Result<PairIndex> {
+        let file = match MmapIndex::load(path.as_ref(), size, alg) {
+            Ok(index) => Loaded::Mapped(index),
+            // Reading the file won't fix a problem with its contents.
+            Err(Error::InvalidIndex(msg)) => return Err(Error::InvalidIndex(msg)),
+            Err(_) => Loaded::Read(FileIndex::load(path, size, alg)?),
+        };
         Ok(PairIndex {
-            file: FileIndex::load(path, size)?,
+            file: file,
             ram: RamIndex::new(),
         })
     }
 
-    pub fn save<P: AsRef<Path>>(&self, path: P, size: u32) -> Result<()> {
+    pub fn save<P: AsRef<Path>>(&self, path: P, size: u64) -> Result<()> {
         FileIndex::save(path, size, self)
     }
 
     pub fn empty() -> PairIndex {
         PairIndex {
-            file: FileIndex::empty(),
+            file: Loaded::Read(FileIndex::empty()),
             ram: RamIndex::new(),
         }
     }
@@ -53,17 +68,37 @@ impl Index for PairIndex {
     }
 }
 
+impl Index for Loaded {
+    fn contains_key(&self, key: &Oid) -> bool {
+        match *self {
+            Loaded::Mapped(ref index) => index.contains_key(key),
+            Loaded::Read(ref index) => index.contains_key(key),
+        }
+    }
+
+    fn get(&self, key: &Oid) -> Option<IndexInfo> {
+        match *self {
+            Loaded::Mapped(ref index) => index.get(key),
+            Loaded::Read(ref index) => index.get(key),
+        }
+    }
+}
+
 impl IndexUpdate for PairIndex {
-    fn insert(&mut self, key: Oid, offset: u32, kind: Kind) {
+    fn insert(&mut self, key: Oid, offset: u64, kind: Kind) {
         self.ram.insert(key, offset, kind);
     }
 }
 
 impl<'a> IntoIterator for &'a PairIndex {
-    type Item = IterItem<'a>;
-    type IntoIter = Chain<file_index::Iter<'a>, ram_index::Iter<'a>>;
+    type Item = IterItem;
+    type IntoIter = Chain<Box<Iterator<Item = IterItem> + 'a>, ram_index::Iter<'a>>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.file.iter().chain(&self.ram)
+        let file: Box<Iterator<Item = IterItem> + 'a> = match self.file {
+            Loaded::Mapped(ref index) => Box::new(index.iter()),
+            Loaded::Read(ref index) => Box::new(index.iter()),
+        };
+        file.chain(&self.ram)
     }
 }
@@ -12,7 +12,7 @@ impl RamIndex {
         RamIndex(BTreeMap::new())
     }
 
-    pub fn insert(&mut self, id: Oid, offset: u32, kind: Kind) {
+    pub fn insert(&mut self, id: Oid, offset: u64, kind: Kind) {
         self.0.insert(id,
                       IndexInfo {
                           offset: offset,
@@ -36,7 +36,7 @@ impl Index for RamIndex {
 }
 
 impl IndexUpdate for RamIndex {
-    fn insert(&mut self, key: Oid, offset: u32, kind: Kind) {
+    fn insert(&mut self, key: Oid, offset: u64, kind: Kind) {
         match self.0.insert(key,
                             IndexInfo {
                                 kind: kind,
@@ -49,7 +49,7 @@ impl IndexUpdate for RamIndex {
 }
 
 impl<'a> IntoIterator for &'a RamIndex {
-    type Item = IterItem<'a>;
+    type Item = IterItem;
     type IntoIter = Iter<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
@@ -60,11 +60,11 @@ impl<'a> IntoIterator for &'a RamIndex {
 pub struct Iter<'a>(btree_map::Iter<'a, Oid, IndexInfo>);
 
 impl<'a> Iterator for Iter<'a> {
-    type Item = IterItem<'a>;
+    type Item = IterItem;
     fn next(&mut self) -> Option<Self::Item> {
         self.0.next().map(|(oid, info)| {
             IterItem {
-                oid: oid,
+                oid: oid.clone(),
                 kind: info.kind,
                 offset: info.offset,
             }
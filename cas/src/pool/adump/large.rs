@@ -0,0 +1,200 @@
+//! Large chunk files.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use Error;
+use HashAlg;
+use Kind;
+use Oid;
+use Result;
+use pool::{self, CheckItem, CheckReport};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+// Chunks larger than `MAX_CHUNK_SIZE` don't fit in the pool data files,
+// whose headers only have room for 32-bit lengths, and pools can set a
+// smaller `max_chunk_size` of their own.  Instead, each one is
+// stored, uncompressed, in its own file in the `large` directory, named
+// after its Oid.  The file starts with a header
+//  offset  length  field
+//       0      16  large-magic
+//      16       4  kind
+//      20       4  reserved, zero
+//      24       8  length of the data
+//      32     len  data
+//
+// As with the data files, numbers are little endian.  Legacy programs
+// don't know about this directory, and won't see these chunks.
+
+static MAGIC: &'static [u8] = b"adump-bigchunk1\n";
+
+fn large_dir(base: &Path) -> PathBuf {
+    base.join("large")
+}
+
+fn large_path(base: &Path, oid: &Oid) -> PathBuf {
+    large_dir(base).join(oid.to_hex())
+}
+
+/// Store all of the data from `source` as a large chunk in the pool at
+/// `base`, returning its Oid, and whether it wasn't already stored.  The
+/// data is written to a temporary file, which is renamed once the Oid is
+/// known.
+pub fn store(base: &Path, alg: HashAlg, kind: Kind, source: &mut Read) -> Result<(Oid, bool)> {
+    let dir = large_dir(base);
+    fs::create_dir_all(&dir)?;
+
+    let tmp = dir.join(format!("tmp-{}", Uuid::new_v4().simple()));
+    let oid = match write_tmp(&tmp, alg, kind, source) {
+        Ok(oid) => oid,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+    };
+
+    let dest = large_path(base, &oid);
+    if dest.is_file() {
+        fs::remove_file(&tmp)?;
+        Ok((oid, false))
+    } else {
+        fs::rename(&tmp, &dest)?;
+        Ok((oid, true))
+    }
+}
+
+/// Remove the large chunk `key` from the pool at `base`.
+pub fn remove(base: &Path, key: &Oid) -> Result<()> {
+    fs::remove_file(large_path(base, key))?;
+    Ok(())
+}
+
+fn write_tmp(tmp: &Path, alg: HashAlg, kind: Kind, source: &mut Read) -> Result<Oid> {
+    let mut fd = File::create(tmp)?;
+
+    // The length isn't known until the data has been read, so write the
+    // header again afterwards.
+    write_header(&mut fd, kind, 0)?;
+    let (oid, len) = {
+        let mut wr = BufWriter::new(&mut fd);
+        let result = pool::copy_hashed(alg, kind, source, &mut wr)?;
+        wr.flush()?;
+        result
+    };
+    fd.seek(SeekFrom::Start(0))?;
+    write_header(&mut fd, kind, len)?;
+    fd.sync_all()?;
+    Ok(oid)
+}
+
+fn write_header(fd: &mut File, kind: Kind, len: u64) -> Result<()> {
+    let mut header = Vec::with_capacity(32);
+    header.write_all(MAGIC)?;
+    header.write_all(&kind.bytes())?;
+    header.write_u32::<LittleEndian>(0)?;
+    header.write_u64::<LittleEndian>(len)?;
+    fd.write_all(&header)?;
+    Ok(())
+}
+
+/// Open the large chunk with this Oid, returning its kind, the length of
+/// its data, and a reader positioned at the start of the data.  Returns
+/// None if there is no such chunk.
+pub fn open(base: &Path, oid: &Oid) -> Result<Option<(Kind, u64, BufReader<File>)>> {
+    let fd = match File::open(large_path(base, oid)) {
+        Ok(fd) => fd,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut fd = BufReader::new(fd);
+
+    let mut magic = vec![0u8; 16];
+    fd.read_exact(&mut magic)?;
+    if &magic[..] != MAGIC {
+        return Err(Error::CorruptChunk(format!("{}: invalid large chunk header",
+                                               oid.to_hex())));
+    }
+
+    let mut kind = vec![0u8; 4];
+    fd.read_exact(&mut kind)?;
+    let kind = Kind::new(&String::from_utf8(kind)?)?;
+    fd.read_u32::<LittleEndian>()?;
+    let len = fd.read_u64::<LittleEndian>()?;
+
+    Ok(Some((kind, len, fd)))
+}
+
+/// The Oids of every large chunk in the pool at `base`.  Anything else in
+/// the directory is ignored.
+pub fn list(base: &Path, alg: HashAlg) -> Result<Vec<Oid>> {
+    let dir = large_dir(base);
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut result = vec![];
+    for ent in fs::read_dir(&dir)? {
+        let path = ent?.path();
+        let name = path.file_name().and_then(|x| x.to_str()).unwrap_or("");
+        if let Some(oid) = Oid::from_hex_alg(alg, name) {
+            result.push(oid);
+        }
+    }
+    Ok(result)
+}
+
+/// Check every large chunk in the pool at `base`, adding any problems to
+/// `report`.  `seen` holds the chunks found in the pool's data files.
+pub fn check(base: &Path,
+             alg: HashAlg,
+             report: &mut CheckReport,
+             seen: &mut HashSet<Oid>)
+             -> Result<()> {
+    let dir = large_dir(base);
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    for ent in fs::read_dir(&dir)? {
+        let path = ent?.path();
+        let location = path.display().to_string();
+        let name = path.file_name().and_then(|x| x.to_str()).unwrap_or("");
+        let oid = match Oid::from_hex_alg(alg, name) {
+            Some(oid) => oid,
+            None => {
+                report.orphaned.push(CheckItem::new(None, location, "not a large chunk"));
+                continue;
+            }
+        };
+
+        match check_one(base, alg, &oid) {
+            Ok(()) => report.good += 1,
+            Err(e) => {
+                let detail = e.to_string();
+                report.corrupt.push(CheckItem::new(Some(oid.clone()), location.clone(), detail));
+            }
+        }
+        if !seen.insert(oid.clone()) {
+            report.duplicate.push(CheckItem::new(Some(oid), location, "stored again"));
+        }
+    }
+    Ok(())
+}
+
+// Read the data of one large chunk, and check it against its Oid.
+fn check_one(base: &Path, alg: HashAlg, oid: &Oid) -> Result<()> {
+    let (kind, len, rd) = match open(base, oid)? {
+        Some(info) => info,
+        None => return Err(Error::MissingChunk),
+    };
+    let (actual, count) = pool::copy_hashed(alg, kind, &mut rd.take(len), &mut io::sink())?;
+    if count != len {
+        return Err(Error::CorruptChunk(format!("only {} of {} bytes present", count, len)));
+    }
+    if &actual != oid {
+        return Err(Error::HashMismatch(oid.clone(), actual));
+    }
+    Ok(())
+}
@@ -0,0 +1,102 @@
+//! Maintenance of the pool-wide master index.
+
+use HashAlg;
+use Result;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use super::{data_number, ChunkFile};
+use super::index::MasterIndex;
+
+// When enabled, the master index covers every pool file that is no longer
+// being written to, and is brought up to date each time the pool is
+// opened.  The files it covers are opened without loading their own
+// indices.  A file whose size no longer matches what the master index
+// recorded is opened normally, and its entries replaced.
+
+fn master_path(base: &Path) -> PathBuf {
+    base.join("pool-master.idx")
+}
+
+/// Open the pool files at `paths`, using the master index for those it
+/// still covers, and adding any other files that are done being written
+/// to.  Returns the files, the master index, and the position in the files
+/// of each file number the master index refers to.  Nothing is written if
+/// the pool is `readonly`.
+pub fn open_files(base: &Path,
+                  alg: HashAlg,
+                  newfile: bool,
+                  readonly: bool,
+                  paths: Vec<PathBuf>)
+                  -> Result<(Vec<ChunkFile>, MasterIndex, HashMap<u32, usize>)> {
+    let name = master_path(base);
+
+    // A missing or unreadable master index is rebuilt from scratch.
+    let old = MasterIndex::load(&name, alg).unwrap_or_else(|_| MasterIndex::empty());
+
+    // Unless each session writes a new file, the last file may still be
+    // appended to.
+    let sealed = if newfile || paths.is_empty() {
+        paths.len()
+    } else {
+        paths.len() - 1
+    };
+
+    let mut cfiles = Vec::with_capacity(paths.len());
+    let mut files = HashMap::new();
+    let mut kept = vec![];
+    let mut added = vec![];
+    for (pos, path) in paths.into_iter().enumerate() {
+        let num = match data_number(&path) {
+            Some(num) if pos < sealed => num,
+            _ => {
+                cfiles.push(ChunkFile::open(path, alg, false, readonly)?);
+                continue;
+            }
+        };
+        files.insert(num, pos);
+
+        let size = path.metadata()?.len();
+        if old.covers(num, size) {
+            kept.push((num, size));
+            cfiles.push(ChunkFile::open(path, alg, true, readonly)?);
+        } else {
+            // A file with a torn tail is left out until it is repaired.
+            let cfile = ChunkFile::open(path, alg, false, readonly)?;
+            if cfile.torn.is_none() {
+                added.push((num, cfile.size, pos));
+            }
+            cfiles.push(cfile);
+        }
+    }
+
+    if added.is_empty() && kept.len() == old.files().len() {
+        return Ok((cfiles, old, files));
+    }
+
+    let master = {
+        let keep: HashSet<u32> = kept.iter().map(|&(num, _)| num).collect();
+        let mut covered = kept.clone();
+        covered.extend(added.iter().map(|&(num, size, _)| (num, size)));
+        covered.sort();
+
+        let entries = old.iter()
+            .filter(|&(num, _)| keep.contains(&num))
+            .chain(added.iter().flat_map(|&(num, _, pos)| {
+                (&cfiles[pos].index).into_iter().map(move |ent| (num, ent))
+            }));
+        MasterIndex::build(covered, entries)
+    };
+
+    // Like the other indices, this only speeds things up, so a pool that
+    // can't be written to is still usable without saving it.
+    if !readonly {
+        let _ = master.save(&name);
+    }
+
+    // The newly covered files no longer need their own indices.
+    for &(_, _, pos) in &added {
+        cfiles[pos].cover();
+    }
+
+    Ok((cfiles, master, files))
+}
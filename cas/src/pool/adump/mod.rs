@@ -1,37 +1,71 @@
 // Adump file format.
 
+use Bloom;
 use Chunk;
+use MAX_CHUNK_SIZE;
+use Codec;
+use CompressPolicy;
 use Error;
+use HashAlg;
 use Kind;
 use Oid;
-use regex::Regex;
 use Result;
-use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, File, OpenOptions};
-use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
 use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use uuid::Uuid;
 
-use self::chunkio::{ChunkRead, ChunkWrite};
-use super::ChunkSource;
+use self::chunkio::{ChunkRead, ChunkWrite, CountingReader};
+use pool::{self, CheckItem, CheckReport, ChunkSource};
 
-use self::index::{Index, IndexUpdate, PairIndex};
+use self::index::{Index, IndexUpdate, MasterIndex, PairIndex};
 
 mod index;
 pub mod chunkio;
+mod compact;
+mod large;
+mod master;
 mod pfile;
+mod repair;
+mod salvage;
+mod writer;
+
+pub use self::compact::CompactReport;
+pub use self::repair::TornTail;
+pub use self::salvage::SalvageReport;
 
 pub struct AdumpPool {
     base: PathBuf,
     uuid: Uuid,
+    alg: HashAlg,
+    codec: Codec,
+    policy: CompressPolicy,
     newfile: bool,
-    limit: u32,
+    limit: u64,
+    max_chunk: usize,
+
+    // Was the pool opened with `open_readonly`.
+    readonly: bool,
 
     // Have we ever written to this pool in this session?
     dirty: bool,
 
-    cfiles: RefCell<Vec<ChunkFile>>,
+    // The backups that have been forgotten.
+    forgotten: HashSet<Oid>,
+
+    // The chunks in the `large` directory, so that looking for a chunk
+    // that isn't in the pool doesn't have to look there.
+    large: HashSet<Oid>,
+
+    cfiles: Vec<ChunkFile>,
+
+    // The master index, which is empty unless enabled for this pool, and
+    // where each file it refers to is in `cfiles`.
+    master: MasterIndex,
+    master_files: HashMap<u32, usize>,
 
     next_file: u32,
 }
@@ -40,19 +74,33 @@ impl AdumpPool {
     pub fn new_builder<P: AsRef<Path>>(dir: P) -> PoolBuilder<P> {
         PoolBuilder {
             dir: dir,
+            alg: HashAlg::Sha1,
+            codec: Codec::Zlib,
+            policy: CompressPolicy::new(),
             newfile: false,
             limit: 640 * 1024 * 1024,
+            max_chunk: MAX_CHUNK_SIZE,
+            master: false,
+            uuid: Uuid::new_v4(),
         }
     }
 
     pub fn open<P: AsRef<Path>>(dir: P) -> Result<AdumpPool> {
-        let base = dir.as_ref().to_owned();
-        let meta = base.join("metadata");
+        AdumpPool::open_with(dir.as_ref(), false)
+    }
 
-        let props = {
-            let fd = File::open(&meta.join("props.txt"))?;
-            pfile::parse(fd)?
-        };
+    /// Open the pool for reading only.  Nothing in the pool directory is
+    /// written, not even indices that are missing or out of date, which
+    /// are instead rebuilt in memory.  This allows pools on read-only
+    /// media or snapshots to be read.  Trying to write to the pool returns
+    /// `Error::ReadOnly`.
+    pub fn open_readonly<P: AsRef<Path>>(dir: P) -> Result<AdumpPool> {
+        AdumpPool::open_with(dir.as_ref(), true)
+    }
+
+    fn open_with(dir: &Path, readonly: bool) -> Result<AdumpPool> {
+        let base = dir.to_owned();
+        let props = read_props(&base)?;
         let uuid = props.get("uuid")
             .ok_or_else(|| Error::PropertyError("No uuid property".to_owned()))?;
         let uuid = Uuid::parse_str(&uuid)?;
@@ -61,21 +109,84 @@ impl AdumpPool {
         let newfile = newfile.parse::<bool>()?;
         let limit = props.get("limit")
             .ok_or_else(|| Error::PropertyError("No limit property".to_owned()))?;
-        let limit = limit.parse::<u32>()?;
+        let limit = limit.parse::<u64>()?;
+        let alg = props_alg(&props)?;
+        let max_chunk = pool::parse_max_chunk(props.get(pool::MAX_CHUNK_KEY).map(|x| &x[..]))?;
+        let codec = match props.get("codec") {
+            None => Codec::Zlib,
+            Some(name) => Codec::from_name(name)?,
+        };
+        let policy = CompressPolicy::from_props(|key| Ok(props.get(key).cloned()))?;
+        let use_master = match props.get("master") {
+            None => false,
+            Some(master) => master.parse::<bool>()?,
+        };
 
-        let (cfiles, next_file) = scan_backups(&base)?;
+        let forgotten = read_oids(&forgotten_path(&base), alg)?;
+        let large = large::list(&base, alg)?.into_iter().collect();
+
+        let (paths, next_file) = data_paths(&base)?;
+        let (cfiles, master, master_files) = if use_master {
+            master::open_files(&base, alg, newfile, readonly, paths)?
+        } else {
+            let cfiles: Vec<ChunkFile> = try!(paths.into_iter()
+                .map(|x| ChunkFile::open(x, alg, false, readonly))
+                .collect());
+            (cfiles, MasterIndex::empty(), HashMap::new())
+        };
 
         Ok(AdumpPool {
             base: base,
             uuid: uuid,
+            alg: alg,
+            codec: codec,
+            policy: policy,
             newfile: newfile,
             limit: limit,
+            max_chunk: max_chunk,
+            readonly: readonly,
             dirty: false,
-            cfiles: RefCell::new(cfiles),
+            forgotten: forgotten,
+            large: large,
+            cfiles: cfiles,
+            master: master,
+            master_files: master_files,
             next_file: next_file,
         })
     }
 
+    /// The data files that end with a chunk that can't be read, such as
+    /// after a crash in the middle of a write, along with where the
+    /// readable data in each ends.  The chunks before that can still be
+    /// read, and new chunks are written to a new file.  `repair` removes
+    /// the damaged tails.
+    pub fn torn_files(&self) -> Vec<(PathBuf, u64)> {
+        self.cfiles
+            .iter()
+            .filter_map(|cf| cf.torn.map(|end| (cf.name.clone(), end)))
+            .collect()
+    }
+
+    // Look for a chunk in the pool files, using the master index first.
+    fn find_small(&self, key: &Oid) -> Result<Option<Chunk>> {
+        if let Some((num, info)) = self.master.get(key) {
+            let cf = &self.cfiles[self.master_files[&num]];
+            return Ok(Some(cf.read_at(key, info.offset)?));
+        }
+
+        for cf in &self.cfiles {
+            if let Some(chunk) = cf.find(key)? {
+                return Ok(Some(chunk));
+            }
+        }
+        Ok(None)
+    }
+
+    // Is the chunk in one of the pool files?
+    fn contains_small(&self, key: &Oid) -> bool {
+        self.master.contains_key(key) || self.cfiles.iter().any(|cf| cf.contains_key(key))
+    }
+
     /// Does a write of size 'size' need a new pool file?
     fn needs_new_file(&self, size: u32) -> bool {
         // If we're configured in newfile mode, always write the new file.
@@ -83,49 +194,108 @@ impl AdumpPool {
             return true;
         }
 
-        match self.cfiles.borrow().last() {
+        // Nothing is appended after a torn tail.
+        match self.cfiles.last() {
             None => true,
-            Some(ref cf) => cf.size + size > self.limit,
+            Some(ref cf) => cf.torn.is_some() || cf.size + size as u64 > self.limit,
+        }
+    }
+
+    // Add a chunk to the end of the last pool file, starting a new one if
+    // needed.
+    fn write_chunk(&mut self, chunk: &Chunk) -> Result<()> {
+        pool::check_chunk(self.alg, self.max_chunk, chunk)?;
+
+        // Settle whether the chunk is compressed according to the policy.
+        // The chunk remembers the result, which is what gets written.
+        let _ = chunk.zdata_policy(self.codec, &self.policy);
+
+        if self.needs_new_file(write_size(chunk, self.codec)) {
+            let name = self.base.join(&format!("pool-data-{:04}.data", self.next_file));
+            self.next_file += 1;
+
+            println!("Needs new file: {:?}", name);
+            let cfile = ChunkFile::create(name, self.alg)?;
+            self.cfiles.push(cfile);
         }
+        self.dirty = true;
+
+        let cfile = self.cfiles.last_mut().expect("should've created a poolfile");
+
+        cfile.add(chunk, self.codec)
+    }
+
+    // Write out everything added to the pool files.
+    fn flush(&mut self) -> Result<()> {
+        for cfile in &mut self.cfiles {
+            cfile.flush()?;
+        }
+        Ok(())
     }
 }
 
-impl ChunkSource for AdumpPool {
+impl pool::ChunkRead for AdumpPool {
     fn find(&self, key: &Oid) -> Result<Chunk> {
-        let mut cfiles = self.cfiles.borrow_mut();
-        for cf in cfiles.iter_mut() {
-            match cf.find(key)? {
-                None => (),
-                Some(chunk) => return Ok(chunk),
+        if let Some(chunk) = self.find_small(key)? {
+            return pool::checked(chunk);
+        }
+
+        if !self.large.contains(key) {
+            return Err(Error::MissingChunk);
+        }
+
+        // Data given to `add_large` that turns out to be small enough is
+        // still returned as a chunk.
+        match large::open(&self.base, key)? {
+            None => Err(Error::MissingChunk),
+            Some((_, len, _)) if len > self.max_chunk as u64 => Err(Error::ChunkTooLarge(len)),
+            Some((kind, len, mut fd)) => {
+                let mut data = Vec::with_capacity(len as usize);
+                fd.take(len).read_to_end(&mut data)?;
+                let chunk = Chunk::new_plain_alg(self.alg, kind, data);
+                if chunk.oid() != key {
+                    return Err(Error::HashMismatch(key.clone(), chunk.oid().clone()));
+                }
+                Ok(chunk)
             }
         }
-        Err(Error::MissingChunk)
     }
 
     fn contains_key(&self, key: &Oid) -> Result<bool> {
-        let mut cfiles = self.cfiles.borrow_mut();
-        for cf in cfiles.iter_mut() {
-            if cf.contains_key(key) {
-                return Ok(true);
-            }
-        }
-        Ok(false)
+        Ok(self.contains_small(key) || self.large.contains(key))
     }
 
     fn uuid<'a>(&'a self) -> &'a Uuid {
         &self.uuid
     }
 
+    fn hash_alg(&self) -> HashAlg {
+        self.alg
+    }
+
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    fn compress_policy(&self) -> &CompressPolicy {
+        &self.policy
+    }
+
     fn backups(&self) -> Result<Vec<Oid>> {
         let back = Kind::new("back").unwrap();
         let mut result = vec![];
 
-        // Scan actual files for these.
-        let cfiles = self.cfiles.borrow();
-        for cfile in cfiles.iter() {
+        // Scan actual files for these.  Files covered by the master index
+        // don't have their own indices loaded.
+        for (_, ent) in self.master.iter() {
+            if ent.kind == back && !self.forgotten.contains(&ent.oid) {
+                result.push(ent.oid);
+            }
+        }
+        for cfile in &self.cfiles {
             for ent in &cfile.index {
-                if ent.kind == back {
-                    result.push(ent.oid.clone());
+                if ent.kind == back && !self.forgotten.contains(&ent.oid) {
+                    result.push(ent.oid);
                 }
             }
         }
@@ -133,46 +303,136 @@ impl ChunkSource for AdumpPool {
         Ok(result)
     }
 
-    fn begin_writing(&mut self) -> Result<()> {
-        Ok(())
+    fn seen(&self, peer: &Uuid) -> Result<Vec<Oid>> {
+        Ok(read_oids(&seen_path(&self.base, peer), self.alg)?.into_iter().collect())
     }
 
-    fn add(&mut self, chunk: &Chunk) -> Result<()> {
-        if self.needs_new_file(write_size(chunk)) {
-            let name = self.base.join(&format!("pool-data-{:04}.data", self.next_file));
-            self.next_file += 1;
+    fn peers(&self) -> Result<Vec<Uuid>> {
+        let dir = self.base.join("seen");
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+        // Anything else in the directory, such as a temporary file left
+        // behind by a crash, isn't a peer.
+        let mut result = vec![];
+        for ent in fs::read_dir(&dir)? {
+            let ent = ent?;
+            let name = ent.file_name();
+            if let Some(peer) = name.to_str().and_then(|name| Uuid::parse_str(name).ok()) {
+                result.push(peer);
+            }
+        }
+        Ok(result)
+    }
 
-            println!("Needs new file: {:?}", name);
-            self.cfiles.borrow_mut().push(ChunkFile::create(name)?);
+    fn max_chunk_size(&self) -> usize {
+        self.max_chunk
+    }
+
+    fn list(&self) -> Result<Vec<(Oid, Kind)>> {
+        // A chunk can be stored in more than one file, but is only listed
+        // once.
+        let mut listed = HashSet::new();
+        let mut result = vec![];
+        for (_, ent) in self.master.iter() {
+            if listed.insert(ent.oid.clone()) {
+                result.push((ent.oid, ent.kind));
+            }
         }
+        for cfile in &self.cfiles {
+            for ent in &cfile.index {
+                if listed.insert(ent.oid.clone()) {
+                    result.push((ent.oid, ent.kind));
+                }
+            }
+        }
+        for oid in &self.large {
+            if let Some((kind, _, _)) = large::open(&self.base, oid)? {
+                result.push((oid.clone(), kind));
+            }
+        }
+        Ok(result)
+    }
 
-        let mut cfiles = self.cfiles.borrow_mut();
-        let cfile = cfiles.last_mut().expect("should've created a poolfile");
+    fn read_large(&self, key: &Oid) -> Result<Box<Read + Send>> {
+        if let Some(chunk) = self.find_small(key)? {
+            return Ok(Box::new(Cursor::new(chunk.try_into_bytes()?)));
+        }
 
-        cfile.add(chunk)
+        if !self.large.contains(key) {
+            return Err(Error::MissingChunk);
+        }
+        match large::open(&self.base, key)? {
+            None => Err(Error::MissingChunk),
+            Some((_, len, fd)) => Ok(Box::new(fd.take(len))),
+        }
     }
 
-    fn flush(&mut self) -> Result<()> {
-        for cfile in self.cfiles.borrow_mut().iter_mut() {
-            cfile.flush()?;
+    fn check(&self) -> Result<CheckReport> {
+        let mut report = CheckReport::new();
+        let mut seen = HashSet::new();
+        for cfile in &self.cfiles {
+            cfile.check(&mut report, &mut seen)?;
         }
+        large::check(&self.base, self.alg, &mut report, &mut seen)?;
+        Ok(report)
+    }
+}
+
+impl ChunkSource for AdumpPool {
+    fn writer<'a>(&'a mut self) -> Result<Box<pool::ChunkWrite + 'a>> {
+        if self.readonly {
+            return Err(Error::ReadOnly);
+        }
+        Ok(Box::new(writer::AdumpWriter::new(self)))
+    }
+
+    fn forget(&mut self, backups: &[Oid]) -> Result<()> {
+        if self.readonly {
+            return Err(Error::ReadOnly);
+        }
+        let mut forgotten = self.forgotten.clone();
+        forgotten.extend(backups.iter().cloned());
+        write_oids(&forgotten_path(&self.base), &forgotten)?;
+        self.forgotten = forgotten;
         Ok(())
     }
+
+    fn add_seen(&mut self, peer: &Uuid, backups: &[Oid]) -> Result<()> {
+        if self.readonly {
+            return Err(Error::ReadOnly);
+        }
+        let dir = self.base.join("seen");
+        if !dir.is_dir() {
+            fs::create_dir(&dir)?;
+        }
+        let name = seen_path(&self.base, peer);
+        let mut seen = read_oids(&name, self.alg)?;
+        seen.extend(backups.iter().cloned());
+        write_oids(&name, &seen)
+    }
 }
 
-fn write_size(chunk: &Chunk) -> u32 {
-    let payload = match chunk.zdata() {
+fn write_size(chunk: &Chunk, codec: Codec) -> u32 {
+    let payload = match chunk.zdata_with(codec) {
         Some(p) => p,
         None => chunk.data(),
     };
-    48 + ((payload.len() + 15) & !15) as u32
+    chunkio::header_size(chunk.oid().alg(), chunk.zcodec()) +
+    ((payload.len() + 15) & !15) as u32
 }
 
 /// A builder to set parameters before creating a pool.
 pub struct PoolBuilder<P: AsRef<Path>> {
     dir: P,
+    alg: HashAlg,
+    codec: Codec,
+    policy: CompressPolicy,
     newfile: bool,
-    limit: u32,
+    limit: u64,
+    max_chunk: usize,
+    master: bool,
+    uuid: Uuid,
 }
 
 impl<P: AsRef<Path>> PoolBuilder<P> {
@@ -186,21 +446,71 @@ impl<P: AsRef<Path>> PoolBuilder<P> {
         self
     }
 
+    /// Change the hash algorithm used for the chunks in this pool.  Pools
+    /// using anything other than SHA-1 can't be read by legacy programs.
+    pub fn set_hash(mut self, alg: HashAlg) -> Self {
+        self.alg = alg;
+        self
+    }
+
+    /// Change the codec used to compress new chunks written to this pool.
+    /// Chunks compressed with anything other than zlib can't be read by
+    /// legacy programs.
+    pub fn set_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Set the policy deciding which chunks are compressed.  Legacy
+    /// programs ignore the policy, but can still read the pool.
+    pub fn set_policy(mut self, policy: CompressPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
     /// Change the default value of the `limit` flag on the pool.  No
-    /// individual pool file will grow larger than this value.  Note that
-    /// this is a u32, but it is best to not allow the value to exceed a
-    /// positive i32 to avoid compatibility issues with legacy programs
-    /// that may read this format.
-    pub fn set_limit(mut self, limit: u32) -> Self {
+    /// individual pool file will grow larger than this value.  Files over
+    /// 4 GiB have indices in a newer format, and values over 2 GiB can
+    /// cause compatibility issues with legacy programs that may read this
+    /// format.
+    pub fn set_limit(mut self, limit: u64) -> Self {
         self.limit = limit;
         self
     }
 
+    /// Change the size of the largest chunk stored in the pool files.  Data
+    /// larger than this can only be stored with `add_large`, in its own
+    /// file, and read back with `read_large`.  This can't be more than
+    /// `MAX_CHUNK_SIZE`, the default.  A smaller value is mostly useful
+    /// for testing large chunks without writing so much data.  Legacy
+    /// programs don't see large chunks at all.
+    pub fn set_max_chunk_size(mut self, size: usize) -> Self {
+        self.max_chunk = size;
+        self
+    }
+
+    /// Keep a master index, merging the indices of all of the pool files
+    /// that are no longer written to.  This keeps opening the pool, and
+    /// looking up chunks, fast as the number of files grows.  Legacy
+    /// programs ignore the master index.
+    pub fn set_master_index(mut self, master: bool) -> Self {
+        self.master = master;
+        self
+    }
+
+    /// Give the pool this uuid, rather than a new one.  This is for pools
+    /// converted from another pool, which keep the uuid of the original.
+    pub fn set_uuid(mut self, uuid: Uuid) -> Self {
+        self.uuid = uuid;
+        self
+    }
+
     /// Actually create the pool.  The given path must name either an empty
     /// directory, or a path where one can be created.
     pub fn create(self) -> Result<()> {
         // The given directory must represent either an empty directory, or
         // a path that a new directory can be created at.
+        pool::check_max_chunk(self.max_chunk)?;
         let base = self.dir.as_ref();
         ensure_dir(base)?;
         let meta = base.join("metadata");
@@ -211,9 +521,24 @@ impl<P: AsRef<Path>> PoolBuilder<P> {
 
         {
             let mut fd = File::create(meta.join("props.txt"))?;
-            writeln!(&mut fd, "uuid={}", Uuid::new_v4().hyphenated())?;
+            writeln!(&mut fd, "uuid={}", self.uuid.hyphenated())?;
             writeln!(&mut fd, "newfile={}", self.newfile)?;
             writeln!(&mut fd, "limit={}", self.limit)?;
+            if self.alg != HashAlg::Sha1 {
+                writeln!(&mut fd, "hash={}", self.alg.name())?;
+            }
+            if self.codec != Codec::Zlib {
+                writeln!(&mut fd, "codec={}", self.codec.name())?;
+            }
+            if self.max_chunk != MAX_CHUNK_SIZE {
+                writeln!(&mut fd, "{}={}", pool::MAX_CHUNK_KEY, self.max_chunk)?;
+            }
+            if self.master {
+                writeln!(&mut fd, "master=true")?;
+            }
+            for (key, value) in self.policy.to_props() {
+                writeln!(&mut fd, "{}={}", key, value)?;
+            }
         }
 
         File::create(meta.join("backups.txt"))?;
@@ -240,10 +565,76 @@ fn ensure_dir(base: &Path) -> Result<()> {
     Ok(())
 }
 
-// Scan the directory for backup files.
-fn scan_backups(base: &Path) -> Result<(Vec<ChunkFile>, u32)> {
-    let reg = Regex::new(r"^pool-data-(\d\d\d\d).data").unwrap();
+// Read the properties file of the pool at `base`.
+fn read_props(base: &Path) -> Result<BTreeMap<String, String>> {
+    let fd = File::open(&base.join("metadata").join("props.txt"))?;
+    pfile::parse(fd)
+}
+
+// Lists of backups are kept in files with the Oid of each, in hex, on its
+// own line.  The `backups.txt` file in the metadata directory lists the
+// backups that have been forgotten.  The `seen` directory has a file for
+// each pool that has been synced from, named by its uuid, listing the
+// backups from that pool that have been seen.
+fn forgotten_path(base: &Path) -> PathBuf {
+    base.join("metadata").join("backups.txt")
+}
 
+fn seen_path(base: &Path, peer: &Uuid) -> PathBuf {
+    base.join("seen").join(peer.hyphenated().to_string())
+}
+
+// Read a list of backups, which is empty if the file is missing.
+fn read_oids(name: &Path, alg: HashAlg) -> Result<HashSet<Oid>> {
+    let mut text = String::new();
+    match File::open(name) {
+        Ok(mut fd) => {
+            fd.read_to_string(&mut text)?;
+        }
+        // Pools created by legacy programs may not have the file.
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut result = HashSet::new();
+    for line in text.lines().map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        let oid = Oid::from_hex_alg(alg, line).ok_or_else(|| {
+            Error::CorruptPool(format!("Invalid line in {:?}: {:?}", name, line))
+        })?;
+        result.insert(oid);
+    }
+    Ok(result)
+}
+
+// Replace a list of backups, by renaming a new file over it.
+fn write_oids(name: &Path, oids: &HashSet<Oid>) -> Result<()> {
+    let tmp_name = name.with_extension("tmp");
+    let mut hexes: Vec<String> = oids.iter().map(|oid| oid.to_hex()).collect();
+    hexes.sort();
+    {
+        let mut fd = File::create(&tmp_name)?;
+        for hex in &hexes {
+            writeln!(&mut fd, "{}", hex)?;
+        }
+        fd.sync_all()?;
+    }
+    fs::rename(&tmp_name, name)?;
+    Ok(())
+}
+
+// Determine the hash algorithm from a pool's properties.  Pools created
+// before the hash was configurable don't have this property, and are
+// SHA-1.
+fn props_alg(props: &BTreeMap<String, String>) -> Result<HashAlg> {
+    match props.get("hash") {
+        None => Ok(HashAlg::Sha1),
+        Some(name) => HashAlg::from_name(name),
+    }
+}
+
+// Find the data files in the pool directory, in order, along with the
+// number to use for the next one.
+fn data_paths(base: &Path) -> Result<(Vec<PathBuf>, u32)> {
     let mut bpaths = vec![];
     let mut next_file = 0;
 
@@ -256,37 +647,74 @@ fn scan_backups(base: &Path) -> Result<(Vec<ChunkFile>, u32)> {
             Some(ext) if ext == "data" => true,
             _ => false,
         } {
-            match name.file_name()
-                .and_then(|x| x.to_str())
-                .and_then(|x| reg.captures(x)) {
-                Some(cap) => {
-                    let num = cap.at(1).unwrap().parse::<u32>().unwrap() + 1;
-                    if num > next_file {
-                        next_file = num;
-                    }
-                }
-                None => (),
+            match data_number(&name) {
+                Some(num) if num + 1 > next_file => next_file = num + 1,
+                _ => (),
             }
             bpaths.push(name);
         }
     }
     bpaths.sort();
 
-    // Open all of the files.
-    Ok((try!(bpaths.into_iter().map(|x| ChunkFile::open(x)).collect()), next_file))
+    Ok((bpaths, next_file))
+}
+
+// Is this the error from opening a file that doesn't exist?
+fn is_not_found(err: &Error) -> bool {
+    match *err {
+        Error::Io(ref err) => err.kind() == io::ErrorKind::NotFound,
+        _ => false,
+    }
+}
+
+// The number in the name of a pool data file, if it has one.  The names
+// are `pool-data-NNNN.data`, with exactly four digits.
+fn data_number(path: &Path) -> Option<u32> {
+    let name = match path.file_name().and_then(|x| x.to_str()) {
+        Some(name) => name.as_bytes(),
+        None => return None,
+    };
+    let prefix: &[u8] = b"pool-data-";
+    let len = prefix.len();
+    if name.len() < len + 9 || !name.starts_with(prefix) || &name[len + 4..len + 9] != b".data" {
+        return None;
+    }
+    let digits = &name[len..len + 4];
+    if !digits.iter().all(|&b| b >= b'0' && b <= b'9') {
+        return None;
+    }
+    Some(digits.iter().fold(0, |num, &b| num * 10 + (b - b'0') as u32))
 }
 
 struct ChunkFile {
     name: PathBuf,
     index: PairIndex,
+    alg: HashAlg,
+
+    // A filter over the Oids in the index, consulted first, so that most
+    // lookups of chunks that aren't in this file don't need the index.
+    bloom: Bloom,
+
+    // Files covered by the master index don't load their own index or
+    // bloom filter, and both are left empty, until they are written to.
+    covered: bool,
+
+    // Files of a read-only pool never have their index or bloom filter
+    // saved.
+    readonly: bool,
 
     // The BufReader or BufWriter holding the descriptor (or nothing, if it
-    // isn't opened at all.
-    buf: ReadWriter,
+    // isn't opened at all.  Reads only need a shared reference to the
+    // file, so the descriptor is behind a lock.
+    buf: Mutex<ReadWriter>,
     // True if the underlying file descriptor is opened for writing.
     writable: bool,
     // The known size of the file.  Should always be updated after writes.
-    size: u32,
+    size: u64,
+    // Where the readable data ends, if the file ends with a chunk that
+    // can't be read, such as after a crash in the middle of a write.
+    // Nothing more is written to such a file.
+    torn: Option<u64>,
 }
 
 enum ReadWriter {
@@ -296,31 +724,131 @@ enum ReadWriter {
 }
 
 impl ChunkFile {
-    fn open(p: PathBuf) -> Result<ChunkFile> {
+    fn open(p: PathBuf, alg: HashAlg, covered: bool, readonly: bool) -> Result<ChunkFile> {
         let m = p.metadata()?;
         if !m.is_file() {
             return Err(Error::CorruptPool(format!("file {:?} is not a regular file", p)));
         }
         let size = m.len();
-        if size > i32::max_value() as u64 {
-            return Err(Error::CorruptPool(format!("file {:?} is larger than 2^31", p)));
-        }
-        let index_name = p.with_extension("idx");
-        let index = match PairIndex::load(&index_name, size as u32) {
-            Ok(x) => x,
-            Err(e @ Error::InvalidIndex(_)) => return Err(e),
-            Err(e) => return Err(Error::InvalidIndex(format!("Index error in {:?}, {:?}", p, e))),
+
+        let (index, bloom, torn) = if covered {
+            (PairIndex::empty(), Bloom::for_oids(0), None)
+        } else {
+            let (index, torn) = ChunkFile::load_index(&p, size, alg, !readonly)?;
+            let bloom = ChunkFile::load_bloom(&p, size, &index, !readonly);
+            (index, bloom, torn)
         };
         Ok(ChunkFile {
             name: p,
             index: index,
-            buf: ReadWriter::None,
+            alg: alg,
+            bloom: bloom,
+            covered: covered,
+            readonly: readonly,
+            buf: Mutex::new(ReadWriter::None),
             writable: false,
-            size: size as u32,
+            size: size,
+            torn: torn,
         })
     }
 
-    fn create(p: PathBuf) -> Result<ChunkFile> {
+    // Load the index of the data file `p`, along with where its readable
+    // data ends, if it has a torn tail (see `rebuild_index`).
+    fn load_index(p: &Path,
+                  size: u64,
+                  alg: HashAlg,
+                  save: bool)
+                  -> Result<(PairIndex, Option<u64>)> {
+        // The index can be missing, truncated, or not match the data, if
+        // the pool wasn't flushed completely.  The data file has everything
+        // needed to regenerate it.  Other errors, such as not being allowed
+        // to read the index, are returned.
+        match PairIndex::load(p.with_extension("idx"), size, alg) {
+            Ok(x) => Ok((x, None)),
+            Err(Error::InvalidIndex(_)) => ChunkFile::rebuild_index(p, size, alg, save),
+            Err(ref e) if e.is_unexpected_eof() || is_not_found(e) => {
+                ChunkFile::rebuild_index(p, size, alg, save)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Generate a new index for the data file `p` by reading every chunk in
+    // it, and write it out if `save` is set.  A file that ends with a
+    // chunk that can't be read is only indexed up to that chunk, and where
+    // the readable data ends is returned as well.  The index of such a
+    // file isn't saved, so that the damage is found again the next time
+    // the pool is opened, until the pool is repaired.
+    fn rebuild_index(p: &Path,
+                     size: u64,
+                     alg: HashAlg,
+                     save: bool)
+                     -> Result<(PairIndex, Option<u64>)> {
+        let mut rd = CountingReader::new(BufReader::new(File::open(p)?));
+        let mut index = PairIndex::empty();
+        let mut torn = None;
+        while rd.position() < size {
+            let pos = rd.position();
+            let chunk = match rd.read_chunk() {
+                Ok(chunk) => chunk,
+                Err(Error::Io(err)) => {
+                    if err.kind() != io::ErrorKind::UnexpectedEof {
+                        return Err(Error::Io(err));
+                    }
+                    torn = Some(pos);
+                    break;
+                }
+                Err(_) => {
+                    torn = Some(pos);
+                    break;
+                }
+            };
+            if chunk.oid().alg() != alg {
+                return Err(Error::CorruptPool(format!("{:?} contains a {} chunk at offset {}",
+                                                      p,
+                                                      chunk.oid().alg().name(),
+                                                      pos)));
+            }
+            if !index.contains_key(chunk.oid()) {
+                index.insert(chunk.oid().to_owned(), pos, chunk.kind());
+            }
+        }
+
+        if !save || torn.is_some() {
+            return Ok((index, torn));
+        }
+        let index_name = p.with_extension("idx");
+        index.save(&index_name, size)?;
+        Ok((PairIndex::load(&index_name, size, alg)?, None))
+    }
+
+    // Load the bloom filter for the data file `p`, or build it from the
+    // index if it is missing or stale, saving it if `save` is set.
+    fn load_bloom(p: &Path, size: u64, index: &PairIndex, save: bool) -> Bloom {
+        let name = p.with_extension("bloom");
+        match Bloom::load(&name, size) {
+            Ok(bloom) => bloom,
+            Err(_) => {
+                let bloom = ChunkFile::build_bloom(index);
+                // The filter only speeds up lookups, so a pool that can't
+                // be written to is still usable without saving it.
+                if save {
+                    let _ = bloom.save(&name, size);
+                }
+                bloom
+            }
+        }
+    }
+
+    fn build_bloom(index: &PairIndex) -> Bloom {
+        let mut bloom = Bloom::for_oids(index.into_iter().count());
+        for ent in index {
+            bloom.add(&ent.oid);
+        }
+        bloom
+    }
+
+    fn create(p: PathBuf, alg: HashAlg) -> Result<ChunkFile> {
         if p.is_file() {
             panic!("Pool file shouldn't be present for creation");
         }
@@ -329,48 +857,165 @@ impl ChunkFile {
         Ok(ChunkFile {
             name: p,
             index: PairIndex::empty(),
-            buf: ReadWriter::Write(BufWriter::new(fd)),
+            alg: alg,
+            // Sized for a typical file, it is rebuilt to fit when flushed.
+            bloom: Bloom::for_oids(65536),
+            covered: false,
+            readonly: false,
+            buf: Mutex::new(ReadWriter::Write(BufWriter::new(fd))),
             writable: true,
             size: 0,
+            torn: None,
         })
     }
 
     fn contains_key(&self, key: &Oid) -> bool {
-        self.index.contains_key(key)
+        self.bloom.maybe_contains(key) && self.index.contains_key(key)
     }
 
     // Read a chunk from this file, if that is possible.
-    fn find(&mut self, key: &Oid) -> Result<Option<Chunk>> {
+    fn find(&self, key: &Oid) -> Result<Option<Chunk>> {
+        if !self.bloom.maybe_contains(key) {
+            return Ok(None);
+        }
         match self.index.get(key) {
             None => Ok(None),
-            Some(info) => {
-                let fd = self.read()?;
-                fd.seek(SeekFrom::Start(info.offset as u64))?;
-                let ch = fd.read_chunk()?;
-                Ok(Some(ch))
-            }
+            Some(info) => Ok(Some(self.read_at(key, info.offset)?)),
+        }
+    }
+
+    // Read the chunk `key`, that the index says is at `offset`.
+    fn read_at(&self, key: &Oid, offset: u64) -> Result<Chunk> {
+        let mut buf = self.buf.lock().unwrap();
+        let fd = buf.reader(&self.name)?;
+        fd.seek(SeekFrom::Start(offset))?;
+        let ch = fd.read_chunk()?;
+        if ch.oid() != key {
+            return Err(Error::HashMismatch(key.clone(), ch.oid().clone()));
         }
+        Ok(ch)
+    }
+
+    // Drop the index and bloom filter, once the master index covers this
+    // file.
+    fn cover(&mut self) {
+        self.index = PairIndex::empty();
+        self.bloom = Bloom::for_oids(0);
+        self.covered = true;
+    }
+
+    // Load the index and bloom filter of a covered file, as they are
+    // needed to write to it.
+    fn uncover(&mut self) -> Result<()> {
+        if self.covered {
+            let (index, torn) = ChunkFile::load_index(&self.name, self.size, self.alg, true)?;
+            self.index = index;
+            self.torn = torn;
+            self.bloom = ChunkFile::load_bloom(&self.name, self.size, &self.index, true);
+            self.covered = false;
+        }
+        Ok(())
     }
 
     // Add a chunk to this file.
-    fn add(&mut self, chunk: &Chunk) -> Result<()> {
+    fn add(&mut self, chunk: &Chunk, codec: Codec) -> Result<()> {
+        self.uncover()?;
+
         let pos;
         let size;
         {
-            let fd = self.write()?;
-            pos = fd.seek(SeekFrom::End(0))? as u32;
-            fd.write_chunk(chunk)?;
-            size = fd.seek(SeekFrom::Current(0))? as u32;
+            let fd = self.buf.get_mut().unwrap().writer(&self.name, self.writable)?;
+            pos = fd.seek(SeekFrom::End(0))?;
+            fd.write_chunk(chunk, codec)?;
+            size = fd.seek(SeekFrom::Current(0))?;
         }
 
         self.index.insert(chunk.oid().to_owned(), pos, chunk.kind());
+        self.bloom.add(chunk.oid());
         self.size = size;
         Ok(())
     }
 
-    // Write the index out if this file is dirty.
+    // Read every chunk in this file, checking it, and that it agrees with
+    // the index.  `seen` holds the chunks found in earlier files, so that
+    // duplicates can be reported.
+    fn check(&self, report: &mut CheckReport, seen: &mut HashSet<Oid>) -> Result<()> {
+        let location = |pos: u64| format!("{}@{}", self.name.display(), pos);
+
+        let loaded;
+        let index = if self.covered {
+            loaded = ChunkFile::load_index(&self.name, self.size, self.alg, !self.readonly)?.0;
+            &loaded
+        } else {
+            &self.index
+        };
+
+        // The chunks found in the file, by offset.
+        let mut found = HashMap::new();
+        {
+            let mut buf = self.buf.lock().unwrap();
+            let fd = buf.reader(&self.name)?;
+            fd.seek(SeekFrom::Start(0))?;
+            let mut rd = CountingReader::new(fd);
+            while rd.position() < self.size {
+                let pos = rd.position();
+                let chunk = match rd.read_chunk_unverified() {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        // Without a valid header, there is no way to find
+                        // the next chunk.
+                        let detail = format!("{}, last {} bytes not checked",
+                                             e,
+                                             self.size - pos);
+                        report.corrupt.push(CheckItem::new(None, location(pos), detail));
+                        break;
+                    }
+                };
+
+                let oid = chunk.oid().clone();
+                match chunk.verify() {
+                    Ok(()) => report.good += 1,
+                    Err(e) => {
+                        report.corrupt
+                            .push(CheckItem::new(Some(oid.clone()), location(pos), e.to_string()))
+                    }
+                }
+                if !index.contains_key(&oid) {
+                    report.orphaned
+                        .push(CheckItem::new(Some(oid.clone()), location(pos), "not in index"));
+                }
+                if !seen.insert(oid.clone()) {
+                    report.duplicate
+                        .push(CheckItem::new(Some(oid.clone()), location(pos), "stored again"));
+                }
+                found.insert(pos, (oid, chunk.kind()));
+            }
+        }
+
+        // Every index entry must refer to one of those chunks.
+        for ent in index {
+            let item = |detail: String| {
+                CheckItem::new(Some(ent.oid.clone()), location(ent.offset), detail)
+            };
+            match found.get(&ent.offset) {
+                None => report.missing.push(item("no chunk at indexed offset".to_owned())),
+                Some(&(ref oid, _)) if oid != &ent.oid => {
+                    report.corrupt.push(item(format!("indexed offset holds {}", oid.to_hex())))
+                }
+                Some(&(_, kind)) if kind != ent.kind => {
+                    report.corrupt
+                        .push(item(format!("indexed as {:?}, chunk is {:?}", ent.kind, kind)))
+                }
+                Some(_) => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Write the index and bloom filter out if this file is dirty.
     fn flush(&mut self) -> Result<()> {
-        match self.buf {
+        match *self.buf.get_mut().unwrap() {
             ReadWriter::Write(ref mut wr) => wr.flush()?,
             _ => (),
         }
@@ -379,18 +1024,46 @@ impl ChunkFile {
             let index_name = self.name.with_extension("idx");
             self.index.save(&index_name, self.size)?;
 
-            mem::replace(&mut self.index, PairIndex::load(&index_name, self.size)?);
+            mem::replace(&mut self.index, PairIndex::load(&index_name, self.size, self.alg)?);
+
+            self.bloom = ChunkFile::build_bloom(&self.index);
+            let _ = self.bloom.save(self.name.with_extension("bloom"), self.size);
+        }
+        Ok(())
+    }
+
+    // Discard everything written to this file after it was `size` bytes
+    // long, along with its entries in the index.
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        if self.size == size {
+            return Ok(());
         }
+
+        // Dropping a writer flushes it, which doesn't matter, as the data
+        // is about to be cut off anyway.
+        *self.buf.get_mut().unwrap() = ReadWriter::None;
+        self.writable = false;
+        OpenOptions::new().write(true).open(&self.name)?.set_len(size)?;
+        self.size = size;
+
+        // The index may have been saved with the discarded entries, in
+        // which case it no longer matches, and is regenerated.
+        let (index, torn) = ChunkFile::load_index(&self.name, size, self.alg, true)?;
+        self.index = index;
+        self.torn = torn;
+        self.bloom = ChunkFile::load_bloom(&self.name, size, &self.index, true);
         Ok(())
     }
+}
 
+impl ReadWriter {
     // Configure the state for reading, and borrow the reader.
-    fn read(&mut self) -> Result<&mut BufReader<File>> {
-        match self.buf {
+    fn reader(&mut self, name: &Path) -> Result<&mut BufReader<File>> {
+        match *self {
             ReadWriter::None => {
-                let file = File::open(&self.name)?;
-                self.buf = ReadWriter::Read(BufReader::new(file));
-                return self.read();
+                let file = File::open(name)?;
+                *self = ReadWriter::Read(BufReader::new(file));
+                return self.reader(name);
             }
             ReadWriter::Read(ref mut rd) => return Ok(rd),
             ReadWriter::Write(_) => (),
@@ -399,7 +1072,7 @@ impl ChunkFile {
         // Writable files will always be opened for reading as well.
         // Consuming the buffer flushes it, so we can wrap it in a read
         // buffer.
-        let wr = mem::replace(&mut self.buf, ReadWriter::None);
+        let wr = mem::replace(self, ReadWriter::None);
         let fd = if let ReadWriter::Write(buf) = wr {
             match buf.into_inner() {
                 Ok(fd) => fd,
@@ -417,58 +1090,59 @@ impl ChunkFile {
         } else {
             panic!("Unexpected path");
         };
-        self.buf = ReadWriter::Read(BufReader::new(fd));
-        self.read()
+        *self = ReadWriter::Read(BufReader::new(fd));
+        self.reader(name)
     }
 
     // Configure the state for writing, and borrow the writer.
-    fn write(&mut self) -> Result<&mut BufWriter<File>> {
-        match self.buf {
+    fn writer(&mut self, name: &Path, writable: bool) -> Result<&mut BufWriter<File>> {
+        match *self {
             ReadWriter::Write(ref mut wr) => return Ok(wr),
             _ => (),
         }
 
         // If it is opened for writing, we can steal the handle.
-        if self.writable {
-            let rd = mem::replace(&mut self.buf, ReadWriter::None);
+        if writable {
+            let rd = mem::replace(self, ReadWriter::None);
             let fd = if let ReadWriter::Read(buf) = rd {
                 buf.into_inner()
             } else {
                 panic!("Unexpected code path");
             };
-            self.buf = ReadWriter::Write(BufWriter::new(fd));
+            *self = ReadWriter::Write(BufWriter::new(fd));
         } else {
             // If it is opened, close it.
-            self.buf = ReadWriter::None;
+            *self = ReadWriter::None;
 
             // And open a fresh descriptor for writing.
             let fd = OpenOptions::new().read(true)
                 .write(true)
                 .append(true)
-                .open(&self.name)?;
-            self.buf = ReadWriter::Write(BufWriter::new(fd));
+                .open(name)?;
+            *self = ReadWriter::Write(BufWriter::new(fd));
         }
-        self.write()
+        self.writer(name, writable)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use Kind;
+    use {Chunk, Codec, CompressPolicy, HashAlg, Kind};
     use rand::{Rng, StdRng};
     use tempdir::TempDir;
     use testutil;
     use super::*;
-    use pool::ChunkSource;
+    use pool::{ChunkRead, ChunkSource, ChunkWrite};
 
     struct Tracker {
         nodes: Vec<(u32, Kind)>,
         kinds: Vec<Kind>,
+        alg: HashAlg,
         rng: StdRng,
     }
 
     impl Tracker {
-        fn new() -> Tracker {
+        fn new(alg: HashAlg) -> Tracker {
             let mut kinds = vec![];
             for text in &["blob", "idx0", "idx1", "data", "dir "] {
                 kinds.push(Kind::new(text).unwrap());
@@ -477,23 +1151,39 @@ mod test {
             Tracker {
                 nodes: vec![],
                 kinds: kinds,
+                alg: alg,
                 rng: StdRng::new().unwrap(),
             }
         }
 
-        fn add<P: ChunkSource>(&mut self, pool: &mut P) {
+        fn make_chunk(&self, kind: Kind, size: u32, num: u32) -> Chunk {
+            Chunk::new_plain_alg(self.alg,
+                                 kind,
+                                 testutil::make_random_string(size, num).into_bytes())
+        }
+
+        fn add(&mut self, wr: &mut ChunkWrite) {
             let num = self.nodes.len() as u32;
             let size = self.rng.gen_range(16u32, 1024);
             let kind = self.kinds[size as usize % self.kinds.len()];
-            let chunk = testutil::make_kinded_random_chunk(kind, size, num);
-            pool.add(&chunk).unwrap();
+            let chunk = self.make_chunk(kind, size, num);
+            wr.add(&chunk).unwrap();
 
             self.nodes.push((size, kind));
         }
 
-        fn check<P: ChunkSource>(&self, pool: &P) {
+        // Add `count` chunks to the pool, committing them.
+        fn add_all(&mut self, pool: &mut ChunkSource, count: usize) {
+            let mut wr = pool.writer().unwrap();
+            for _ in 0..count {
+                self.add(&mut *wr);
+            }
+            wr.commit().unwrap();
+        }
+
+        fn check(&self, pool: &ChunkRead) {
             for (i, &(size, kind)) in self.nodes.iter().enumerate() {
-                let expect = testutil::make_kinded_random_chunk(kind, size, i as u32);
+                let expect = self.make_chunk(kind, size, i as u32);
                 let got = pool.find(expect.oid()).unwrap();
                 assert_eq!(&got.data()[..], &expect.data()[..]);
             }
@@ -502,42 +1192,544 @@ mod test {
 
     #[test]
     fn test_pool() {
-        let mut tr = Tracker::new();
+        check_pool(HashAlg::Sha1, Codec::Zlib);
+    }
+
+    #[test]
+    fn test_pool_wide() {
+        check_pool(HashAlg::Sha256, Codec::Zlib);
+        check_pool(HashAlg::Blake2b256, Codec::Zlib);
+    }
+
+    #[test]
+    fn test_pool_codec() {
+        check_pool(HashAlg::Sha1, Codec::Zstd);
+        check_pool(HashAlg::Sha1, Codec::Lz4);
+        check_pool(HashAlg::Sha256, Codec::None);
+    }
+
+    fn check_pool(alg: HashAlg, codec: Codec) {
+        let mut tr = Tracker::new(alg);
         let tmp = TempDir::new("adump").unwrap();
         let name = tmp.path().join("blort");
-        AdumpPool::new_builder(&name).create().unwrap();
+        AdumpPool::new_builder(&name).set_hash(alg).set_codec(codec).create().unwrap();
 
         // println!("Path: {:?}", tmp.into_path());
 
         {
             let mut pool = AdumpPool::open(&name).unwrap();
+            assert_eq!(pool.hash_alg(), alg);
+            assert_eq!(pool.codec(), codec);
             assert_eq!(pool.backups().unwrap().len(), 0);
 
-            for _ in 1..1000 {
-                tr.add(&mut pool);
+            tr.add_all(&mut pool, 999);
+            tr.check(&pool);
+        }
+
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            tr.check(&pool);
+            {
+                let mut wr = pool.writer().unwrap();
+                for _ in 1..500 {
+                    tr.add(&mut *wr);
+                }
+                tr.check(wr.reader());
+                for _ in 1..500 {
+                    tr.add(&mut *wr);
+                }
+                wr.commit().unwrap();
             }
-            pool.flush().unwrap();
+            tr.check(&pool);
+        }
 
+        {
+            let pool = AdumpPool::open(&name).unwrap();
             tr.check(&pool);
         }
+    }
+
+    #[test]
+    fn test_pool_policy() {
+        let tmp = TempDir::new("adump").unwrap();
+        let name = tmp.path().join("blort");
+        let policy = CompressPolicy::new().skip_kind("idx*").set_min_saving(5);
+        AdumpPool::new_builder(&name).set_policy(policy.clone()).create().unwrap();
 
+        let mut tr = Tracker::new(HashAlg::Sha1);
         {
             let mut pool = AdumpPool::open(&name).unwrap();
-            tr.check(&pool);
-            for _ in 1..500 {
-                tr.add(&mut pool);
+            assert_eq!(pool.compress_policy(), &policy);
+            tr.add_all(&mut pool, 199);
+        }
+
+        let pool = AdumpPool::open(&name).unwrap();
+        assert_eq!(pool.compress_policy(), &policy);
+        tr.check(&pool);
+        for (i, &(size, kind)) in tr.nodes.iter().enumerate() {
+            let chunk = pool.find(tr.make_chunk(kind, size, i as u32).oid()).unwrap();
+            if !policy.wants(kind) {
+                assert_eq!(chunk.zcodec(), Codec::None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_corrupt_chunk() {
+        let tmp = TempDir::new("adump").unwrap();
+        let name = tmp.path().join("blort");
+        AdumpPool::new_builder(&name).create().unwrap();
+
+        let chunk = testutil::make_random_chunk(4096, 1);
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            let mut wr = pool.writer().unwrap();
+            wr.add(&chunk).unwrap();
+            wr.commit().unwrap();
+        }
+        assert!(chunk.zdata().is_some());
+
+        // Clobber the start of the compressed payload.
+        {
+            let mut fd = OpenOptions::new()
+                .write(true)
+                .open(name.join("pool-data-0000.data"))
+                .unwrap();
+            fd.seek(SeekFrom::Start(48)).unwrap();
+            fd.write_all(&[0u8; 16]).unwrap();
+        }
+
+        let pool = AdumpPool::open(&name).unwrap();
+        match pool.find(chunk.oid()) {
+            Err(Error::CorruptChunk(_)) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Corrupt chunk was returned"),
+        }
+    }
+
+    #[test]
+    fn test_shared_reads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let tmp = TempDir::new("adump").unwrap();
+        let name = tmp.path().join("blort");
+        AdumpPool::new_builder(&name).create().unwrap();
+
+        let mut oids = vec![];
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            let mut wr = pool.writer().unwrap();
+            for i in 0..200 {
+                let chunk = testutil::make_random_chunk(i * 10 + 16, i);
+                wr.add(&chunk).unwrap();
+                oids.push(chunk.oid().clone());
             }
+            wr.commit().unwrap();
+        }
+
+        let pool = Arc::new(AdumpPool::open(&name).unwrap());
+        let oids = Arc::new(oids);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let pool = pool.clone();
+                let oids = oids.clone();
+                thread::spawn(move || {
+                    for oid in oids.iter().rev() {
+                        pool.find(oid).unwrap().verify().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for th in threads {
+            th.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_data_number() {
+        assert_eq!(data_number(Path::new("/pool/pool-data-0000.data")), Some(0));
+        assert_eq!(data_number(Path::new("pool-data-0172.data")), Some(172));
+        assert_eq!(data_number(Path::new("pool-data-12.data")), None);
+        assert_eq!(data_number(Path::new("pool-data-12345.data")), None);
+        assert_eq!(data_number(Path::new("pool-data-00x1.data")), None);
+        assert_eq!(data_number(Path::new("other-0001.data")), None);
+    }
+
+    #[test]
+    fn test_rebuild_index() {
+        let tmp = TempDir::new("adump").unwrap();
+        let name = tmp.path().join("blort");
+        AdumpPool::new_builder(&name).set_hash(HashAlg::Sha256).create().unwrap();
+        let index_name = name.join("pool-data-0000.idx");
+        let saved_name = name.join("saved.idx");
+
+        let mut tr = Tracker::new(HashAlg::Sha256);
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            tr.add_all(&mut pool, 100);
+        }
+
+        // A missing index is regenerated.
+        fs::rename(&index_name, &saved_name).unwrap();
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            assert!(index_name.is_file());
+            tr.check(&pool);
+            tr.add_all(&mut pool, 100);
+        }
+
+        // As is one that doesn't cover all of the data.
+        fs::rename(&saved_name, &index_name).unwrap();
+        {
+            let pool = AdumpPool::open(&name).unwrap();
+            tr.check(&pool);
+            assert_eq!(tr.nodes.len(), 200);
+        }
+
+        // But an index that can't be read at all is an error, rather than
+        // being replaced.
+        fs::remove_file(&index_name).unwrap();
+        fs::create_dir(&index_name).unwrap();
+        match AdumpPool::open(&name) {
+            Err(Error::Io(_)) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Opened pool with unreadable index"),
+        }
+        assert!(index_name.is_dir());
+    }
+
+    #[test]
+    fn test_bloom() {
+        let tmp = TempDir::new("adump").unwrap();
+        let name = tmp.path().join("blort");
+        AdumpPool::new_builder(&name).set_limit(64 * 1024).create().unwrap();
+
+        let mut tr = Tracker::new(HashAlg::Sha1);
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            tr.add_all(&mut pool, 500);
+            assert!(pool.cfiles.len() > 1);
+        }
+
+        let bloom0 = name.join("pool-data-0000.bloom");
+        assert!(bloom0.is_file());
+        assert!(name.join("pool-data-0001.bloom").is_file());
+
+        // A missing filter is regenerated.
+        fs::remove_file(&bloom0).unwrap();
+        let pool = AdumpPool::open(&name).unwrap();
+        assert!(bloom0.is_file());
+        tr.check(&pool);
+
+        for i in 0..1000 {
+            let absent = tr.make_chunk(Kind::new("none").unwrap(), 100, i);
+            assert!(!pool.contains_key(absent.oid()).unwrap());
+            assert!(pool.find(absent.oid()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_master() {
+        let tmp = TempDir::new("adump").unwrap();
+        let name = tmp.path().join("blort");
+        let master_name = name.join("pool-master.idx");
+        AdumpPool::new_builder(&name)
+            .set_newfile(true)
+            .set_master_index(true)
+            .create()
+            .unwrap();
+
+        let mut tr = Tracker::new(HashAlg::Sha1);
+        let back = Chunk::new_plain(Kind::new("back").unwrap(), b"backup".to_vec());
+        for session in 0..3 {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            assert_eq!(pool.master.files().len(), session);
             tr.check(&pool);
-            for _ in 1..500 {
-                tr.add(&mut pool);
+            let mut wr = pool.writer().unwrap();
+            for _ in 0..100 {
+                tr.add(&mut *wr);
             }
-            pool.flush().unwrap();
+            if session == 1 {
+                wr.add(&back).unwrap();
+            }
+            wr.commit().unwrap();
+        }
+
+        // Every file is covered, and none of them have their index loaded.
+        {
+            let pool = AdumpPool::open(&name).unwrap();
+            assert_eq!(pool.master.files().len(), 3);
+            assert_eq!(pool.master.len(), 301);
+            assert!(pool.cfiles.iter().all(|cf| cf.covered && cf.index.into_iter().count() == 0));
             tr.check(&pool);
+            assert_eq!(pool.backups().unwrap(), vec![back.oid().clone()]);
+            assert!(pool.check().unwrap().is_clean());
         }
 
+        // A file that has changed isn't trusted.  Its chunks are still
+        // readable, up to the damage, but it is left out of the master
+        // index until it is repaired.
+        let data_name = name.join("pool-data-0001.data");
+        let good_size = fs::metadata(&data_name).unwrap().len();
+        {
+            let mut fd = OpenOptions::new()
+                .write(true)
+                .append(true)
+                .open(&data_name)
+                .unwrap();
+            fd.write_all(&[0u8; 16]).unwrap();
+        }
         {
             let pool = AdumpPool::open(&name).unwrap();
+            assert_eq!(pool.torn_files(), vec![(data_name.clone(), good_size)]);
+            assert_eq!(pool.master.files().len(), 2);
             tr.check(&pool);
         }
+        AdumpPool::repair(&name).unwrap();
+
+        // And a missing master index is just rebuilt.
+        fs::remove_file(&master_name).unwrap();
+        let pool = AdumpPool::open(&name).unwrap();
+        assert!(master_name.is_file());
+        assert_eq!(pool.master.files().len(), 3);
+        tr.check(&pool);
+    }
+
+    #[test]
+    fn test_master_append() {
+        let tmp = TempDir::new("adump").unwrap();
+        let name = tmp.path().join("blort");
+        AdumpPool::new_builder(&name)
+            .set_limit(64 * 1024)
+            .set_master_index(true)
+            .create()
+            .unwrap();
+
+        let mut tr = Tracker::new(HashAlg::Sha1);
+        for _ in 0..3 {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            tr.check(&pool);
+            tr.add_all(&mut pool, 200);
+        }
+
+        // The last file can still be appended to, so isn't covered.
+        let pool = AdumpPool::open(&name).unwrap();
+        assert!(pool.cfiles.len() > 2);
+        assert_eq!(pool.master.files().len(), pool.cfiles.len() - 1);
+        assert!(!pool.cfiles.last().unwrap().covered);
+        tr.check(&pool);
+    }
+
+    #[test]
+    fn test_check() {
+        let tmp = TempDir::new("adump").unwrap();
+        let name = tmp.path().join("blort");
+        AdumpPool::new_builder(&name).set_newfile(true).create().unwrap();
+
+        // Uncompressible data is stored as is, so damage to it can be made
+        // at a known place.
+        let bad = testutil::make_uncompressible_chunk(4096, 1);
+        let mut chunks = vec![];
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            {
+                let mut wr = pool.writer().unwrap();
+                wr.add(&bad).unwrap();
+                for i in 0..20 {
+                    let chunk = testutil::make_random_chunk(i * 100 + 16, i);
+                    wr.add(&chunk).unwrap();
+                    chunks.push(chunk);
+                }
+                wr.commit().unwrap();
+            }
+
+            let report = pool.check().unwrap();
+            assert!(report.is_clean());
+            assert_eq!(report.good, 21);
+        }
+
+        // Writers don't store chunks that are already present, but writing
+        // to the files directly does, in a new file for this session.
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            pool.write_chunk(&chunks[3]).unwrap();
+            pool.flush().unwrap();
+        }
+        {
+            let mut fd = OpenOptions::new()
+                .write(true)
+                .open(name.join("pool-data-0000.data"))
+                .unwrap();
+            fd.seek(SeekFrom::Start(48 + 100)).unwrap();
+            fd.write_all(b"damage").unwrap();
+        }
+
+        let report = AdumpPool::open(&name).unwrap().check().unwrap();
+        assert_eq!(report.good, 21);
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!(report.corrupt[0].oid.as_ref(), Some(bad.oid()));
+        assert_eq!(report.duplicate.len(), 1);
+        assert_eq!(report.duplicate[0].oid.as_ref(), Some(chunks[3].oid()));
+        assert!(report.missing.is_empty());
+        assert!(report.orphaned.is_empty());
+    }
+
+    #[test]
+    fn test_large() {
+        let tmp = TempDir::new("adump").unwrap();
+        let name = tmp.path().join("blort");
+        AdumpPool::new_builder(&name).set_max_chunk_size(256 * 1024).create().unwrap();
+
+        let mut pool = AdumpPool::open(&name).unwrap();
+        testutil::check_large_chunks(&mut pool);
+
+        // The large chunks are found by a fresh open.
+        let mut pool = AdumpPool::open(&name).unwrap();
+        let other = Chunk::new_plain(Kind::new("blob").unwrap(), b"hello".to_vec());
+        assert!(!pool.contains_key(other.oid()).unwrap());
+        assert_eq!(fs::read_dir(name.join("large")).unwrap().count(), 2);
+        assert_eq!(pool.large.len(), 2);
+        assert_eq!(pool.max_chunk_size(), 256 * 1024);
+
+        // A large chunk that is rolled back is gone again.
+        let oid = {
+            let mut wr = pool.writer().unwrap();
+            let data = &mut io::repeat(0x33).take(300 * 1024);
+            let oid = wr.add_large(Kind::new("blob").unwrap(), data).unwrap();
+            assert!(wr.reader().contains_key(&oid).unwrap());
+            oid
+        };
+        assert!(!pool.contains_key(&oid).unwrap());
+        assert_eq!(pool.large.len(), 2);
+        assert_eq!(fs::read_dir(name.join("large")).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_forget() {
+        use std::collections::BTreeSet;
+
+        let tmp = TempDir::new("adump").unwrap();
+        let name = tmp.path().join("blort");
+        AdumpPool::new_builder(&name).create().unwrap();
+
+        let listed = {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            testutil::check_forget(&mut pool)
+        };
+
+        // The forgotten backups are recorded in the metadata, and stay
+        // forgotten until the pool is compacted.
+        let mut pool = AdumpPool::open(&name).unwrap();
+        let backups: BTreeSet<_> = pool.backups().unwrap().into_iter().collect();
+        assert_eq!(backups, listed);
+        assert_eq!(pool.forgotten.len(), 3);
+
+        let live = listed.iter().cloned().collect();
+        pool.compact(&live).unwrap();
+        assert!(pool.forgotten.is_empty());
+        let backups: BTreeSet<_> = pool.backups().unwrap().into_iter().collect();
+        assert_eq!(backups, listed);
+        assert!(read_oids(&forgotten_path(&name), HashAlg::Sha1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_seen() {
+        let tmp = TempDir::new("adump").unwrap();
+        let name = tmp.path().join("blort");
+        AdumpPool::new_builder(&name).create().unwrap();
+
+        let peer = Uuid::new_v4();
+        let mut oids: Vec<Oid> = (1..4).map(Oid::from_u32).collect();
+        oids.sort();
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            assert!(pool.peers().unwrap().is_empty());
+            pool.add_seen(&peer, &oids).unwrap();
+        }
+
+        // Stray files, such as one left while writing a list, aren't
+        // taken as peers.
+        let seen = name.join("seen");
+        File::create(seen.join(format!("{}.tmp", Uuid::new_v4().hyphenated()))).unwrap();
+        File::create(seen.join("README")).unwrap();
+
+        let pool = AdumpPool::open_readonly(&name).unwrap();
+        assert_eq!(pool.peers().unwrap(), vec![peer]);
+        let mut got = pool.seen(&peer).unwrap();
+        got.sort();
+        assert_eq!(got, oids);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let tmp = TempDir::new("adump").unwrap();
+        let name = tmp.path().join("blort");
+        AdumpPool::new_builder(&name).set_limit(64 * 1024).create().unwrap();
+
+        let mut tr = Tracker::new(HashAlg::Sha1);
+        let mut pool = AdumpPool::open(&name).unwrap();
+        tr.add_all(&mut pool, 50);
+        let files = pool.cfiles.len();
+        let size = pool.cfiles.last().unwrap().size;
+
+        // Enough to spill into new files, and a large chunk.
+        let chunks: Vec<Chunk> =
+            (0..500).map(|i| testutil::make_random_chunk(1000, i + 1000)).collect();
+        let large = {
+            let mut wr = pool.writer().unwrap();
+            for chunk in &chunks {
+                wr.add(chunk).unwrap();
+            }
+            let data = testutil::make_random_string(1000, 2000).into_bytes();
+            let large = wr.add_large(Kind::new("blob").unwrap(), &mut &data[..]).unwrap();
+            assert!(wr.reader().contains_key(&large).unwrap());
+            large
+        };
+
+        assert_eq!(pool.cfiles.len(), files);
+        assert_eq!(pool.cfiles.last().unwrap().size, size);
+        assert!(!pool.contains_key(&large).unwrap());
+        for chunk in &chunks {
+            assert!(!pool.contains_key(chunk.oid()).unwrap());
+        }
+
+        // The pool can still be written to, and is intact when reopened.
+        tr.add_all(&mut pool, 50);
+        drop(pool);
+        let pool = AdumpPool::open(&name).unwrap();
+        tr.check(&pool);
+        assert!(pool.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_readonly() {
+        let tmp = TempDir::new("adump").unwrap();
+        let name = tmp.path().join("blort");
+        AdumpPool::new_builder(&name).create().unwrap();
+
+        let mut tr = Tracker::new(HashAlg::Sha1);
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            tr.add_all(&mut pool, 100);
+        }
+
+        // Missing indices are rebuilt, but not written out.
+        let index_name = name.join("pool-data-0000.idx");
+        let bloom_name = name.join("pool-data-0000.bloom");
+        fs::remove_file(&index_name).unwrap();
+        fs::remove_file(&bloom_name).unwrap();
+
+        let mut pool = AdumpPool::open_readonly(&name).unwrap();
+        tr.check(&pool);
+        assert!(pool.check().unwrap().is_clean());
+        match pool.writer() {
+            Err(Error::ReadOnly) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Read-only pool was writable"),
+        }
+        assert!(!index_name.exists());
+        assert!(!bloom_name.exists());
     }
 }
+
@@ -0,0 +1,250 @@
+//! Recovery of pool files after a crash.
+
+use Error;
+use HashAlg;
+use Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use super::{data_paths, props_alg, read_props, AdumpPool};
+use super::chunkio::{self, ChunkRead, CountingReader};
+
+/// The damaged end of a pool data file, removed by `AdumpPool::repair`.
+#[derive(Debug)]
+pub struct TornTail {
+    /// The data file that was truncated.
+    pub file: PathBuf,
+    /// The offset the file was truncated to, which is the end of the last
+    /// valid chunk.
+    pub offset: u64,
+    /// The number of bytes removed.
+    pub length: u64,
+    /// The file holding the bytes that were removed.
+    pub saved: PathBuf,
+}
+
+impl AdumpPool {
+    /// Repair the pool at `dir` after a crash.  If a write was
+    /// interrupted, a data file can end with a partial chunk, which makes
+    /// the file unusable.  Each data file is scanned for the last
+    /// complete chunk whose data matches its hash.  Anything after that is
+    /// copied into a `.torn-<offset>` file beside it, and the data file is
+    /// truncated.  The indices of truncated files are regenerated when the
+    /// pool is next opened.
+    ///
+    /// Only damage that runs to the end of a file is removed.  If a valid
+    /// chunk follows the damage, the file wasn't torn by a crash, and
+    /// truncating it would lose good chunks, so `Error::CorruptPool` is
+    /// returned instead.  Such a pool can be recovered with `salvage`.
+    ///
+    /// The pool must not be open while this runs.  Returns the torn tails
+    /// that were removed, which is empty if there was no damage.
+    pub fn repair<P: AsRef<Path>>(dir: P) -> Result<Vec<TornTail>> {
+        let base = dir.as_ref();
+        let alg = props_alg(&read_props(base)?)?;
+        let (paths, _) = data_paths(base)?;
+
+        let mut result = vec![];
+        for path in paths {
+            let size = fs::metadata(&path)?.len();
+            let end = valid_end(&path, alg, size)?;
+            if end == size {
+                continue;
+            }
+
+            // Keep the tail, in case there is something in it worth
+            // recovering.
+            let saved = path.with_extension(format!("torn-{}", end));
+            {
+                let mut src = File::open(&path)?;
+                src.seek(SeekFrom::Start(end))?;
+                let mut dest = File::create(&saved)?;
+                io::copy(&mut src, &mut dest)?;
+                dest.sync_all()?;
+            }
+
+            let fd = OpenOptions::new().write(true).open(&path)?;
+            fd.set_len(end)?;
+            fd.sync_all()?;
+
+            result.push(TornTail {
+                file: path,
+                offset: end,
+                length: size - end,
+                saved: saved,
+            });
+        }
+
+        Ok(result)
+    }
+}
+
+// Return the offset just past the last valid chunk in the data file at
+// `path`.  Errors reading the file are returned, but a chunk that can't be
+// decoded, or doesn't match its hash, ends the valid data, as long as no
+// valid chunk comes after it.
+fn valid_end(path: &Path, alg: HashAlg, size: u64) -> Result<u64> {
+    let mut rd = CountingReader::new(BufReader::new(File::open(path)?));
+    let mut end = 0;
+    while end < size {
+        if !read_valid(&mut rd, alg, path)? {
+            break;
+        }
+        end = rd.position();
+    }
+
+    if end < size {
+        if let Some(pos) = next_valid(path, alg, end, size)? {
+            return Err(Error::CorruptPool(format!("{:?} is damaged at offset {}, but has a \
+                                                   valid chunk at offset {}, use salvage to \
+                                                   recover it",
+                                                  path,
+                                                  end,
+                                                  pos)));
+        }
+    }
+    Ok(end)
+}
+
+// Read a chunk, returning whether it could be decoded, and matches its
+// hash.  Only errors reading the file are returned.
+fn read_valid<R: Read>(rd: &mut R, alg: HashAlg, path: &Path) -> Result<bool> {
+    match rd.read_chunk_unverified() {
+        Ok(ref chunk) => Ok(chunk.oid().alg() == alg && chunk.verify().is_ok()),
+        Err(Error::Io(ref err)) if err.kind() != ErrorKind::UnexpectedEof => {
+            Err(Error::CorruptPool(format!("error reading {:?}: {}", path, err)))
+        }
+        Err(_) => Ok(false),
+    }
+}
+
+// Look for a valid chunk after the damage at `start`.  Chunks always start
+// on a 16 byte boundary, so only those offsets need to be tried, and only
+// those where a chunk header starts.
+fn next_valid(path: &Path, alg: HashAlg, start: u64, size: u64) -> Result<Option<u64>> {
+    let mut fd = BufReader::new(File::open(path)?);
+    let mut pos = (start + 16) & !15;
+    let mut magic = [0u8; 16];
+    fd.seek(SeekFrom::Start(pos))?;
+    while pos + 16 <= size {
+        fd.read_exact(&mut magic)?;
+        if chunkio::is_magic(&magic) {
+            fd.seek(SeekFrom::Start(pos))?;
+            if read_valid(&mut fd, alg, path)? {
+                return Ok(Some(pos));
+            }
+            fd.seek(SeekFrom::Start(pos + 16))?;
+        }
+        pos += 16;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use Error;
+    use HashAlg;
+    use pool::{AdumpPool, ChunkRead, ChunkSource};
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use tempdir::TempDir;
+    use testutil;
+
+    #[test]
+    fn torn() {
+        let tmp = TempDir::new("repair").unwrap();
+        let name = tmp.path().join("pool");
+        AdumpPool::new_builder(&name).set_hash(HashAlg::Sha256).create().unwrap();
+        let data_name = name.join("pool-data-0000.data");
+
+        let mut chunks = vec![];
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            let mut wr = pool.writer().unwrap();
+            for i in 0..50 {
+                let chunk = testutil::make_hashed_random_chunk(HashAlg::Sha256, i * 50 + 20, i);
+                wr.add(&chunk).unwrap();
+                chunks.push(chunk);
+            }
+            wr.commit().unwrap();
+        }
+        let good_size = fs::metadata(&data_name).unwrap().len();
+
+        // An undamaged pool is left alone.
+        assert!(AdumpPool::repair(&name).unwrap().is_empty());
+        assert_eq!(fs::metadata(&data_name).unwrap().len(), good_size);
+
+        // Write part of a chunk header, as if the writer had crashed.
+        {
+            let mut fd = OpenOptions::new().append(true).open(&data_name).unwrap();
+            fd.write_all(b"adump-pool-v1.2\n\x10\x00").unwrap();
+        }
+        {
+            // The pool can still be read, and isn't written after the
+            // damage.
+            let mut pool = AdumpPool::open(&name).unwrap();
+            assert_eq!(pool.torn_files(), vec![(data_name.clone(), good_size)]);
+            assert_eq!(&pool.find(chunks[49].oid()).unwrap().data()[..], &chunks[49].data()[..]);
+            let mut wr = pool.writer().unwrap();
+            wr.add(&testutil::make_hashed_random_chunk(HashAlg::Sha256, 100, 98)).unwrap();
+            wr.commit().unwrap();
+        }
+        assert!(name.join("pool-data-0001.data").is_file());
+
+        let torn = AdumpPool::repair(&name).unwrap();
+        assert_eq!(torn.len(), 1);
+        assert_eq!(torn[0].file, data_name);
+        assert_eq!(torn[0].offset, good_size);
+        assert_eq!(torn[0].length, 18);
+        assert_eq!(fs::metadata(&torn[0].saved).unwrap().len(), 18);
+        assert_eq!(fs::metadata(&data_name).unwrap().len(), good_size);
+
+        // The pool can be read, and appended to.
+        let extra = testutil::make_hashed_random_chunk(HashAlg::Sha256, 100, 99);
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            for chunk in &chunks {
+                assert_eq!(&pool.find(chunk.oid()).unwrap().data()[..], &chunk.data()[..]);
+            }
+            let mut wr = pool.writer().unwrap();
+            wr.add(&extra).unwrap();
+            wr.commit().unwrap();
+        }
+        let pool = AdumpPool::open(&name).unwrap();
+        assert_eq!(&pool.find(extra.oid()).unwrap().data()[..], &extra.data()[..]);
+    }
+
+    // Damage followed by valid chunks isn't a torn write, and is left for
+    // salvage to deal with.
+    #[test]
+    fn damaged_middle() {
+        let tmp = TempDir::new("repair").unwrap();
+        let name = tmp.path().join("pool");
+        AdumpPool::new_builder(&name).create().unwrap();
+        let data_name = name.join("pool-data-0000.data");
+
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            let mut wr = pool.writer().unwrap();
+            for i in 0..20 {
+                wr.add(&testutil::make_uncompressible_chunk(1000, i + 1)).unwrap();
+            }
+            wr.commit().unwrap();
+        }
+        let size = fs::metadata(&data_name).unwrap().len();
+
+        // Damage the data of the first chunk.
+        {
+            let mut fd = OpenOptions::new().write(true).open(&data_name).unwrap();
+            fd.seek(SeekFrom::Start(100)).unwrap();
+            fd.write_all(b"damage").unwrap();
+        }
+
+        match AdumpPool::repair(&name) {
+            Err(Error::CorruptPool(_)) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(torn) => panic!("Repaired pool with damage in the middle: {:?}", torn),
+        }
+        assert_eq!(fs::metadata(&data_name).unwrap().len(), size);
+    }
+}
@@ -0,0 +1,322 @@
+//! Recovery of chunks from damaged pool files.
+
+use Chunk;
+use HashAlg;
+use Result;
+use pool::{ChunkSource, ChunkWrite};
+use std::cmp;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use super::{data_paths, props_alg, read_props, AdumpPool};
+use super::chunkio::{self, ChunkRead, CountingReader};
+
+// Reading a pool file normally follows the lengths in each chunk header
+// from one chunk to the next, so a single damaged header makes the rest of
+// the file unreachable.  Since every chunk starts on a 16-byte boundary
+// with a magic number, the scanner here instead searches each boundary for
+// a magic number, and only accepts a chunk there if it matches its hash.
+
+// How much of the file to read at a time.
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// The results of salvaging chunks with `AdumpPool::salvage`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SalvageReport {
+    /// The number of files scanned.
+    pub files: u64,
+    /// The number of chunks recovered, and added to the destination.
+    pub chunks: u64,
+    /// Chunks that were recovered, but were already in the destination.
+    pub duplicates: u64,
+    /// Places that looked like the start of a chunk, but weren't a chunk
+    /// that matched its hash.
+    pub bad: u64,
+    /// The number of bytes not in any recovered chunk.
+    pub lost: u64,
+}
+
+impl AdumpPool {
+    /// Read the hash algorithm of the pool at `dir` from its properties,
+    /// without opening the pool.
+    pub fn hash_alg_of<P: AsRef<Path>>(dir: P) -> Result<HashAlg> {
+        props_alg(&read_props(dir.as_ref())?)
+    }
+
+    /// Recover every intact chunk from the pool at `dir`, and add them to
+    /// `dest`, which would normally be a freshly created pool.  Only the
+    /// data files (and any tails saved by `repair`) are read, the index
+    /// files and properties aren't needed.  Chunks not hashed with the
+    /// algorithm `dest` uses are skipped, and counted as bad.  The chunks
+    /// are all added by a single writer, so nothing is added to `dest` if
+    /// this fails.  The source pool isn't modified.
+    pub fn salvage<P: AsRef<Path>>(dir: P, dest: &mut ChunkSource) -> Result<SalvageReport> {
+        let base = dir.as_ref();
+        let (mut paths, _) = data_paths(base)?;
+        for ent in base.read_dir()? {
+            let path = ent?.path();
+            let torn = match path.extension().and_then(|x| x.to_str()) {
+                Some(ext) => ext.starts_with("torn-"),
+                None => false,
+            };
+            if torn {
+                paths.push(path);
+            }
+        }
+
+        let mut report = SalvageReport::default();
+        let mut wr = dest.writer()?;
+        for path in &paths {
+            salvage_file(path, &mut *wr, &mut report)?;
+        }
+        wr.commit()?;
+        Ok(report)
+    }
+}
+
+// Recover every intact chunk from a single pool data file, adding them to
+// `dest`, and updating `report`.
+fn salvage_file(path: &Path,
+                dest: &mut ChunkWrite,
+                report: &mut SalvageReport)
+                -> Result<()> {
+    let alg = dest.reader().hash_alg();
+    let mut chunks = 0;
+    let mut duplicates = 0;
+    let mut kept = 0;
+    let mut bad = 0;
+    let size = scan(path, alg, &mut bad, |chunk, len| {
+        kept += len;
+        if dest.reader().contains_key(chunk.oid())? {
+            duplicates += 1;
+        } else {
+            dest.add(&chunk)?;
+            chunks += 1;
+        }
+        Ok(())
+    })?;
+
+    report.files += 1;
+    report.chunks += chunks;
+    report.duplicates += duplicates;
+    report.bad += bad;
+    report.lost += size - kept;
+    Ok(())
+}
+
+// Scan the file at `path` for chunks, calling `found` with each intact one,
+// and the number of bytes it occupies in the file.  Candidates that turn
+// out not to be valid chunks are counted in `bad`.  Returns the size of
+// the file.
+fn scan<F>(path: &Path, alg: HashAlg, bad: &mut u64, mut found: F) -> Result<u64>
+    where F: FnMut(Chunk, u64) -> Result<()>
+{
+    let fd = File::open(path)?;
+    let size = fd.metadata()?.len();
+    let mut win = Window::new(fd);
+
+    loop {
+        // Find the next boundary with a magic number.
+        win.fill(16)?;
+        let (off, is_magic) = {
+            let data = win.data();
+            let mut off = 0;
+            while off + 16 <= data.len() && !chunkio::is_magic(&data[off..]) {
+                off += 16;
+            }
+            (off, off + 16 <= data.len())
+        };
+        win.consume(off);
+        if !is_magic {
+            if win.eof {
+                break;
+            }
+            continue;
+        }
+
+        // Make sure the whole chunk is in the window before decoding it.
+        let stored = chunkio::stored_size(win.data());
+        if let Some(len) = stored {
+            win.fill(len)?;
+        }
+        let (chunk, len) = {
+            let mut rd = CountingReader::new(win.data());
+            (rd.read_chunk_unverified(), rd.position())
+        };
+        match chunk {
+            Ok(chunk) => {
+                if chunk.oid().alg() == alg && chunk.verify().is_ok() {
+                    found(chunk, len)?;
+                    win.consume(len as usize);
+                    continue;
+                }
+            }
+            Err(_) => (),
+        }
+        *bad += 1;
+        win.consume(16);
+    }
+
+    Ok(size)
+}
+
+// A window onto the file being scanned, which only moves forward.  Moving
+// the window along doesn't read anything, and filling it only reads the
+// part of the file after what is already in the window, so each byte of
+// the file is only read once.
+struct Window {
+    fd: File,
+    buf: Vec<u8>,
+    // Where the window starts in `buf`.  Everything before it has been
+    // consumed.
+    head: usize,
+    // Has the end of the file been reached?
+    eof: bool,
+}
+
+impl Window {
+    fn new(fd: File) -> Window {
+        Window {
+            fd: fd,
+            buf: Vec::with_capacity(BLOCK_SIZE),
+            head: 0,
+            eof: false,
+        }
+    }
+
+    // The data in the window.
+    fn data(&self) -> &[u8] {
+        &self.buf[self.head..]
+    }
+
+    // Move the start of the window past `count` bytes.
+    fn consume(&mut self, count: usize) {
+        self.head += count;
+        assert!(self.head <= self.buf.len());
+    }
+
+    // Read more of the file, until the window holds at least `want` bytes,
+    // or the end of the file is reached.  The file is read a block at a
+    // time, and the data already consumed is only discarded then.
+    fn fill(&mut self, want: usize) -> Result<()> {
+        if self.buf.len() - self.head >= want || self.eof {
+            return Ok(());
+        }
+
+        self.buf.drain(..self.head);
+        self.head = 0;
+        let target = cmp::max(want, BLOCK_SIZE);
+        while self.buf.len() < target {
+            let old = self.buf.len();
+            self.buf.resize(target, 0);
+            let count = self.fd.read(&mut self.buf[old..]);
+            let count = match count {
+                Ok(count) => count,
+                Err(e) => {
+                    self.buf.truncate(old);
+                    return Err(e.into());
+                }
+            };
+            self.buf.truncate(old + count);
+            if count == 0 {
+                self.eof = true;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pool::{AdumpPool, ChunkRead, ChunkSource, RamPool};
+    use pool::adump::index::Index;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use tempdir::TempDir;
+    use testutil;
+
+    #[test]
+    fn salvage() {
+        let tmp = TempDir::new("salvage").unwrap();
+        let name = tmp.path().join("pool");
+        AdumpPool::new_builder(&name).create().unwrap();
+
+        let mut chunks = vec![];
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            let mut wr = pool.writer().unwrap();
+            for i in 0..30 {
+                let chunk = testutil::make_random_chunk(i * 100 + 2000, i);
+                wr.add(&chunk).unwrap();
+                chunks.push(chunk);
+            }
+            wr.commit().unwrap();
+        }
+
+        // Destroy the header of one chunk, and the data of another.
+        {
+            let pool = AdumpPool::open(&name).unwrap();
+            let index = &pool.cfiles[0].index;
+            let header = index.get(chunks[10].oid()).unwrap().offset;
+            let data = index.get(chunks[20].oid()).unwrap().offset;
+
+            let mut fd = OpenOptions::new()
+                .write(true)
+                .open(name.join("pool-data-0000.data"))
+                .unwrap();
+            fd.seek(SeekFrom::Start(header)).unwrap();
+            fd.write_all(&[0xffu8; 24]).unwrap();
+            fd.seek(SeekFrom::Start(data + 100)).unwrap();
+            fd.write_all(b"damage").unwrap();
+        }
+
+        let mut dest = RamPool::new();
+        let report = AdumpPool::salvage(&name, &mut dest).unwrap();
+        assert_eq!(report.files, 1);
+        assert_eq!(report.chunks, 28);
+        assert_eq!(report.duplicates, 0);
+        assert_eq!(report.bad, 1);
+        assert!(report.lost > 0);
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert_eq!(dest.contains_key(chunk.oid()).unwrap(), i != 10 && i != 20);
+        }
+
+        // Everything recovered is already present the second time.
+        let again = AdumpPool::salvage(&name, &mut dest).unwrap();
+        assert_eq!(again.chunks, 0);
+        assert_eq!(again.duplicates, 28);
+    }
+
+    // Chunks are found wherever they fall relative to the blocks the file
+    // is read in, including ones larger than a block.
+    #[test]
+    fn straddle() {
+        let tmp = TempDir::new("salvage").unwrap();
+        let name = tmp.path().join("pool");
+        AdumpPool::new_builder(&name).create().unwrap();
+
+        let sizes = [300000, 700000, 300000, 3 * super::BLOCK_SIZE as u32 / 2, 100, 500000];
+        let mut chunks = vec![];
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            let mut wr = pool.writer().unwrap();
+            for (i, &size) in sizes.iter().enumerate() {
+                let chunk = testutil::make_uncompressible_chunk(size, i as u32 + 1);
+                wr.add(&chunk).unwrap();
+                chunks.push(chunk);
+            }
+            wr.commit().unwrap();
+        }
+
+        let mut dest = RamPool::new();
+        let report = AdumpPool::salvage(&name, &mut dest).unwrap();
+        assert_eq!(report.chunks, chunks.len() as u64);
+        assert_eq!(report.bad, 0);
+        assert_eq!(report.lost, 0);
+        for chunk in &chunks {
+            assert_eq!(&dest.find(chunk.oid()).unwrap().data()[..], &chunk.data()[..]);
+        }
+    }
+}
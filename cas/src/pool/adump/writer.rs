@@ -0,0 +1,109 @@
+//! Writing to an adump pool.
+
+use Chunk;
+use Kind;
+use Oid;
+use Result;
+use std::fs;
+use std::io::Read;
+use pool::{ChunkRead, ChunkWrite};
+use super::{large, AdumpPool};
+
+// Chunks are appended to the pool files as they are added, and the indices
+// written out on commit.  To roll back, the last file that existed when
+// writing began is cut back to its old size, any files created since are
+// removed, along with any new large chunks.
+pub struct AdumpWriter<'a> {
+    pool: &'a mut AdumpPool,
+
+    // The state of the pool when writing began: the number of files, the
+    // size of the last one, and the pool's own bookkeeping.
+    files: usize,
+    size: u64,
+    next_file: u32,
+    dirty: bool,
+
+    // Large chunks stored by this writer.
+    large: Vec<Oid>,
+
+    done: bool,
+}
+
+impl<'a> AdumpWriter<'a> {
+    pub fn new(pool: &'a mut AdumpPool) -> AdumpWriter<'a> {
+        let size = pool.cfiles.last().map_or(0, |cf| cf.size);
+        AdumpWriter {
+            files: pool.cfiles.len(),
+            size: size,
+            next_file: pool.next_file,
+            dirty: pool.dirty,
+            pool: pool,
+            large: vec![],
+            done: false,
+        }
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        let pool = &mut *self.pool;
+        while pool.cfiles.len() > self.files {
+            let name = pool.cfiles.pop().unwrap().name;
+            for ext in &["data", "idx", "bloom"] {
+                let _ = fs::remove_file(name.with_extension(ext));
+            }
+        }
+        if let Some(cfile) = pool.cfiles.last_mut() {
+            cfile.truncate(self.size)?;
+        }
+        pool.next_file = self.next_file;
+        pool.dirty = self.dirty;
+
+        for oid in &self.large {
+            pool.large.remove(oid);
+            large::remove(&pool.base, oid)?;
+        }
+        self.large.clear();
+        Ok(())
+    }
+}
+
+impl<'a> ChunkWrite for AdumpWriter<'a> {
+    fn reader(&self) -> &ChunkRead {
+        &*self.pool
+    }
+
+    fn add(&mut self, chunk: &Chunk) -> Result<()> {
+        if self.pool.contains_key(chunk.oid())? {
+            return Ok(());
+        }
+        self.pool.write_chunk(chunk)
+    }
+
+    fn add_large(&mut self, kind: Kind, source: &mut Read) -> Result<Oid> {
+        let (oid, created) = large::store(&self.pool.base, self.pool.alg, kind, source)?;
+        if created {
+            if self.pool.contains_small(&oid) {
+                // Already stored as a regular chunk.
+                large::remove(&self.pool.base, &oid)?;
+            } else {
+                self.pool.large.insert(oid.clone());
+                self.large.push(oid.clone());
+            }
+        }
+        Ok(oid)
+    }
+
+    fn commit(mut self: Box<Self>) -> Result<()> {
+        // If this fails, dropping the writer rolls it back.
+        self.pool.flush()?;
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl<'a> Drop for AdumpWriter<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.rollback();
+        }
+    }
+}
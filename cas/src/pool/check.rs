@@ -0,0 +1,86 @@
+// Pool integrity checking.
+
+use std::fmt;
+
+use Oid;
+
+/// A single problem found by `ChunkRead::check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckItem {
+    /// The chunk involved, if it could be determined.
+    pub oid: Option<Oid>,
+    /// Where in the pool the problem is, such as a file and offset.
+    pub location: String,
+    /// A description of the problem.
+    pub detail: String,
+}
+
+impl CheckItem {
+    pub fn new<L: Into<String>, D: Into<String>>(oid: Option<Oid>,
+                                                 location: L,
+                                                 detail: D)
+                                                 -> CheckItem {
+        CheckItem {
+            oid: oid,
+            location: location.into(),
+            detail: detail.into(),
+        }
+    }
+}
+
+impl fmt::Display for CheckItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.oid {
+            Some(ref oid) => write!(f, "{}: {}: {}", self.location, oid.to_hex(), self.detail),
+            None => write!(f, "{}: {}", self.location, self.detail),
+        }
+    }
+}
+
+/// The result of checking every chunk in a pool.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// The number of chunks whose data was read, and matched its hash.
+    pub good: u64,
+    /// Chunks the pool has a record of, but whose data isn't there.
+    pub missing: Vec<CheckItem>,
+    /// Chunks whose data is damaged, or doesn't agree with the pool's
+    /// record of it.
+    pub corrupt: Vec<CheckItem>,
+    /// Data in the pool that nothing refers to.
+    pub orphaned: Vec<CheckItem>,
+    /// Chunks that are stored more than once.
+    pub duplicate: Vec<CheckItem>,
+}
+
+impl CheckReport {
+    pub fn new() -> CheckReport {
+        CheckReport::default()
+    }
+
+    /// Were no problems found?
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty() && self.orphaned.is_empty() &&
+        self.duplicate.is_empty()
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(name, ref items) in &[("missing", &self.missing),
+                                    ("corrupt", &self.corrupt),
+                                    ("orphaned", &self.orphaned),
+                                    ("duplicate", &self.duplicate)] {
+            for item in items.iter() {
+                writeln!(f, "{}: {}", name, item)?;
+            }
+        }
+        write!(f,
+               "{} good, {} missing, {} corrupt, {} orphaned, {} duplicate",
+               self.good,
+               self.missing.len(),
+               self.corrupt.len(),
+               self.orphaned.len(),
+               self.duplicate.len())
+    }
+}
@@ -3,49 +3,88 @@
 // For development.
 #![allow(dead_code)]
 
+use std::collections::HashSet;
 use std::io::prelude::*;
+use std::io::{self, ErrorKind};
 use std::fs;
 use std::path::{Path, PathBuf};
-use rusqlite::{SqliteConnection, SqliteTransaction};
+use std::sync::Mutex;
+use rusqlite::{SqliteConnection, SQLITE_OPEN_CREATE, SQLITE_OPEN_FULL_MUTEX,
+               SQLITE_OPEN_READ_ONLY, SQLITE_OPEN_READ_WRITE};
+use rusqlite::types::ToSql;
 use uuid::Uuid;
 
 use oid::Oid;
-use chunk::Chunk;
+use chunk::{Chunk, MAX_CHUNK_SIZE};
 use kind::Kind;
-use pool::sql;
+use pool::{self, sql};
 use pool::wrapper::XactConnection;
-use pool::ChunkSource;
+use pool::{CheckItem, CheckReport, ChunkRead, ChunkSource, ChunkWrite};
+use Codec;
+use CompressPolicy;
+use HashAlg;
 use Result;
 use Error;
 
 pub struct FilePool {
-    db: XactConnection,
+    // The connection can only be used by one thread at a time.
+    db: Mutex<XactConnection>,
     uuid: Uuid,
+    alg: HashAlg,
+    codec: Codec,
+    policy: CompressPolicy,
+    max_chunk: usize,
     path: PathBuf,
+
+    // Does the blobs table have a codec column.
+    has_codecs: bool,
+
+    // Was the pool opened with `open_readonly`.
+    readonly: bool,
 }
 
 impl FilePool {
     pub fn create<P: AsRef<Path>>(path: P) -> Result<()> {
-        let path = path.as_ref();
-        fs::create_dir(path)?;
-        fs::create_dir(&path.join("blobs"))?;
-        let mut db = SqliteConnection::open(&path.join("data.db"))?;
-        POOL_SCHEMA.set(&mut db)?;
-        POOL_SCHEMA.check(&db)?;
+        FilePool::new_builder(path).create()
+    }
 
-        let tx = db.transaction()?;
-        tx.execute("INSERT INTO props (key, value) values ('uuid', ?)",
-                     &[&Uuid::new_v4().hyphenated().to_string()])?;
-        tx.commit()?;
-        Ok(())
+    pub fn new_builder<P: AsRef<Path>>(path: P) -> FilePoolBuilder<P> {
+        FilePoolBuilder {
+            path: path,
+            alg: HashAlg::Sha1,
+            codec: Codec::Zlib,
+            policy: CompressPolicy::new(),
+            max_chunk: MAX_CHUNK_SIZE,
+            uuid: Uuid::new_v4(),
+        }
     }
 
     pub fn open<P: AsRef<Path>>(path: P) -> Result<FilePool> {
-        let path = path.as_ref();
-        let db = SqliteConnection::open(&path.join("data.db"))?;
+        FilePool::open_with(path.as_ref(), false)
+    }
+
+    /// Open the pool for reading only.  The database is opened read-only,
+    /// so the pool can be on read-only media, and no write permission is
+    /// needed on it.  Trying to write to the pool returns
+    /// `Error::ReadOnly`.
+    pub fn open_readonly<P: AsRef<Path>>(path: P) -> Result<FilePool> {
+        FilePool::open_with(path.as_ref(), true)
+    }
+
+    fn open_with(path: &Path, readonly: bool) -> Result<FilePool> {
+        let name = path.join("data.db");
+        // The connection is opened in serialized mode, which `XactConnection`
+        // relies upon to be `Send`.
+        let flags = if readonly {
+            SQLITE_OPEN_READ_ONLY | SQLITE_OPEN_FULL_MUTEX
+        } else {
+            SQLITE_OPEN_READ_WRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_FULL_MUTEX
+        };
+        let db = SqliteConnection::open_with_flags(&name, flags)?;
         let db = XactConnection::new(db);
 
-        let _inabilities = POOL_SCHEMA.check(&db)?;
+        let inabilities = POOL_SCHEMA.check(&db)?.unwrap_or_default();
+        let has_codecs = !inabilities.contains(&PoolInabilities::NoCodec);
 
         // Retrieve the uuid.
         // TODO: Need something more robust than their query_one.
@@ -57,10 +96,33 @@ impl FilePool {
 
         let uuid = Uuid::parse_str(&uuid)?;
 
+        // Pools created before the hash was configurable don't have this
+        // property, and are SHA-1.
+        let alg = match get_prop(&db, "hash")? {
+            None => HashAlg::Sha1,
+            Some(name) => HashAlg::from_name(&name)?,
+        };
+
+        // Likewise, older pools are always zlib.  Pools whose schema
+        // can't record a codec can't store anything else.
+        let codec = match get_prop(&db, "codec")? {
+            Some(ref name) if has_codecs => Codec::from_name(name)?,
+            _ => Codec::Zlib,
+        };
+        let policy = CompressPolicy::from_props(|key| get_prop(&db, key))?;
+        let max_chunk = get_prop(&db, pool::MAX_CHUNK_KEY)?;
+        let max_chunk = pool::parse_max_chunk(max_chunk.as_ref().map(|x| &x[..]))?;
+
         Ok(FilePool {
-            db: db,
+            db: Mutex::new(db),
             uuid: uuid,
+            alg: alg,
+            codec: codec,
+            policy: policy,
+            max_chunk: max_chunk,
             path: path.to_path_buf(),
+            has_codecs: has_codecs,
+            readonly: readonly,
         })
     }
 
@@ -78,6 +140,60 @@ impl FilePool {
         (dir, name)
     }
 
+    // Record a chunk in the blobs table.  The payload is only given if it
+    // is stored inline, otherwise it has already been written to a file.
+    fn insert(&mut self,
+              oid: &Oid,
+              kind: Kind,
+              size: i64,
+              zsize: i64,
+              data: Option<&[u8]>,
+              codec: Codec)
+              -> Result<()> {
+        let oid = oid.as_bytes();
+        let kind = kind.to_string();
+        let codec = codec.id() as i32;
+
+        let mut columns = "oid, kind, size, zsize".to_owned();
+        let mut params: Vec<&ToSql> = vec![&oid, &kind, &size, &zsize];
+        if let Some(ref data) = data {
+            columns.push_str(", data");
+            params.push(data);
+        }
+        if self.has_codecs {
+            columns.push_str(", codec");
+            params.push(&codec);
+        }
+        let marks = vec!["?"; params.len()].join(", ");
+        self.db
+            .get_mut()
+            .unwrap()
+            .execute(&format!("INSERT INTO blobs ({}) VALUES ({})", columns, marks),
+                     &params)?;
+
+        Ok(())
+    }
+
+    // Check the chunk stored in a single row of the blobs table.
+    fn check_row(&self, oid: &Oid, kind: &str, size: i64) -> Result<()> {
+        if size <= self.max_chunk as i64 {
+            return self.find(oid)?.verify();
+        }
+
+        // Large chunks have to be hashed as they are read.
+        let kind = Kind::new(kind)?;
+        let (_, name) = self.get_paths(oid);
+        let mut fd = fs::File::open(&name)?;
+        let (actual, len) = pool::copy_hashed(self.alg, kind, &mut fd, &mut io::sink())?;
+        if len != size as u64 {
+            return Err(Error::CorruptChunk(format!("{} bytes of data, expecting {}", len, size)));
+        }
+        if &actual != oid {
+            return Err(Error::HashMismatch(oid.clone(), actual));
+        }
+        Ok(())
+    }
+
     fn read_payload(&self, oid: &Oid) -> Result<Vec<u8>> {
         let (_, fname) = self.get_paths(oid);
         let mut fd = fs::File::open(&fname)?;
@@ -87,23 +203,34 @@ impl FilePool {
     }
 }
 
-impl ChunkSource for FilePool {
+impl ChunkRead for FilePool {
     fn find(&self, key: &Oid) -> Result<Chunk> {
         // Ideally, we could just query the data for NULL, but this doesn't
         // seem to be exposed properly.  Instead, retrieve it as a separate
         // column.
-        let mut stmt = self.db
-            .prepare("SELECT kind, size, zsize, data, data IS NULL FROM blobs WHERE oid = ?")?;
-        let mut rows = stmt.query(&[&&key.0[..]])?;
+        // Pools without a codec column only contain zlib data.
+        let db = self.db.lock().unwrap();
+        let mut stmt = if self.has_codecs {
+            db.prepare("SELECT kind, size, zsize, data, data IS NULL, codec FROM blobs \
+                        WHERE oid = ?")?
+        } else {
+            db.prepare("SELECT kind, size, zsize, data, data IS NULL, 1 FROM blobs WHERE oid = ?")?
+        };
+        let mut rows = stmt.query(&[&key.as_bytes()])?;
         match rows.next() {
             None => Err(Error::MissingChunk),
             Some(row) => {
                 let row = row?;
                 let kind: String = row.get(0);
-                let kind = Kind::new(&kind).unwrap();
-                let size: i32 = row.get(1);
-                let zsize: i32 = row.get(2);
+                let kind = Kind::new(&kind)?;
+                let size: i64 = row.get(1);
+                let zsize: i64 = row.get(2);
+                if size > self.max_chunk as i64 {
+                    return Err(Error::ChunkTooLarge(size as u64));
+                }
                 let null_data: i32 = row.get(4);
+                let codec: i32 = row.get(5);
+                let codec = Codec::from_id(codec as u8)?;
                 let payload: Vec<u8> = if null_data != 0 {
                     self.read_payload(key)?
                 } else {
@@ -112,23 +239,28 @@ impl ChunkSource for FilePool {
 
                 let chunk = if size == zsize {
                     // TODO: Use new_plain_with_oid()
-                    Chunk::new_plain(kind, payload)
+                    Chunk::new_plain_alg(self.alg, kind, payload)
                 } else {
-                    Chunk::new_compressed(kind, key.clone(), payload, size as u32)
+                    Chunk::new_compressed_codec(codec, kind, key.clone(), payload, size as u32)
                 };
 
-                assert_eq!(key, chunk.oid());
+                // Plain chunks have their hash computed, which catches
+                // damage to the data.  Compressed chunks are only checked
+                // by a `VerifyingSource`.
+                if key != chunk.oid() {
+                    return Err(Error::HashMismatch(key.clone(), chunk.oid().clone()));
+                }
 
-                Ok(chunk)
+                pool::checked(chunk)
             }
         }
     }
 
     fn contains_key(&self, key: &Oid) -> Result<bool> {
-        let count: i32 = self.db
-            .query_row("SELECT COUNT(*) FROM blobs WHERE oid = ?",
-                       &[&&key.0[..]],
-                       |row| row.get(0))?;
+        let db = self.db.lock().unwrap();
+        let count: i32 = db.query_row("SELECT COUNT(*) FROM blobs WHERE oid = ?",
+                                      &[&key.as_bytes()],
+                                      |row| row.get(0))?;
         Ok(count > 0)
     }
 
@@ -136,43 +268,408 @@ impl ChunkSource for FilePool {
         &self.uuid
     }
 
+    fn hash_alg(&self) -> HashAlg {
+        self.alg
+    }
+
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    fn compress_policy(&self) -> &CompressPolicy {
+        &self.policy
+    }
+
     fn backups(&self) -> Result<Vec<Oid>> {
-        let mut stmt = self.db
-            .prepare("SELECT oid FROM blobs WHERE kind = 'back'")?;
+        let db = self.db.lock().unwrap();
+        let sql = if has_table(&db, "forgotten")? {
+            "SELECT oid FROM blobs WHERE kind = 'back'
+             AND oid NOT IN (SELECT oid FROM forgotten)"
+        } else {
+            "SELECT oid FROM blobs WHERE kind = 'back'"
+        };
+        query_oids(&db, sql, &[], self.alg)
+    }
+
+    fn seen(&self, peer: &Uuid) -> Result<Vec<Oid>> {
+        let db = self.db.lock().unwrap();
+        if !has_table(&db, "seen")? {
+            return Ok(vec![]);
+        }
+        query_oids(&db,
+                   "SELECT oid FROM seen WHERE peer = ?",
+                   &[&peer.hyphenated().to_string()],
+                   self.alg)
+    }
+
+    fn peers(&self) -> Result<Vec<Uuid>> {
+        let db = self.db.lock().unwrap();
+        if !has_table(&db, "seen")? {
+            return Ok(vec![]);
+        }
+        let mut stmt = db.prepare("SELECT DISTINCT peer FROM seen")?;
         let mut result = vec![];
-        for oid in stmt.query_map(&[], |row| {
+        for peer in stmt.query_map(&[], |row| {
+            let peer: String = row.get(0);
+            peer
+        })? {
+            result.push(Uuid::parse_str(&peer?)?);
+        }
+        Ok(result)
+    }
+
+    fn max_chunk_size(&self) -> usize {
+        self.max_chunk
+    }
+
+    fn list(&self) -> Result<Vec<(Oid, Kind)>> {
+        let db = self.db.lock().unwrap();
+        let mut stmt = db.prepare("SELECT oid, kind FROM blobs")?;
+        let mut result = vec![];
+        for row in stmt.query_map(&[], |row| {
             let oid: Vec<u8> = row.get(0);
-            Oid::from_raw(&oid)
+            let kind: String = row.get(1);
+            (Oid::from_raw_alg(self.alg, &oid), kind)
         })? {
-            let oid = oid?;
-            result.push(oid);
+            let (oid, kind) = row?;
+            result.push((oid, Kind::new(&kind)?));
         }
         Ok(result)
     }
 
-    fn begin_writing(&mut self) -> Result<()> {
-        self.db.begin()?;
+    fn read_large(&self, key: &Oid) -> Result<Box<Read + Send>> {
+        let size: i64 = {
+            let db = self.db.lock().unwrap();
+            let mut stmt = db.prepare("SELECT size FROM blobs WHERE oid = ?")?;
+            let mut rows = stmt.query(&[&key.as_bytes()])?;
+            match rows.next() {
+                None => return Err(Error::MissingChunk),
+                Some(row) => row?.get(0),
+            }
+        };
+
+        if size > self.max_chunk as i64 {
+            let (_, name) = self.get_paths(key);
+            Ok(Box::new(io::BufReader::new(fs::File::open(&name)?)))
+        } else {
+            Ok(Box::new(io::Cursor::new(self.find(key)?.try_into_bytes()?)))
+        }
+    }
+
+    fn check(&self) -> Result<CheckReport> {
+        let mut report = CheckReport::new();
+
+        // Collect the rows first, since reading the chunks needs the
+        // database as well.
+        let rows = {
+            let db = self.db.lock().unwrap();
+            let mut stmt = db.prepare("SELECT oid, kind, size, data IS NULL FROM blobs")?;
+            let mut rows = vec![];
+            for row in stmt.query_map(&[], |row| {
+                let oid: Vec<u8> = row.get(0);
+                let kind: String = row.get(1);
+                let size: i64 = row.get(2);
+                let null_data: i32 = row.get(3);
+                (oid, kind, size, null_data != 0)
+            })? {
+                rows.push(row?);
+            }
+            rows
+        };
+
+        let mut external = HashSet::new();
+        for (oid, kind, size, null_data) in rows {
+            let oid = Oid::from_raw_alg(self.alg, &oid);
+            let location = if null_data {
+                external.insert(oid.clone());
+                self.get_paths(&oid).1.display().to_string()
+            } else {
+                "blobs table".to_owned()
+            };
+
+            match self.check_row(&oid, &kind, size) {
+                Ok(()) => report.good += 1,
+                Err(Error::Io(ref e)) if e.kind() == ErrorKind::NotFound => {
+                    report.missing.push(CheckItem::new(Some(oid), location, "no data file"));
+                }
+                Err(e) => report.corrupt.push(CheckItem::new(Some(oid), location, e.to_string())),
+            }
+        }
+
+        // Every file under `blobs` should hold the data of a row.
+        for ent in fs::read_dir(self.path.join("blobs"))? {
+            let ent = ent?;
+            let dir = ent.path();
+            if !dir.is_dir() {
+                report.orphaned
+                    .push(CheckItem::new(None, dir.display().to_string(), "unexpected file"));
+                continue;
+            }
+            let prefix = ent.file_name().to_string_lossy().into_owned();
+            for ent in fs::read_dir(&dir)? {
+                let ent = ent?;
+                let text = format!("{}{}", prefix, ent.file_name().to_string_lossy());
+                match Oid::from_hex_alg(self.alg, &text) {
+                    Some(ref oid) if external.contains(oid) => (),
+                    oid => {
+                        report.orphaned.push(CheckItem::new(oid,
+                                                            ent.path().display().to_string(),
+                                                            "file not referenced by blobs table"))
+                    }
+                }
+            }
+        }
+
+        // The blobs table doesn't allow an oid to be stored twice, so there
+        // can't be duplicates.
+        Ok(report)
+    }
+}
+
+// Look up a single value in the props table.
+fn get_prop(db: &SqliteConnection, key: &str) -> Result<Option<String>> {
+    let mut stmt = db.prepare("SELECT value FROM props WHERE key = ?")?;
+    let mut rows = stmt.query(&[&key])?;
+    match rows.next() {
+        None => Ok(None),
+        Some(row) => {
+            let row = row?;
+            Ok(Some(row.get(0)))
+        }
+    }
+}
+
+// The forgotten backups, and those seen from other pools, are kept in
+// tables with a row for each backup.  The tables are only created once
+// something is added to them, so they are missing from pools that have
+// never forgotten or synced anything, and from pools written by older
+// programs.
+static FORGOTTEN_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS forgotten (
+    oid BLOB PRIMARY KEY)";
+static SEEN_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS seen (
+    peer TEXT,
+    oid BLOB,
+    PRIMARY KEY (peer, oid))";
+
+fn has_table(db: &SqliteConnection, name: &str) -> Result<bool> {
+    let count: i32 = db.query_row("SELECT COUNT(*) FROM sqlite_master
+                                   WHERE type = 'table' AND name = ?",
+                                  &[&name],
+                                  |row| row.get(0))?;
+    Ok(count > 0)
+}
+
+// Run a query whose rows each hold a single Oid.
+fn query_oids(db: &SqliteConnection,
+              sql: &str,
+              params: &[&ToSql],
+              alg: HashAlg)
+              -> Result<Vec<Oid>> {
+    let mut stmt = db.prepare(sql)?;
+    let mut result = vec![];
+    for oid in stmt.query_map(params, |row| {
+        let oid: Vec<u8> = row.get(0);
+        Oid::from_raw_alg(alg, &oid)
+    })? {
+        result.push(oid?);
+    }
+    Ok(result)
+}
+
+// Add backups to one of these tables, all in a single transaction.  The
+// `insert` statement takes the `params`, followed by the Oid.
+fn add_oids(db: &mut SqliteConnection,
+            create: &str,
+            insert: &str,
+            params: &[&ToSql],
+            oids: &[Oid])
+            -> Result<()> {
+    let tx = db.transaction()?;
+    tx.execute(create, &[])?;
+    {
+        let mut stmt = tx.prepare(insert)?;
+        for oid in oids {
+            let oid = oid.as_bytes();
+            let mut all: Vec<&ToSql> = params.to_vec();
+            all.push(&oid);
+            stmt.execute(&all)?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// A builder to set parameters before creating a pool.
+pub struct FilePoolBuilder<P: AsRef<Path>> {
+    path: P,
+    alg: HashAlg,
+    codec: Codec,
+    policy: CompressPolicy,
+    max_chunk: usize,
+    uuid: Uuid,
+}
+
+impl<P: AsRef<Path>> FilePoolBuilder<P> {
+    /// Change the hash algorithm used for the chunks in this pool.  The
+    /// algorithm is recorded in the `props` table.  Pools without this
+    /// property use SHA-1.
+    pub fn set_hash(mut self, alg: HashAlg) -> Self {
+        self.alg = alg;
+        self
+    }
+
+    /// Change the codec used to compress new chunks written to this pool.
+    pub fn set_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Set the policy deciding which chunks are compressed.
+    pub fn set_policy(mut self, policy: CompressPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Change the size of the largest chunk stored as a regular chunk.
+    /// Data larger than this can only be stored with `add_large`, and read
+    /// back with `read_large`.  This can't be more than `MAX_CHUNK_SIZE`,
+    /// the default, and is mostly useful to test large chunks with less
+    /// data.
+    pub fn set_max_chunk_size(mut self, size: usize) -> Self {
+        self.max_chunk = size;
+        self
+    }
+
+    /// Give the pool this uuid, rather than a new one.  Only a pool holding
+    /// a copy of another pool's chunks should share its uuid.
+    pub fn set_uuid(mut self, uuid: Uuid) -> Self {
+        self.uuid = uuid;
+        self
+    }
+
+    /// Actually create the pool.  The given path must not exist.
+    pub fn create(self) -> Result<()> {
+        pool::check_max_chunk(self.max_chunk)?;
+        let path = self.path.as_ref();
+        fs::create_dir(path)?;
+        fs::create_dir(&path.join("blobs"))?;
+        let mut db = SqliteConnection::open(&path.join("data.db"))?;
+        if self.codec == Codec::Zlib {
+            LEGACY_SCHEMA.set(&mut db)?;
+        } else {
+            POOL_SCHEMA.set(&mut db)?;
+        }
+        POOL_SCHEMA.check(&db)?;
+
+        // As with adump pools, the hash and codec are only recorded when
+        // they aren't the legacy ones.
+        let tx = db.transaction()?;
+        tx.execute("INSERT INTO props (key, value) values ('uuid', ?)",
+                     &[&self.uuid.hyphenated().to_string()])?;
+        if self.alg != HashAlg::Sha1 {
+            tx.execute("INSERT INTO props (key, value) values ('hash', ?)",
+                         &[&self.alg.name()])?;
+        }
+        if self.codec != Codec::Zlib {
+            tx.execute("INSERT INTO props (key, value) values ('codec', ?)",
+                         &[&self.codec.name()])?;
+        }
+        if self.max_chunk != MAX_CHUNK_SIZE {
+            tx.execute("INSERT INTO props (key, value) values (?, ?)",
+                         &[&pool::MAX_CHUNK_KEY, &self.max_chunk.to_string()])?;
+        }
+        for (key, value) in self.policy.to_props() {
+            tx.execute("INSERT INTO props (key, value) values (?, ?)", &[&key, &value])?;
+        }
+        tx.commit()?;
         Ok(())
     }
+}
+
+impl ChunkSource for FilePool {
+    fn writer<'a>(&'a mut self) -> Result<Box<ChunkWrite + 'a>> {
+        if self.readonly {
+            return Err(Error::ReadOnly);
+        }
+        self.db.get_mut().unwrap().begin()?;
+        Ok(Box::new(FilePoolWriter {
+            pool: self,
+            files: vec![],
+            done: false,
+        }))
+    }
+
+    fn forget(&mut self, backups: &[Oid]) -> Result<()> {
+        if self.readonly {
+            return Err(Error::ReadOnly);
+        }
+        add_oids(self.db.get_mut().unwrap(),
+                 FORGOTTEN_TABLE,
+                 "INSERT OR IGNORE INTO forgotten (oid) VALUES (?)",
+                 &[],
+                 backups)
+    }
+
+    fn add_seen(&mut self, peer: &Uuid, backups: &[Oid]) -> Result<()> {
+        if self.readonly {
+            return Err(Error::ReadOnly);
+        }
+        add_oids(self.db.get_mut().unwrap(),
+                 SEEN_TABLE,
+                 "INSERT OR IGNORE INTO seen (peer, oid) VALUES (?, ?)",
+                 &[&peer.hyphenated().to_string()],
+                 backups)
+    }
+}
+
+/// Writes to a `FilePool` within a single database transaction.  Chunks
+/// too large to keep in the database are written to files as they are
+/// added, and these files are removed again if the transaction is rolled
+/// back.
+pub struct FilePoolWriter<'a> {
+    pool: &'a mut FilePool,
+    // The blob files written by this transaction.
+    files: Vec<PathBuf>,
+    // Has the transaction been committed (or tried to be).
+    done: bool,
+}
+
+impl<'a> FilePoolWriter<'a> {
+    fn remove_files(&mut self) {
+        for name in &self.files {
+            let _ = fs::remove_file(name);
+        }
+        self.files.clear();
+    }
+}
+
+impl<'a> ChunkWrite for FilePoolWriter<'a> {
+    fn reader(&self) -> &ChunkRead {
+        &*self.pool
+    }
 
     fn add(&mut self, chunk: &Chunk) -> Result<()> {
-        let payload = match chunk.zdata() {
+        pool::check_chunk(self.pool.alg, self.pool.max_chunk, chunk)?;
+        if self.pool.contains_key(chunk.oid())? {
+            return Ok(());
+        }
+
+        // A chunk that is already compressed with a codec this pool
+        // can't record has to be stored plain.
+        let mut zdata = chunk.zdata_policy(self.pool.codec, &self.pool.policy);
+        let mut codec = chunk.zcodec();
+        if !self.pool.has_codecs && codec != Codec::Zlib {
+            zdata = None;
+            codec = Codec::None;
+        }
+        let payload = match zdata {
             None => chunk.data(),
             Some(zdata) => zdata,
         };
+        let inline = payload.len() < 100000;
 
-        if payload.len() < 100000 {
-            self.db
-                .execute("INSERT INTO blobs (oid, kind, size, zsize, data)
-                    \
-                          VALUES (?, ?, ?, ?, ?)",
-                         &[&&chunk.oid().0[..],
-                           &chunk.kind().to_string(),
-                           &(chunk.data_len() as i32),
-                           &(payload.len() as i32),
-                           &&payload[..]])?;
-        } else {
-            let (dir, name) = self.get_paths(chunk.oid());
+        if !inline {
+            let (dir, name) = self.pool.get_paths(chunk.oid());
 
             // Just try writing the fd first.
             let mut fd = match fs::File::create(&name) {
@@ -184,41 +681,86 @@ impl ChunkSource for FilePool {
                 }
             };
 
+            self.files.push(name.clone());
             fd.write_all(&payload[..])?;
+        }
+
+        self.pool.insert(chunk.oid(),
+                         chunk.kind(),
+                         chunk.data_len() as i64,
+                         payload.len() as i64,
+                         if inline { Some(&payload[..]) } else { None },
+                         codec)
+    }
+
+    fn add_large(&mut self, kind: Kind, source: &mut Read) -> Result<Oid> {
+        // The data is streamed to a temporary file, and moved into place
+        // once its Oid is known.
+        let alg = self.pool.alg;
+        let tmp = self.pool.path.join("blobs").join(format!("tmp-{}", Uuid::new_v4().simple()));
+        let result = fs::File::create(&tmp)
+            .map_err(Error::from)
+            .and_then(|mut fd| pool::copy_hashed(alg, kind, source, &mut fd));
+        let (oid, size) = match result {
+            Ok(r) => r,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            }
+        };
 
-            self.db
-                .execute("INSERT INTO blobs (oid, kind, size, zsize)
-                     VALUES \
-                          (?, ?, ?, ?)",
-                         &[&&chunk.oid().0[..],
-                           &chunk.kind().to_string(),
-                           &(chunk.data_len() as i32),
-                           &(payload.len() as i32)])?;
+        if self.pool.contains_key(&oid)? {
+            fs::remove_file(&tmp)?;
+            return Ok(oid);
         }
 
-        Ok(())
+        let (dir, name) = self.pool.get_paths(&oid);
+        if !dir.is_dir() {
+            fs::create_dir(&dir)?;
+        }
+        fs::rename(&tmp, &name)?;
+        self.files.push(name);
+
+        // Large chunks are never compressed.
+        self.pool.insert(&oid, kind, size as i64, size as i64, None, Codec::None)?;
+        Ok(oid)
     }
 
-    fn flush(&mut self) -> Result<()> {
-        self.db.commit()?;
-        Ok(())
+    fn commit(mut self: Box<Self>) -> Result<()> {
+        // The transaction is finished by the commit, even if it fails, in
+        // which case it has been rolled back.
+        self.done = true;
+        match self.pool.db.get_mut().unwrap().commit() {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                self.remove_files();
+                Err(e.into())
+            }
+        }
     }
 }
 
-pub struct FilePoolWriter<'a> {
-    tx: SqliteTransaction<'a>,
+impl<'a> Drop for FilePoolWriter<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.pool.db.get_mut().unwrap().rollback();
+            self.remove_files();
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use pool::ChunkSource;
+    use pool::{ChunkRead, ChunkSource};
     use kind::Kind;
     // use std::path::Path;
     use std::collections::HashMap;
     use tempdir::TempDir;
+    use testutil;
     use testutil::{make_random_chunk, make_uncompressible_chunk, make_kinded_random_chunk,
-                   boundary_sizes};
+                   make_hashed_random_chunk, boundary_sizes};
+    use {Codec, HashAlg};
 
     #[test]
     fn simple_create() {
@@ -231,11 +773,11 @@ mod test {
         let mut all = HashMap::new();
 
         {
-            pool.begin_writing().unwrap();
+            let mut wr = pool.writer().unwrap();
 
             for i in boundary_sizes() {
                 let ch = make_random_chunk(i, i);
-                pool.add(&ch).unwrap();
+                wr.add(&ch).unwrap();
                 let oi = all.insert(ch.oid().clone(), ch);
                 match oi {
                     None => (),
@@ -249,7 +791,7 @@ mod test {
                     continue;
                 }
                 let ch = make_uncompressible_chunk(i, i);
-                pool.add(&ch).unwrap();
+                wr.add(&ch).unwrap();
                 let oi = all.insert(ch.oid().clone(), ch);
                 match oi {
                     None => (),
@@ -257,7 +799,7 @@ mod test {
                 }
             }
 
-            pool.flush().unwrap();
+            wr.commit().unwrap();
         }
 
         // Verify all of them.
@@ -281,14 +823,14 @@ mod test {
         let mut oids = HashSet::new();
 
         {
-            pool.begin_writing().unwrap();
+            let mut wr = pool.writer().unwrap();
 
             for i in 0..1000 {
                 let ch = make_kinded_random_chunk(Kind::new("back").unwrap(), 64, i);
-                pool.add(&ch).unwrap();
+                wr.add(&ch).unwrap();
                 oids.insert(ch.oid().clone());
             }
-            pool.flush().unwrap();
+            wr.commit().unwrap();
         }
 
         for id in pool.backups().unwrap() {
@@ -298,46 +840,390 @@ mod test {
 
         assert_eq!(oids.len(), 0);
     }
+
+    #[test]
+    fn hashed() {
+        let tmp = TempDir::new("filepool").unwrap();
+        let path = tmp.path().join("pool");
+
+        FilePool::new_builder(&path).set_hash(HashAlg::Blake2b256).create().unwrap();
+        let mut pool = FilePool::open(&path).unwrap();
+        assert_eq!(pool.hash_alg(), HashAlg::Blake2b256);
+
+        let mut chunks = vec![];
+        {
+            let mut wr = pool.writer().unwrap();
+            for i in boundary_sizes() {
+                let ch = make_hashed_random_chunk(HashAlg::Blake2b256, i, i);
+                wr.add(&ch).unwrap();
+                chunks.push(ch);
+            }
+
+            // Chunks of a different hash can't be stored.
+            assert!(wr.add(&make_random_chunk(64, 1)).is_err());
+            wr.commit().unwrap();
+        }
+
+        for c1 in &chunks {
+            let c2 = pool.find(c1.oid()).unwrap();
+            assert_eq!(c1.oid(), c2.oid());
+            assert_eq!(&c1.data()[..], &c2.data()[..]);
+        }
+    }
+
+    #[test]
+    fn codecs() {
+        for &codec in &[Codec::None, Codec::Zstd, Codec::Lz4] {
+            let tmp = TempDir::new("filepool").unwrap();
+            let path = tmp.path().join("pool");
+
+            FilePool::new_builder(&path).set_codec(codec).create().unwrap();
+            let mut pool = FilePool::open(&path).unwrap();
+            assert_eq!(pool.codec(), codec);
+
+            let mut chunks = vec![];
+            {
+                let mut wr = pool.writer().unwrap();
+                for i in boundary_sizes() {
+                    let ch = make_random_chunk(i, i);
+                    wr.add(&ch).unwrap();
+                    chunks.push(ch);
+                }
+                wr.commit().unwrap();
+            }
+
+            for c1 in &chunks {
+                let c2 = pool.find(c1.oid()).unwrap();
+                assert_eq!(c1.oid(), c2.oid());
+                if c2.zdata().is_some() {
+                    assert_eq!(c2.zcodec(), codec);
+                }
+                assert_eq!(&c1.data()[..], &c2.data()[..]);
+            }
+        }
+    }
+
+    // Only pools that need the codec column get the new schema, and only
+    // properties that differ from the legacy defaults are written.
+    #[test]
+    fn schema_version() {
+        use rusqlite::SqliteConnection;
+
+        let tmp = TempDir::new("filepool").unwrap();
+        for &(codec, version) in &[(Codec::Zlib, "1:2014-03-18"), (Codec::Zstd, "2:2026-10-17")] {
+            let path = tmp.path().join(codec.name());
+            FilePool::new_builder(&path).set_codec(codec).create().unwrap();
+
+            let db = SqliteConnection::open(&path.join("data.db")).unwrap();
+            let actual: String =
+                db.query_row("SELECT version FROM schema_version", &[], |row| row.get(0)).unwrap();
+            assert_eq!(actual, version);
+            assert_eq!(super::get_prop(&db, "hash").unwrap(), None);
+            let expected = if codec == Codec::Zlib { None } else { Some(codec.name().to_owned()) };
+            assert_eq!(super::get_prop(&db, "codec").unwrap(), expected);
+
+            let pool = FilePool::open(&path).unwrap();
+            assert_eq!(pool.codec(), codec);
+            assert_eq!(pool.hash_alg(), HashAlg::Sha1);
+        }
+    }
+
+    #[test]
+    fn check() {
+        let tmp = TempDir::new("filepool").unwrap();
+        let path = tmp.path().join("pool");
+
+        FilePool::create(&path).unwrap();
+        let mut pool = FilePool::open(&path).unwrap();
+        let mut chunks = vec![];
+        let external = make_uncompressible_chunk(200000, 1);
+        {
+            let mut wr = pool.writer().unwrap();
+            for i in 0..10 {
+                let ch = make_random_chunk(i * 100 + 16, i);
+                wr.add(&ch).unwrap();
+                chunks.push(ch);
+            }
+
+            // Large enough to be stored in its own file.
+            wr.add(&external).unwrap();
+            wr.commit().unwrap();
+        }
+
+        let report = pool.check().unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.good, 11);
+
+        let (dir, name) = pool.get_paths(external.oid());
+        fs::remove_file(&name).unwrap();
+        fs::File::create(dir.join("0123")).unwrap();
+        pool.db
+            .lock()
+            .unwrap()
+            .execute("UPDATE blobs SET kind = 'junk' WHERE oid = ?",
+                     &[&chunks[2].oid().as_bytes()])
+            .unwrap();
+
+        let report = pool.check().unwrap();
+        assert_eq!(report.good, 9);
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].oid.as_ref(), Some(external.oid()));
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!(report.corrupt[0].oid.as_ref(), Some(chunks[2].oid()));
+        assert_eq!(report.orphaned.len(), 1);
+        assert!(report.duplicate.is_empty());
+    }
+
+    #[test]
+    fn large() {
+        let tmp = TempDir::new("filepool").unwrap();
+        let path = tmp.path().join("pool");
+
+        FilePool::new_builder(&path).set_max_chunk_size(256 * 1024).create().unwrap();
+        {
+            let mut pool = FilePool::open(&path).unwrap();
+            testutil::check_large_chunks(&mut pool);
+        }
+
+        let pool = FilePool::open(&path).unwrap();
+        assert_eq!(pool.max_chunk_size(), 256 * 1024);
+        assert!(pool.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn forget() {
+        use std::collections::BTreeSet;
+
+        let tmp = TempDir::new("filepool").unwrap();
+        let path = tmp.path().join("pool");
+
+        FilePool::create(&path).unwrap();
+        let listed = {
+            let mut pool = FilePool::open(&path).unwrap();
+            testutil::check_forget(&mut pool)
+        };
+
+        // The forgotten backups stay forgotten.
+        let mut pool = FilePool::open_readonly(&path).unwrap();
+        let backups: BTreeSet<_> = pool.backups().unwrap().into_iter().collect();
+        assert_eq!(backups, listed);
+        match pool.forget(&[]) {
+            Err(Error::ReadOnly) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn seen() {
+        let tmp = TempDir::new("filepool").unwrap();
+        let path = tmp.path().join("pool");
+
+        FilePool::create(&path).unwrap();
+        let peer1 = Uuid::new_v4();
+        let peer2 = Uuid::new_v4();
+        let oids: Vec<Oid> = (1..5).map(|i| make_random_chunk(64, i).oid().clone()).collect();
+
+        // Without the table, nothing has been seen.
+        assert!(FilePool::open_readonly(&path).unwrap().seen(&peer1).unwrap().is_empty());
+
+        {
+            let mut pool = FilePool::open(&path).unwrap();
+            pool.add_seen(&peer1, &oids[..3]).unwrap();
+            pool.add_seen(&peer1, &oids[2..]).unwrap();
+            pool.add_seen(&peer2, &oids[..1]).unwrap();
+        }
+
+        let pool = FilePool::open_readonly(&path).unwrap();
+        let mut seen = pool.seen(&peer1).unwrap();
+        seen.sort();
+        let mut expected = oids.clone();
+        expected.sort();
+        assert_eq!(seen, expected);
+        assert_eq!(pool.seen(&peer2).unwrap(), &oids[..1]);
+        assert!(pool.seen(&Uuid::new_v4()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn readonly() {
+        let tmp = TempDir::new("filepool").unwrap();
+        let path = tmp.path().join("pool");
+
+        FilePool::create(&path).unwrap();
+        let chunk = make_random_chunk(1024, 1);
+        {
+            let mut pool = FilePool::open(&path).unwrap();
+            let mut wr = pool.writer().unwrap();
+            wr.add(&chunk).unwrap();
+            wr.commit().unwrap();
+        }
+
+        let mut pool = FilePool::open_readonly(&path).unwrap();
+        assert_eq!(&pool.find(chunk.oid()).unwrap().data()[..], &chunk.data()[..]);
+        match pool.writer() {
+            Err(Error::ReadOnly) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Read-only pool was writable"),
+        }
+    }
+
+    #[test]
+    fn rollback() {
+        let tmp = TempDir::new("filepool").unwrap();
+        let path = tmp.path().join("pool");
+
+        FilePool::create(&path).unwrap();
+        let mut pool = FilePool::open(&path).unwrap();
+        let kept = make_random_chunk(1024, 1);
+        {
+            let mut wr = pool.writer().unwrap();
+            wr.add(&kept).unwrap();
+            // Adding a chunk twice does nothing.
+            wr.add(&kept).unwrap();
+            wr.commit().unwrap();
+        }
+
+        let small = make_random_chunk(1024, 2);
+        let external = make_uncompressible_chunk(200000, 3);
+        {
+            let mut wr = pool.writer().unwrap();
+            wr.add(&kept).unwrap();
+            wr.add(&small).unwrap();
+            wr.add(&external).unwrap();
+            assert!(wr.reader().contains_key(external.oid()).unwrap());
+        }
+
+        // Dropping the writer discards the chunks, and their files.
+        assert!(pool.contains_key(kept.oid()).unwrap());
+        assert!(!pool.contains_key(small.oid()).unwrap());
+        assert!(!pool.contains_key(external.oid()).unwrap());
+        assert!(!pool.get_paths(external.oid()).1.exists());
+        assert!(pool.check().unwrap().is_clean());
+    }
+
+    // A commit that fails, here because another connection is reading the
+    // database, is rolled back, and the pool can still be written to.
+    #[test]
+    fn failed_commit() {
+        let tmp = TempDir::new("filepool").unwrap();
+        let path = tmp.path().join("pool");
+
+        FilePool::create(&path).unwrap();
+        let mut pool = FilePool::open(&path).unwrap();
+        let small = make_random_chunk(1024, 1);
+        let external = make_uncompressible_chunk(200000, 2);
+
+        let reader = SqliteConnection::open(&path.join("data.db")).unwrap();
+        reader.execute_batch("BEGIN").unwrap();
+        let count: i32 = reader.query_row("SELECT COUNT(*) FROM blobs", &[], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+        {
+            let mut wr = pool.writer().unwrap();
+            wr.add(&small).unwrap();
+            wr.add(&external).unwrap();
+            assert!(wr.commit().is_err());
+        }
+        reader.execute_batch("ROLLBACK").unwrap();
+
+        assert!(!pool.contains_key(small.oid()).unwrap());
+        assert!(!pool.contains_key(external.oid()).unwrap());
+        assert!(!pool.get_paths(external.oid()).1.exists());
+
+        {
+            let mut wr = pool.writer().unwrap();
+            wr.add(&small).unwrap();
+            wr.add(&external).unwrap();
+            wr.commit().unwrap();
+        }
+        assert!(pool.contains_key(small.oid()).unwrap());
+        assert!(pool.contains_key(external.oid()).unwrap());
+        assert!(pool.check().unwrap().is_clean());
+    }
 }
 
 #[derive(PartialEq, Eq, Clone)]
 enum PoolInabilities {
     NoFilesystems,
     NoCTimeCache,
+    // The blobs table has no codec column, all compressed data is zlib.
+    NoCodec,
 }
 
+// Pools using zlib, as every legacy pool does, are created with the
+// legacy schema, so that legacy programs can still read them.  Only pools
+// using another codec need the codec column, which legacy programs don't
+// know about.
 static POOL_SCHEMA: sql::Schema<'static, PoolInabilities> = sql::Schema {
+    version: "2:2026-10-17",
+    schema: &[PAGE_SIZE,
+              BLOBS_CODEC_TABLE,
+              BLOBS_OID_INDEX,
+              BLOBS_BACKS_INDEX,
+              PROPS_TABLE,
+              FILESYSTEMS_TABLE,
+              CTIME_DIRS_TABLE,
+              CTIME_CACHE_TABLE,
+              CTIME_CACHE_INDEX],
+    compats: &[sql::SchemaCompat {
+                   version: "1:2014-03-18",
+                   inabilities: &[PoolInabilities::NoCodec],
+               },
+               sql::SchemaCompat {
+                   version: "1:2014-03-13",
+                   inabilities: &[PoolInabilities::NoFilesystems,
+                                  PoolInabilities::NoCTimeCache,
+                                  PoolInabilities::NoCodec],
+               }],
+};
+
+static LEGACY_SCHEMA: sql::Schema<'static, PoolInabilities> = sql::Schema {
     version: "1:2014-03-18",
-    schema: &[r#"PRAGMA PAGE_SIZE=8192"#,
-              r#"CREATE TABLE blobs (
+    schema: &[PAGE_SIZE,
+              BLOBS_TABLE,
+              BLOBS_OID_INDEX,
+              BLOBS_BACKS_INDEX,
+              PROPS_TABLE,
+              FILESYSTEMS_TABLE,
+              CTIME_DIRS_TABLE,
+              CTIME_CACHE_TABLE,
+              CTIME_CACHE_INDEX],
+    compats: &[],
+};
+
+const PAGE_SIZE: &'static str = r#"PRAGMA PAGE_SIZE=8192"#;
+const BLOBS_TABLE: &'static str = r#"CREATE TABLE blobs (
                 id INTEGER PRIMARY KEY,
                 oid BLOB UNIQUE NOT NULL,
                 kind TEXT,
                 size INTEGER,
                 zsize INTEGER,
-                data BLOB)"#,
-              r#"CREATE INDEX blobs_oid ON blobs(oid)"#,
-              r#"CREATE INDEX blobs_backs ON blobs(kind) where kind = 'back'"#,
-              r#"CREATE TABLE props (
+                data BLOB)"#;
+const BLOBS_CODEC_TABLE: &'static str = r#"CREATE TABLE blobs (
+                id INTEGER PRIMARY KEY,
+                oid BLOB UNIQUE NOT NULL,
+                kind TEXT,
+                size INTEGER,
+                zsize INTEGER,
+                data BLOB,
+                codec INTEGER)"#;
+const BLOBS_OID_INDEX: &'static str = r#"CREATE INDEX blobs_oid ON blobs(oid)"#;
+const BLOBS_BACKS_INDEX: &'static str =
+    r#"CREATE INDEX blobs_backs ON blobs(kind) where kind = 'back'"#;
+const PROPS_TABLE: &'static str = r#"CREATE TABLE props (
                 key text PRIMARY KEY,
-                value TEXT)"#,
-              r#"CREATE TABLE filesystems (
+                value TEXT)"#;
+const FILESYSTEMS_TABLE: &'static str = r#"CREATE TABLE filesystems (
                 fsid INTEGER PRIMARY KEY,
-                uuid TEXT UNIQUE)"#,
-              r#"CREATE TABLE ctime_dirs (
+                uuid TEXT UNIQUE)"#;
+const CTIME_DIRS_TABLE: &'static str = r#"CREATE TABLE ctime_dirs (
                 pkey INTEGER PRIMARY KEY,
                 fsid INTEGER REFERENCES filesystem (fsid) NOT NULL,
                 pino INTEGER NOT NULL,
-                UNIQUE (fsid, pino))"#,
-              r#"CREATE TABLE ctime_cache (
+                UNIQUE (fsid, pino))"#;
+const CTIME_CACHE_TABLE: &'static str = r#"CREATE TABLE ctime_cache (
                 pkey INTEGER REFERENCES ctime_dirs (pkey) NOT NULL,
                 ino INTEGER NOT NULL,
                 expire INTEGER NOT NULL,
                 ctime INTEGER NOT NULL,
-                oid BLOB NOT NULL)"#,
-              r#"CREATE INDEX ctime_cache_pkey ON ctime_cache(pkey)"#],
-    compats: &[sql::SchemaCompat {
-                   version: "1:2014-03-13",
-                   inabilities: &[PoolInabilities::NoFilesystems, PoolInabilities::NoCTimeCache],
-               }],
-};
+                oid BLOB NOT NULL)"#;
+const CTIME_CACHE_INDEX: &'static str = r#"CREATE INDEX ctime_cache_pkey ON ctime_cache(pkey)"#;
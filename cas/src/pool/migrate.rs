@@ -0,0 +1,370 @@
+// Converting pools from one type to another.
+
+use std::collections::HashSet;
+
+use Error;
+use Kind;
+use Oid;
+use Result;
+use pool::{ChunkRead, ChunkSource, ChunkWrite};
+
+/// The result of a migration.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MigrateReport {
+    /// The number of chunks copied.
+    pub chunks: u64,
+    /// How many of the chunks copied were large chunks, which are
+    /// streamed rather than read whole.
+    pub large: u64,
+    /// The number of chunks already in the destination, such as from an
+    /// earlier migration that was interrupted.
+    pub present: u64,
+    /// The number of bytes of data in the chunks copied.
+    pub bytes: u64,
+}
+
+// Chunks are added in transactions of about this much data, so that an
+// interrupted migration only loses the work since the last commit.
+const COMMIT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Copy every chunk in `src` to `dest`, which must have the same uuid and
+/// hash, and would normally be a new pool of a different type.  Chunks
+/// are copied with their compressed payload, so they are only compressed
+/// again if they were stored uncompressed, and chunks too large for `dest`
+/// are stored in it as large chunks.  Backups forgotten in `src` are
+/// forgotten in `dest` as well, and the backups `src` has seen from other
+/// pools are recorded as seen in `dest`.
+///
+/// The backup chunks are copied last, so that `dest` never lists a backup
+/// whose chunks aren't all there.  Running the migration again, after it
+/// has been interrupted, only copies what is missing.  Once everything is
+/// copied, `dest` is checked to hold every chunk and backup of `src`.
+pub fn migrate(src: &ChunkRead, dest: &mut ChunkSource) -> Result<MigrateReport> {
+    if dest.uuid() != src.uuid() {
+        return Err(Error::PropertyError(format!("Pool uuid {} doesn't match {}",
+                                                dest.uuid().hyphenated(),
+                                                src.uuid().hyphenated())));
+    }
+    if dest.hash_alg() != src.hash_alg() {
+        return Err(Error::WrongHash(format!("{} pool can't hold the chunks of a {} pool",
+                                            dest.hash_alg().name(),
+                                            src.hash_alg().name())));
+    }
+
+    let back = Kind::new("back").unwrap();
+    let mut chunks = src.list()?;
+    chunks.sort_by_key(|&(_, kind)| kind == back);
+
+    let live: HashSet<Oid> = src.backups()?.into_iter().collect();
+    let forgotten: Vec<Oid> = chunks.iter()
+        .filter(|&&(ref oid, kind)| kind == back && !live.contains(oid))
+        .map(|&(ref oid, _)| oid.clone())
+        .collect();
+    if !forgotten.is_empty() {
+        dest.forget(&forgotten)?;
+    }
+    for peer in src.peers()? {
+        dest.add_seen(&peer, &src.seen(&peer)?)?;
+    }
+
+    let mut report = MigrateReport::default();
+    let mut pos = 0;
+    while pos < chunks.len() {
+        let mut wr = dest.writer()?;
+        let mut added = 0;
+        while pos < chunks.len() && added < COMMIT_BYTES {
+            let (ref oid, kind) = chunks[pos];
+            pos += 1;
+            if wr.reader().contains_key(oid)? {
+                report.present += 1;
+                continue;
+            }
+            added += copy_chunk(src, &mut *wr, oid, kind, &mut report)?;
+        }
+        wr.commit()?;
+    }
+
+    for &(ref oid, _) in &chunks {
+        if !dest.contains_key(oid)? {
+            return Err(Error::CorruptPool(format!("Chunk {} missing after migration",
+                                                  oid.to_hex())));
+        }
+    }
+    let mut expected = src.backups()?;
+    expected.sort();
+    let mut backups = dest.backups()?;
+    backups.sort();
+    if backups != expected {
+        return Err(Error::CorruptPool("Backups differ after migration".to_owned()));
+    }
+
+    Ok(report)
+}
+
+// Copy a single chunk, returning the number of bytes of data in it.
+fn copy_chunk(src: &ChunkRead,
+              wr: &mut ChunkWrite,
+              oid: &Oid,
+              kind: Kind,
+              report: &mut MigrateReport)
+              -> Result<u64> {
+    let len = match src.find(oid) {
+        Ok(ref chunk) if chunk.data_len() as usize > wr.reader().max_chunk_size() => {
+            let actual = wr.add_large(kind, &mut &chunk.data()[..])?;
+            if &actual != oid {
+                return Err(Error::HashMismatch(oid.clone(), actual));
+            }
+            report.large += 1;
+            chunk.data_len() as u64
+        }
+        Ok(chunk) => {
+            wr.add(&chunk)?;
+            chunk.data_len() as u64
+        }
+        Err(Error::ChunkTooLarge(len)) => {
+            let mut rd = src.read_large(oid)?;
+            let actual = wr.add_large(kind, &mut rd)?;
+            if &actual != oid {
+                return Err(Error::HashMismatch(oid.clone(), actual));
+            }
+            report.large += 1;
+            len
+        }
+        Err(e) => return Err(e),
+    };
+    report.chunks += 1;
+    report.bytes += len;
+    Ok(len)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pool::{AdumpPool, ChunkRead, ChunkSource, FilePool};
+    use std::io::{self, Read};
+    use tempdir::TempDir;
+    use testutil::{make_kinded_random_chunk, make_random_chunk, make_uncompressible_chunk};
+    use uuid::Uuid;
+    use Error;
+    use Kind;
+    use Oid;
+
+    // Both pools use the same, small, limit, so that the large chunk
+    // doesn't have to be huge.
+    const MAX_CHUNK: usize = 256 * 1024;
+
+    #[test]
+    fn file_to_adump() {
+        let tmp = TempDir::new("migrate").unwrap();
+        let src_path = tmp.path().join("src");
+        let dest_path = tmp.path().join("dest");
+
+        FilePool::new_builder(&src_path).set_max_chunk_size(MAX_CHUNK).create().unwrap();
+        let mut src = FilePool::open(&src_path).unwrap();
+        fill(&mut src);
+        AdumpPool::new_builder(&dest_path)
+            .set_uuid(src.uuid().clone())
+            .set_max_chunk_size(MAX_CHUNK)
+            .create()
+            .unwrap();
+        let mut dest = AdumpPool::open(&dest_path).unwrap();
+        check_migrate(&src, &mut dest);
+
+        // The copy is intact once reopened.
+        let dest = AdumpPool::open_readonly(&dest_path).unwrap();
+        check_same(&src, &dest);
+        assert!(dest.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn adump_to_file() {
+        let tmp = TempDir::new("migrate").unwrap();
+        let src_path = tmp.path().join("src");
+        let dest_path = tmp.path().join("dest");
+
+        AdumpPool::new_builder(&src_path).set_max_chunk_size(MAX_CHUNK).create().unwrap();
+        let src = {
+            let mut src = AdumpPool::open(&src_path).unwrap();
+            fill(&mut src);
+            src
+        };
+        FilePool::new_builder(&dest_path)
+            .set_uuid(src.uuid().clone())
+            .set_max_chunk_size(MAX_CHUNK)
+            .create()
+            .unwrap();
+        let mut dest = FilePool::open(&dest_path).unwrap();
+        check_migrate(&src, &mut dest);
+        assert!(dest.check().unwrap().is_clean());
+    }
+
+    // Chunks too large for the destination are stored in it as large
+    // chunks.
+    #[test]
+    fn smaller_limit() {
+        let tmp = TempDir::new("migrate").unwrap();
+        let src_path = tmp.path().join("src");
+        let dest_path = tmp.path().join("dest");
+
+        FilePool::new_builder(&src_path).set_max_chunk_size(MAX_CHUNK).create().unwrap();
+        let mut src = FilePool::open(&src_path).unwrap();
+        fill(&mut src);
+        let chunk = make_random_chunk(MAX_CHUNK as u32 / 2, 100);
+        {
+            let mut wr = src.writer().unwrap();
+            wr.add(&chunk).unwrap();
+            wr.commit().unwrap();
+        }
+        AdumpPool::new_builder(&dest_path)
+            .set_uuid(src.uuid().clone())
+            .set_max_chunk_size(MAX_CHUNK / 4)
+            .create()
+            .unwrap();
+        let mut dest = AdumpPool::open(&dest_path).unwrap();
+        let report = migrate(&src, &mut dest).unwrap();
+        assert_eq!(report.large, 2);
+
+        match dest.find(chunk.oid()) {
+            Err(Error::ChunkTooLarge(_)) => (),
+            r => panic!("Unexpected result: {:?}", r.map(|c| c.oid().clone())),
+        }
+        let mut data = vec![];
+        dest.read_large(chunk.oid()).unwrap().read_to_end(&mut data).unwrap();
+        assert!(&data[..] == &chunk.data()[..]);
+        assert!(dest.check().unwrap().is_clean());
+    }
+
+    // A destination that isn't a copy of the source is refused.
+    #[test]
+    fn wrong_uuid() {
+        let tmp = TempDir::new("migrate").unwrap();
+        let src_path = tmp.path().join("src");
+        let dest_path = tmp.path().join("dest");
+
+        FilePool::create(&src_path).unwrap();
+        let src = FilePool::open(&src_path).unwrap();
+        AdumpPool::new_builder(&dest_path).create().unwrap();
+        let mut dest = AdumpPool::open(&dest_path).unwrap();
+        match migrate(&src, &mut dest) {
+            Err(Error::PropertyError(_)) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Migrated to a pool with another uuid"),
+        }
+    }
+
+    // Fill a pool with compressible and uncompressible chunks, a large
+    // chunk, and some backups, one of which is forgotten, and some seen
+    // from other pools.
+    fn fill(pool: &mut ChunkSource) {
+        let back = Kind::new("back").unwrap();
+        let forgotten = {
+            let mut wr = pool.writer().unwrap();
+            for i in 0..50 {
+                wr.add(&make_random_chunk(1000 + i * 100, i)).unwrap();
+                wr.add(&make_uncompressible_chunk(1000 + i * 100, i + 1)).unwrap();
+            }
+            let size = wr.reader().max_chunk_size() as u64 + 1;
+            wr.add_large(Kind::new("blob").unwrap(), &mut io::repeat(0x5a).take(size))
+                .unwrap();
+            let mut backs = vec![];
+            for i in 0..3 {
+                let chunk = make_kinded_random_chunk(back, 64, i);
+                wr.add(&chunk).unwrap();
+                backs.push(chunk.oid().clone());
+            }
+            wr.commit().unwrap();
+            backs[1].clone()
+        };
+        pool.forget(&[forgotten]).unwrap();
+
+        for i in 0..2 {
+            let seen: Vec<Oid> = (0..3)
+                .map(|j| make_kinded_random_chunk(back, 64, 10 * i + j + 10).oid().clone())
+                .collect();
+            pool.add_seen(&Uuid::new_v4(), &seen).unwrap();
+        }
+    }
+
+    // Migrate `src` to `dest`, after copying some of it already, as if an
+    // earlier migration had been interrupted.  Then migrating again finds
+    // everything present.
+    fn check_migrate(src: &ChunkRead, dest: &mut ChunkSource) {
+        let chunks = src.list().unwrap();
+        let small: Vec<Oid> = chunks.iter()
+            .filter(|&&(_, kind)| kind == Kind::new("blob").unwrap())
+            .map(|&(ref oid, _)| oid.clone())
+            .filter(|oid| src.find(oid).is_ok())
+            .take(10)
+            .collect();
+        {
+            let mut wr = dest.writer().unwrap();
+            for oid in &small {
+                wr.add(&src.find(oid).unwrap()).unwrap();
+            }
+            wr.commit().unwrap();
+        }
+
+        let report = migrate(src, dest).unwrap();
+        assert_eq!(report.present, 10);
+        assert_eq!(report.chunks as usize, chunks.len() - 10);
+        assert_eq!(report.large, 1);
+        check_same(src, dest);
+
+        let report = migrate(src, dest).unwrap();
+        assert_eq!(report.present as usize, chunks.len());
+        assert_eq!(report.chunks, 0);
+    }
+
+    // Check that `dest` holds the same chunks and backups as `src`, with
+    // the same compression.
+    fn check_same(src: &ChunkRead, dest: &ChunkRead) {
+        assert_eq!(dest.uuid(), src.uuid());
+
+        let mut chunks = src.list().unwrap();
+        chunks.sort();
+        let mut copied = dest.list().unwrap();
+        copied.sort();
+        assert_eq!(copied, chunks);
+
+        let mut backups = src.backups().unwrap();
+        backups.sort();
+        let mut copied = dest.backups().unwrap();
+        copied.sort();
+        assert_eq!(backups.len(), 2);
+        assert_eq!(copied, backups);
+
+        let mut peers = src.peers().unwrap();
+        peers.sort();
+        let mut copied = dest.peers().unwrap();
+        copied.sort();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(copied, peers);
+        for peer in &peers {
+            let mut seen = src.seen(peer).unwrap();
+            seen.sort();
+            let mut copied = dest.seen(peer).unwrap();
+            copied.sort();
+            assert_eq!(seen.len(), 3);
+            assert_eq!(copied, seen);
+        }
+
+        for &(ref oid, _) in &chunks {
+            match src.find(oid) {
+                Ok(c1) => {
+                    let c2 = dest.find(oid).unwrap();
+                    assert_eq!(c2.kind(), c1.kind());
+                    assert_eq!(c2.zcodec(), c1.zcodec());
+                    assert_eq!(&c2.data()[..], &c1.data()[..]);
+                }
+                Err(Error::ChunkTooLarge(_)) => {
+                    let mut d1 = vec![];
+                    src.read_large(oid).unwrap().read_to_end(&mut d1).unwrap();
+                    let mut d2 = vec![];
+                    dest.read_large(oid).unwrap().read_to_end(&mut d2).unwrap();
+                    assert!(d1 == d2);
+                }
+                Err(e) => panic!("Unexpected error: {:?}", e),
+            }
+        }
+    }
+}
@@ -1,29 +1,46 @@
 // A pool is a place that chunks can be stored.
 
 use Result;
+use Codec;
+use CompressPolicy;
 use Error;
+use HashAlg;
+use Kind;
+use OidHasher;
 use oid::Oid;
-use chunk::Chunk;
+use chunk::{Chunk, MAX_CHUNK_SIZE};
 use uuid::Uuid;
 
+use std::io::{ErrorKind, Read, Write};
 use std::path::Path;
 use std::fs;
 
 pub use pool::file::FilePool;
 pub use pool::adump::AdumpPool;
 pub use self::ram::RamPool;
+pub use self::verify::VerifyingSource;
+pub use self::check::{CheckItem, CheckReport};
+pub use self::migrate::{migrate, MigrateReport};
 
+mod check;
+mod migrate;
 mod sql;
 mod file;
 mod ram;
 mod wrapper;
+mod verify;
 pub mod adump;
 
 /// A source of chunks.  This is similar to a `Map`, except that the values
 /// aren't kept in memory, so we have to return real items rather than
 /// references to them.
-pub trait ChunkSource {
-    /// Return a new chunk with the given key.
+///
+/// Pools are `Send` and `Sync`, and the reading methods may be called from
+/// several threads at once.
+pub trait ChunkRead: Send + Sync {
+    /// Return a new chunk with the given key.  The chunk's data is
+    /// decompressed, so that a chunk that is corrupt in the pool results in
+    /// `Error::CorruptChunk` here, rather than failing when it is used.
     fn find(&self, key: &Oid) -> Result<Chunk>;
 
     /// Is this key present in the store.
@@ -35,29 +52,359 @@ pub trait ChunkSource {
     /// Return the Uuid associated with this pool.
     fn uuid<'a>(&'a self) -> &'a Uuid;
 
-    /// Return the set of backups stored in this pool.
+    /// Return the hash algorithm used for the Oids in this pool.  Chunks
+    /// added to the pool must be hashed with this algorithm.
+    fn hash_alg(&self) -> HashAlg;
+
+    /// Return the codec this pool uses to compress new chunks.  Chunks
+    /// that are already compressed when they are added may be stored with
+    /// their existing compression.
+    fn codec(&self) -> Codec;
+
+    /// Return the policy deciding which chunks this pool compresses.
+    fn compress_policy(&self) -> &CompressPolicy;
+
+    /// Return the set of backups stored in this pool, other than those
+    /// that have been forgotten.
     fn backups(&self) -> Result<Vec<Oid>>;
 
-    /// Begin allowing writing on this particular ChunkSource.
-    fn begin_writing(&mut self) -> Result<()>;
+    /// Return the backups from the pool with the uuid `peer` that have
+    /// been recorded as seen by `ChunkSource::add_seen`.
+    fn seen(&self, peer: &Uuid) -> Result<Vec<Oid>>;
+
+    /// Return the uuids of every pool with backups recorded as seen, in no
+    /// particular order.
+    fn peers(&self) -> Result<Vec<Uuid>>;
+
+    /// Return the Oid and kind of every chunk in the pool, in no particular
+    /// order.  This includes large chunks, and the chunks of forgotten
+    /// backups that are still in the pool.
+    fn list(&self) -> Result<Vec<(Oid, Kind)>>;
+
+    /// Return the size of the largest chunk that `ChunkWrite::add` will
+    /// accept, and that `find` will return.
+    fn max_chunk_size(&self) -> usize {
+        MAX_CHUNK_SIZE
+    }
+
+    /// Return a reader for the data of a chunk.  This works with any
+    /// chunk, but is the only way to read chunks larger than
+    /// `max_chunk_size`, for which `find` returns `Error::ChunkTooLarge`.
+    /// Unlike `find`, the data of large chunks is not checked.
+    fn read_large(&self, key: &Oid) -> Result<Box<Read + Send>>;
+
+    /// Read every chunk in the pool, checking that it is intact and agrees
+    /// with the pool's own records of it.  Problems with the chunks are
+    /// described in the report, errors are only returned if the pool
+    /// can't be read at all.
+    fn check(&self) -> Result<CheckReport>;
+}
+
+/// A pool that can also be written to.  Writing requires exclusive access
+/// to the pool, and is done through a `ChunkWrite`.
+pub trait ChunkSource: ChunkRead {
+    /// Begin writing to this pool.  The writer borrows the pool for as
+    /// long as it exists, so the pool can only be read through
+    /// `ChunkWrite::reader` until it is committed or dropped.
+    fn writer<'a>(&'a mut self) -> Result<Box<ChunkWrite + 'a>>;
 
-    /// Add a new chunk to this pool.
+    /// Forget the given backups, so that `backups` no longer returns them.
+    /// This is recorded in the pool right away, but the chunks of the
+    /// backups stay in the pool until it is compacted.
+    fn forget(&mut self, backups: &[Oid]) -> Result<()>;
+
+    /// Record that the given backups, from the pool with the uuid `peer`,
+    /// have been seen.  Syncing uses this so that backups already copied,
+    /// even ones since forgotten, aren't copied again.
+    fn add_seen(&mut self, peer: &Uuid, backups: &[Oid]) -> Result<()>;
+}
+
+/// A transaction adding chunks to a pool.
+///
+/// Every pool gives its writers the same semantics:
+///
+/// - Chunks added are visible to reads through `reader` right away, but
+///   only become a permanent part of the pool when `commit` returns
+///   successfully.
+/// - A writer dropped without being committed is rolled back, leaving the
+///   pool as it was before the writer was created.
+/// - Adding a chunk that is already in the pool, or was already added by
+///   this writer, does nothing.
+pub trait ChunkWrite: Send {
+    /// Read the pool being written to, including the chunks added so far.
+    fn reader(&self) -> &ChunkRead;
+
+    /// Add a new chunk to the pool.
     fn add(&mut self, chunk: &Chunk) -> Result<()>;
 
-    /// Consume the writer, closing the transaction.
-    fn flush(&mut self) -> Result<()>;
+    /// Store all of the data read from `source` as a single chunk,
+    /// returning its Oid.  The data can be larger than `max_chunk_size`,
+    /// and is streamed into the pool rather than held in memory.  The Oid
+    /// is the same as a chunk with the same kind and data would have.
+    fn add_large(&mut self, kind: Kind, source: &mut Read) -> Result<Oid>;
+
+    /// Make everything added through this writer permanent.  If this
+    /// fails, the writer is rolled back.
+    fn commit(self: Box<Self>) -> Result<()>;
+}
+
+// Allow boxed pools, such as those returned by `open`, to be used
+// wherever a pool is expected, such as in a `VerifyingSource`.
+impl<T: ChunkRead + ?Sized> ChunkRead for Box<T> {
+    fn find(&self, key: &Oid) -> Result<Chunk> {
+        (**self).find(key)
+    }
+
+    fn contains_key(&self, key: &Oid) -> Result<bool> {
+        (**self).contains_key(key)
+    }
+
+    fn uuid<'a>(&'a self) -> &'a Uuid {
+        (**self).uuid()
+    }
+
+    fn hash_alg(&self) -> HashAlg {
+        (**self).hash_alg()
+    }
+
+    fn codec(&self) -> Codec {
+        (**self).codec()
+    }
+
+    fn compress_policy(&self) -> &CompressPolicy {
+        (**self).compress_policy()
+    }
+
+    fn backups(&self) -> Result<Vec<Oid>> {
+        (**self).backups()
+    }
+
+    fn seen(&self, peer: &Uuid) -> Result<Vec<Oid>> {
+        (**self).seen(peer)
+    }
+
+    fn peers(&self) -> Result<Vec<Uuid>> {
+        (**self).peers()
+    }
+
+    fn list(&self) -> Result<Vec<(Oid, Kind)>> {
+        (**self).list()
+    }
+
+    fn max_chunk_size(&self) -> usize {
+        (**self).max_chunk_size()
+    }
+
+    fn read_large(&self, key: &Oid) -> Result<Box<Read + Send>> {
+        (**self).read_large(key)
+    }
+
+    fn check(&self) -> Result<CheckReport> {
+        (**self).check()
+    }
+}
+
+impl<T: ChunkSource + ?Sized> ChunkSource for Box<T> {
+    fn writer<'a>(&'a mut self) -> Result<Box<ChunkWrite + 'a>> {
+        (**self).writer()
+    }
+
+    fn forget(&mut self, backups: &[Oid]) -> Result<()> {
+        (**self).forget(backups)
+    }
+
+    fn add_seen(&mut self, peer: &Uuid, backups: &[Oid]) -> Result<()> {
+        (**self).add_seen(peer, backups)
+    }
+}
+
+// Ensure that a chunk being added to a pool was hashed with the pool's
+// algorithm, and isn't larger than the pool's `max_chunk_size`.
+fn check_chunk(alg: HashAlg, max_chunk: usize, chunk: &Chunk) -> Result<()> {
+    if chunk.oid().alg() != alg {
+        return Err(Error::WrongHash(format!("{} chunk added to {} pool",
+                                            chunk.oid().alg().name(),
+                                            alg.name())));
+    }
+    if chunk.data_len() as usize > max_chunk {
+        return Err(Error::ChunkTooLarge(chunk.data_len() as u64));
+    }
+    Ok(())
+}
+
+// Pools with a `max_chunk_size` smaller than `MAX_CHUNK_SIZE` record it in
+// this property.  Pools without it use `MAX_CHUNK_SIZE`.
+const MAX_CHUNK_KEY: &'static str = "max-chunk";
+
+// Parse the value of the `max-chunk` property, if the pool has one.
+fn parse_max_chunk(value: Option<&str>) -> Result<usize> {
+    let size = match value {
+        None => return Ok(MAX_CHUNK_SIZE),
+        Some(value) => value.parse::<usize>()?,
+    };
+    check_max_chunk(size)?;
+    Ok(size)
+}
+
+// Ensure that a pool's `max_chunk_size` is one it can store.
+fn check_max_chunk(size: usize) -> Result<()> {
+    if size == 0 || size > MAX_CHUNK_SIZE {
+        return Err(Error::PropertyError(format!("Invalid {}: {}", MAX_CHUNK_KEY, size)));
+    }
+    Ok(())
+}
+
+// Copy all of `source` to `dest`, hashing it as the data of a chunk of the
+// given kind.  Returns the Oid, and the length of the data.
+fn copy_hashed(alg: HashAlg,
+               kind: Kind,
+               source: &mut Read,
+               dest: &mut Write)
+               -> Result<(Oid, u64)> {
+    let mut hasher = OidHasher::new_alg(alg, kind);
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut total = 0u64;
+    loop {
+        let count = match source.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        hasher.update(&buf[..count]);
+        dest.write_all(&buf[..count])?;
+        total += count as u64;
+    }
+    Ok((hasher.finish(), total))
 }
 
-/// Attempt to open a pool for reading, auto-determining the type.
+// Check that a chunk read from a pool can be decompressed.
+fn checked(chunk: Chunk) -> Result<Chunk> {
+    chunk.try_data()?;
+    Ok(chunk)
+}
+
+/// Attempt to open a pool for reading, auto-determining the type.  A
+/// directory holding `data.db` is a `FilePool`, and one holding
+/// `metadata/props.txt` is an `AdumpPool`.  Anything else gives
+/// `Error::NotAPool`, describing what was found at `path`.
 pub fn open<P: AsRef<Path>>(path: P) -> Result<Box<ChunkSource>> {
-    let meta = fs::metadata(path.as_ref().join("data.db"))?;
+    let path = path.as_ref();
+    match pool_type(path)? {
+        PoolType::File => Ok(Box::new(FilePool::open(path)?)),
+        PoolType::Adump => Ok(Box::new(AdumpPool::open(path)?)),
+    }
+}
+
+/// Like `open`, but opens the pool read-only.  Nothing in the pool is
+/// written to, so it can be on read-only media, and writing to it returns
+/// `Error::ReadOnly`.
+pub fn open_readonly<P: AsRef<Path>>(path: P) -> Result<Box<ChunkSource>> {
+    let path = path.as_ref();
+    match pool_type(path)? {
+        PoolType::File => Ok(Box::new(FilePool::open_readonly(path)?)),
+        PoolType::Adump => Ok(Box::new(AdumpPool::open_readonly(path)?)),
+    }
+}
+
+// The kinds of pool that `open` recognises.
+enum PoolType {
+    File,
+    Adump,
+}
+
+// Determine the type of the pool at `path`, by the files it holds.
+fn pool_type(path: &Path) -> Result<PoolType> {
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            return Err(Error::NotAPool(format!("{}: not found", path.display())));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if !meta.is_dir() {
+        return Err(Error::NotAPool(format!("{}: not a directory", path.display())));
+    }
+
+    if path.join("data.db").is_file() {
+        Ok(PoolType::File)
+    } else if path.join("metadata").join("props.txt").is_file() {
+        Ok(PoolType::Adump)
+    } else {
+        Err(Error::NotAPool(format!("{}: directory has neither data.db nor \
+                                     metadata/props.txt",
+                                    path.display())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{self, File};
+    use std::path::Path;
+    use tempdir::TempDir;
+    use Error;
+
+    #[test]
+    fn test_open() {
+        let tmp = TempDir::new("pool").unwrap();
+
+        let path = tmp.path().join("file");
+        FilePool::create(&path).unwrap();
+        let uuid = FilePool::open(&path).unwrap().uuid().clone();
+        assert_eq!(open(&path).unwrap().uuid(), &uuid);
+        assert_eq!(open_readonly(&path).unwrap().uuid(), &uuid);
+
+        let path = tmp.path().join("adump");
+        AdumpPool::new_builder(&path).create().unwrap();
+        let uuid = AdumpPool::open(&path).unwrap().uuid().clone();
+        assert_eq!(open(&path).unwrap().uuid(), &uuid);
+        assert_eq!(open_readonly(&path).unwrap().uuid(), &uuid);
+
+        let path = tmp.path().join("empty");
+        fs::create_dir(&path).unwrap();
+        check_not_a_pool(&path);
+        check_not_a_pool(&tmp.path().join("missing"));
+        let path = tmp.path().join("plain");
+        File::create(&path).unwrap();
+        check_not_a_pool(&path);
+    }
+
+    // Pools of either type opened by `open_readonly` refuse to be written
+    // to.
+    #[test]
+    fn test_open_readonly() {
+        let tmp = TempDir::new("pool").unwrap();
+        let file = tmp.path().join("file");
+        FilePool::create(&file).unwrap();
+        let adump = tmp.path().join("adump");
+        AdumpPool::new_builder(&adump).create().unwrap();
 
-    if !meta.is_file() {
-        return Err(Error::NotAPool);
+        for path in &[file, adump] {
+            let mut pool = open_readonly(path).unwrap();
+            match pool.writer() {
+                Err(Error::ReadOnly) => (),
+                Err(e) => panic!("Unexpected error: {:?}", e),
+                Ok(_) => panic!("Wrote to read-only pool {:?}", path),
+            }
+            match pool.forget(&[]) {
+                Err(Error::ReadOnly) => (),
+                Err(e) => panic!("Unexpected error: {:?}", e),
+                Ok(_) => panic!("Forgot in read-only pool {:?}", path),
+            }
+        }
     }
 
-    match FilePool::open(path) {
-        Ok(p) => Ok(Box::new(p)),
-        Err(e) => Err(e),
+    fn check_not_a_pool(path: &Path) {
+        match open(path) {
+            Err(Error::NotAPool(_)) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Opened {:?} as a pool", path),
+        }
+        match open_readonly(path) {
+            Err(Error::NotAPool(_)) => (),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Opened {:?} as a pool", path),
+        }
     }
 }
@@ -1,22 +1,30 @@
 // RAM pools.
 
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Read};
+use std::sync::RwLock;
 use uuid::Uuid;
 
 use Chunk;
+use Codec;
+use CompressPolicy;
+use HashAlg;
 use Kind;
 use Oid;
 use Result;
 use Error;
-use pool::ChunkSource;
+use pool::{self, CheckItem, CheckReport, ChunkRead, ChunkSource, ChunkWrite};
 
 // TODO: Should Chunks implement clone, so we could just store them
 // directly?
 
 pub struct RamPool {
     uuid: Uuid,
-    chunks: RefCell<HashMap<Oid, Stashed>>,
+    alg: HashAlg,
+    policy: CompressPolicy,
+    chunks: RwLock<HashMap<Oid, Stashed>>,
+    forgotten: HashSet<Oid>,
+    seen: HashMap<Uuid, HashSet<Oid>>,
 }
 
 pub struct Stashed {
@@ -25,55 +33,188 @@ pub struct Stashed {
 }
 
 impl Stashed {
-    fn to_chunk(&self) -> Chunk {
-        Chunk::new_plain(self.kind, self.data.clone())
+    fn to_chunk(&self, alg: HashAlg) -> Result<Chunk> {
+        Chunk::try_new_plain_alg(alg, self.kind, self.data.clone())
     }
 }
 
 impl RamPool {
     pub fn new() -> RamPool {
+        RamPool::new_alg(HashAlg::Sha1)
+    }
+
+    /// Construct a RamPool whose chunks are hashed with `alg`.
+    pub fn new_alg(alg: HashAlg) -> RamPool {
         RamPool {
             uuid: Uuid::new_v4(),
-            chunks: RefCell::new(HashMap::new()),
+            alg: alg,
+            policy: CompressPolicy::new(),
+            chunks: RwLock::new(HashMap::new()),
+            forgotten: HashSet::new(),
+            seen: HashMap::new(),
         }
     }
 }
 
-impl ChunkSource for RamPool {
+impl ChunkRead for RamPool {
     fn find(&self, key: &Oid) -> Result<Chunk> {
-        self.chunks.borrow().get(key).map(|x| x.to_chunk()).ok_or(Error::MissingChunk)
+        self.chunks
+            .read()
+            .unwrap()
+            .get(key)
+            .ok_or(Error::MissingChunk)
+            .and_then(|x| x.to_chunk(self.alg))
     }
 
     fn contains_key(&self, key: &Oid) -> Result<bool> {
-        Ok(self.chunks.borrow().contains_key(key))
+        Ok(self.chunks.read().unwrap().contains_key(key))
     }
 
     fn uuid<'a>(&'a self) -> &'a Uuid {
         &self.uuid
     }
 
+    fn hash_alg(&self) -> HashAlg {
+        self.alg
+    }
+
+    fn codec(&self) -> Codec {
+        // Chunks are kept uncompressed in memory.
+        Codec::None
+    }
+
+    fn compress_policy(&self) -> &CompressPolicy {
+        &self.policy
+    }
+
     fn backups(&self) -> Result<Vec<Oid>> {
-        unimplemented!();
+        let back = Kind::new("back").unwrap();
+        Ok(self.chunks
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|&(oid, stashed)| stashed.kind == back && !self.forgotten.contains(oid))
+            .map(|(oid, _)| oid.clone())
+            .collect())
+    }
+
+    fn seen(&self, peer: &Uuid) -> Result<Vec<Oid>> {
+        Ok(self.seen.get(peer).map_or(vec![], |seen| seen.iter().cloned().collect()))
+    }
+
+    fn peers(&self) -> Result<Vec<Uuid>> {
+        Ok(self.seen.keys().cloned().collect())
+    }
+
+    fn list(&self) -> Result<Vec<(Oid, Kind)>> {
+        Ok(self.chunks
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(oid, stashed)| (oid.clone(), stashed.kind))
+            .collect())
+    }
+
+    fn read_large(&self, key: &Oid) -> Result<Box<Read + Send>> {
+        let chunks = self.chunks.read().unwrap();
+        let stashed = chunks.get(key).ok_or(Error::MissingChunk)?;
+        Ok(Box::new(Cursor::new(stashed.data.clone())))
+    }
+
+    fn check(&self) -> Result<CheckReport> {
+        let mut report = CheckReport::new();
+        for (oid, stashed) in self.chunks.read().unwrap().iter() {
+            let actual = Oid::from_data_alg(self.alg, stashed.kind, &stashed.data);
+            if &actual == oid {
+                report.good += 1;
+            } else {
+                report.corrupt.push(CheckItem::new(Some(oid.clone()),
+                                                   "memory",
+                                                   format!("data hashes to {}", actual.to_hex())));
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl ChunkSource for RamPool {
+    fn writer<'a>(&'a mut self) -> Result<Box<ChunkWrite + 'a>> {
+        Ok(Box::new(RamWriter {
+            pool: self,
+            added: vec![],
+            done: false,
+        }))
+    }
+
+    fn forget(&mut self, backups: &[Oid]) -> Result<()> {
+        self.forgotten.extend(backups.iter().cloned());
+        Ok(())
     }
 
-    fn begin_writing(&mut self) -> Result<()> {
+    fn add_seen(&mut self, peer: &Uuid, backups: &[Oid]) -> Result<()> {
+        self.seen.entry(peer.clone()).or_insert_with(HashSet::new).extend(backups.iter().cloned());
         Ok(())
     }
+}
+
+// Chunks are stored in the pool as they are added, and the ones that
+// weren't there before are removed again if the writer isn't committed.
+struct RamWriter<'a> {
+    pool: &'a mut RamPool,
+    added: Vec<Oid>,
+    done: bool,
+}
+
+impl<'a> RamWriter<'a> {
+    fn stash(&mut self, id: Oid, stashed: Stashed) {
+        let chunks = self.pool.chunks.get_mut().unwrap();
+        if !chunks.contains_key(&id) {
+            chunks.insert(id.clone(), stashed);
+            self.added.push(id);
+        }
+    }
+}
+
+impl<'a> ChunkWrite for RamWriter<'a> {
+    fn reader(&self) -> &ChunkRead {
+        &*self.pool
+    }
 
     fn add(&mut self, chunk: &Chunk) -> Result<()> {
-        let id = chunk.oid().clone();
-        let payload = Stashed {
-            kind: chunk.kind(),
-            data: chunk.data().to_vec(),
-        };
-        self.chunks
-            .borrow_mut()
-            .entry(id)
-            .or_insert(payload);
+        pool::check_chunk(self.pool.alg, self.pool.max_chunk_size(), chunk)?;
+        self.stash(chunk.oid().clone(),
+                   Stashed {
+                       kind: chunk.kind(),
+                       data: chunk.data().to_vec(),
+                   });
         Ok(())
     }
 
-    fn flush(&mut self) -> Result<()> {
+    fn add_large(&mut self, kind: Kind, source: &mut Read) -> Result<Oid> {
+        // Everything is in memory anyway, so just keep the data.
+        let mut data = vec![];
+        let (id, _) = pool::copy_hashed(self.pool.alg, kind, source, &mut data)?;
+        self.stash(id.clone(),
+                   Stashed {
+                       kind: kind,
+                       data: data,
+                   });
+        Ok(id)
+    }
+
+    fn commit(mut self: Box<Self>) -> Result<()> {
+        self.done = true;
         Ok(())
     }
 }
+
+impl<'a> Drop for RamWriter<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            let chunks = self.pool.chunks.get_mut().unwrap();
+            for id in &self.added {
+                chunks.remove(id);
+            }
+        }
+    }
+}
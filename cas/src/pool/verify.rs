@@ -0,0 +1,178 @@
+// Verifying pool wrapper.
+
+//! Pools trust their own bookkeeping when returning chunks, only checking
+//! what is cheap.  `VerifyingSource` wraps any other pool, and recomputes
+//! the hash of every chunk returned by `find`, so that a chunk that has
+//! been silently damaged is never handed back.  Data streamed by
+//! `read_large` is passed through unchecked.  Writing is done by the
+//! wrapped pool's own writer, and reads through it aren't checked either.
+
+use std::io::Read;
+use uuid::Uuid;
+
+use Chunk;
+use Codec;
+use CompressPolicy;
+use HashAlg;
+use Kind;
+use Oid;
+use Result;
+use Error;
+use pool::{CheckReport, ChunkRead, ChunkSource, ChunkWrite};
+
+pub struct VerifyingSource<S> {
+    inner: S,
+}
+
+impl<S: ChunkRead> VerifyingSource<S> {
+    pub fn new(inner: S) -> VerifyingSource<S> {
+        VerifyingSource { inner: inner }
+    }
+
+    /// Return the wrapped pool.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: ChunkRead> ChunkRead for VerifyingSource<S> {
+    fn find(&self, key: &Oid) -> Result<Chunk> {
+        let chunk = self.inner.find(key)?;
+        if chunk.oid() != key {
+            return Err(Error::HashMismatch(key.clone(), chunk.oid().clone()));
+        }
+        chunk.verify()?;
+        Ok(chunk)
+    }
+
+    fn contains_key(&self, key: &Oid) -> Result<bool> {
+        self.inner.contains_key(key)
+    }
+
+    fn uuid<'a>(&'a self) -> &'a Uuid {
+        self.inner.uuid()
+    }
+
+    fn hash_alg(&self) -> HashAlg {
+        self.inner.hash_alg()
+    }
+
+    fn codec(&self) -> Codec {
+        self.inner.codec()
+    }
+
+    fn compress_policy(&self) -> &CompressPolicy {
+        self.inner.compress_policy()
+    }
+
+    fn backups(&self) -> Result<Vec<Oid>> {
+        self.inner.backups()
+    }
+
+    fn seen(&self, peer: &Uuid) -> Result<Vec<Oid>> {
+        self.inner.seen(peer)
+    }
+
+    fn peers(&self) -> Result<Vec<Uuid>> {
+        self.inner.peers()
+    }
+
+    fn list(&self) -> Result<Vec<(Oid, Kind)>> {
+        self.inner.list()
+    }
+
+    fn max_chunk_size(&self) -> usize {
+        self.inner.max_chunk_size()
+    }
+
+    fn read_large(&self, key: &Oid) -> Result<Box<Read + Send>> {
+        self.inner.read_large(key)
+    }
+
+    fn check(&self) -> Result<CheckReport> {
+        self.inner.check()
+    }
+}
+
+impl<S: ChunkSource> ChunkSource for VerifyingSource<S> {
+    fn writer<'a>(&'a mut self) -> Result<Box<ChunkWrite + 'a>> {
+        self.inner.writer()
+    }
+
+    fn forget(&mut self, backups: &[Oid]) -> Result<()> {
+        self.inner.forget(backups)
+    }
+
+    fn add_seen(&mut self, peer: &Uuid, backups: &[Oid]) -> Result<()> {
+        self.inner.add_seen(peer, backups)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pool::{AdumpPool, ChunkRead, ChunkSource, RamPool};
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use tempdir::TempDir;
+    use testutil::{make_random_chunk, make_uncompressible_chunk};
+    use Error;
+    use Oid;
+
+    #[test]
+    fn ram() {
+        let mut pool = VerifyingSource::new(RamPool::new());
+        let chunk = make_random_chunk(1024, 1);
+        {
+            let mut wr = pool.writer().unwrap();
+            wr.add(&chunk).unwrap();
+            wr.commit().unwrap();
+        }
+
+        pool.find(chunk.oid()).unwrap();
+        assert!(pool.contains_key(chunk.oid()).unwrap());
+    }
+
+    #[test]
+    fn damaged() {
+        let tmp = TempDir::new("verify").unwrap();
+        let name = tmp.path().join("pool");
+        AdumpPool::new_builder(&name).create().unwrap();
+
+        // Uncompressible data is stored as is, so altering it doesn't stop
+        // the chunk from being decoded.
+        let chunk = make_uncompressible_chunk(4096, 1);
+        {
+            let mut pool = AdumpPool::open(&name).unwrap();
+            let mut wr = pool.writer().unwrap();
+            wr.add(&chunk).unwrap();
+            wr.commit().unwrap();
+        }
+        assert!(chunk.zdata().is_none());
+
+        {
+            let mut fd = OpenOptions::new()
+                .write(true)
+                .open(name.join("pool-data-0000.data"))
+                .unwrap();
+            fd.seek(SeekFrom::Start(48 + 100)).unwrap();
+            fd.write_all(b"damage").unwrap();
+        }
+
+        // Adump pools hash plain data as it is read, so the damage is
+        // already reported without the wrapper, and the wrapper passes the
+        // error on.
+        let pool = AdumpPool::open(&name).unwrap();
+        check_damaged(&pool, chunk.oid());
+        let pool = VerifyingSource::new(pool);
+        check_damaged(&pool, chunk.oid());
+    }
+
+    fn check_damaged(pool: &ChunkRead, oid: &Oid) {
+        match pool.find(oid) {
+            Err(Error::HashMismatch(ref expect, _)) => assert_eq!(expect, oid),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Damaged chunk was returned"),
+        }
+    }
+}
@@ -6,7 +6,8 @@ use std::ops::{Deref, DerefMut};
 
 /// Wrap a rusqlite::Connection an maintain a transaction within it.
 pub struct XactConnection {
-    conn: Connection,
+    // Boxed, so that the transaction's reference to it stays valid when the wrapper is moved.
+    conn: Box<Connection>,
     // The possibly open transaction.  We lie about the lifetime, since it can't be tied to
     // the connection, because that is not safe.  We make it safe by: 1. Having a Drop
     // implementation that drops the `xact` before the `conn`, and 2. Making sure that the only
@@ -19,7 +20,7 @@ pub struct XactConnection {
 impl XactConnection {
     pub fn new(conn: Connection) -> XactConnection {
         XactConnection {
-            conn: conn,
+            conn: Box::new(conn),
             xact: None,
         }
     }
@@ -46,12 +47,23 @@ impl XactConnection {
         Ok(())
     }
 
-    /// Commit the transaction.
+    /// Commit the transaction.  If the commit fails, the transaction is rolled back, so that
+    /// either way, no transaction is open afterwards.
     pub fn commit(&mut self) -> Result<()> {
         let xact = mem::replace(&mut self.xact, None);
         match xact {
             None => panic!("No transaction started"),
-            Some(xact) => xact.commit(),
+            Some(xact) => {
+                match xact.commit() {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        // Sqlite leaves the transaction open when a commit fails because the
+                        // database is busy, but rusqlite considers it finished.
+                        let _ = self.conn.execute_batch("ROLLBACK");
+                        Err(e)
+                    }
+                }
+            }
         }
     }
 
@@ -74,17 +86,29 @@ impl XactConnection {
     // }
 }
 
+// Safety: rusqlite's `Connection` is only `!Send` because it holds a raw
+// `sqlite3` handle.  Moving that handle to another thread is allowed by
+// sqlite whenever the library is built thread safe, and `FilePool` opens
+// its connections with `SQLITE_OPEN_FULL_MUTEX` (serialized mode), so
+// sqlite itself serializes any calls made on the handle.  The transaction
+// only borrows the boxed connection stored alongside it, and is always
+// dropped before it (see `Drop` below), so it never outlives the connection
+// and the two always move between threads together.  Callers still keep
+// the wrapper in a `Mutex`, since rusqlite's statement cache is not
+// synchronized.
+unsafe impl Send for XactConnection {}
+
 impl Deref for XactConnection {
     type Target = Connection;
 
     fn deref(&self) -> &Connection {
-        &self.conn
+        &*self.conn
     }
 }
 
 impl DerefMut for XactConnection {
     fn deref_mut(&mut self) -> &mut Connection {
-        &mut self.conn
+        &mut *self.conn
     }
 }
 
@@ -4,9 +4,14 @@
 
 use std::collections::BTreeSet;
 use std::fmt::Write;
+use std::io::{self, Cursor, Read};
 use std::num::Wrapping;
 use chunk::Chunk;
+use hash::{HashAlg, OidHasher};
 use kind::Kind;
+use oid::Oid;
+use pool::{ChunkRead, ChunkSource};
+use Error;
 
 // A short list of words to help generate reasonably compressible
 // data.
@@ -47,6 +52,12 @@ pub fn make_kinded_random_chunk(kind: Kind, size: u32, index: u32) -> Chunk {
     Chunk::new_plain(kind, make_random_string(size, index).into_bytes())
 }
 
+pub fn make_hashed_random_chunk(alg: HashAlg, size: u32, index: u32) -> Chunk {
+    Chunk::new_plain_alg(alg,
+                         Kind::new("blob").unwrap(),
+                         make_random_string(size, index).into_bytes())
+}
+
 pub fn make_uncompressible_chunk(size: u32, index: u32) -> Chunk {
     use rand::{Rng, SeedableRng, XorShiftRng};
     use std::iter::repeat;
@@ -173,3 +184,96 @@ mod test {
         };
     }
 }
+
+// Exercise the large chunk support of a pool.  Small data given to
+// `add_large` must still be readable as a regular chunk, and data larger
+// than the pool's `max_chunk_size` must only be readable as a stream.
+// Pools should be created with a small `max_chunk_size`, so that this
+// doesn't have to write so much data.
+pub fn check_large_chunks(pool: &mut ChunkSource) {
+    let alg = pool.hash_alg();
+    let kind = Kind::new("blob").unwrap();
+
+    let small = make_hashed_random_chunk(alg, 70000, 1);
+    let size = pool.max_chunk_size() as u64 + 1;
+    assert!(size > 70000);
+    let oid = {
+        let mut wr = pool.writer().unwrap();
+        match wr.add(&make_hashed_random_chunk(alg, size as u32, 2)) {
+            Err(Error::ChunkTooLarge(len)) => assert_eq!(len, size),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Chunk over the pool's limit added"),
+        }
+
+        let oid = wr.add_large(kind, &mut Cursor::new(small.data().to_vec())).unwrap();
+        assert_eq!(&oid, small.oid());
+        assert!(wr.reader().contains_key(&oid).unwrap());
+
+        let oid = wr.add_large(kind, &mut io::repeat(0x5a).take(size)).unwrap();
+        assert!(wr.reader().contains_key(&oid).unwrap());
+        wr.commit().unwrap();
+        oid
+    };
+    assert_eq!(&pool.find(small.oid()).unwrap().data()[..], &small.data()[..]);
+
+    let mut hasher = OidHasher::new_alg(alg, kind);
+    io::copy(&mut io::repeat(0x5a).take(size), &mut hasher).unwrap();
+    assert_eq!(oid, hasher.finish());
+    assert!(pool.contains_key(&oid).unwrap());
+
+    match pool.find(&oid) {
+        Err(Error::ChunkTooLarge(len)) => assert_eq!(len, size),
+        Err(e) => panic!("Unexpected error: {:?}", e),
+        Ok(_) => panic!("Large chunk returned by find"),
+    }
+
+    let mut count = 0u64;
+    let mut buf = vec![0u8; 65536];
+    let mut rd = pool.read_large(&oid).unwrap();
+    loop {
+        let n = rd.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        assert!(buf[..n].iter().all(|&b| b == 0x5a));
+        count += n as u64;
+    }
+    assert_eq!(count, size);
+
+    // Regular chunks can be streamed as well.
+    let mut data = vec![];
+    pool.read_large(small.oid()).unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(&data[..], &small.data()[..]);
+}
+
+// Exercise forgetting backups.  Forgotten backups are no longer listed,
+// but their chunks are still present.  Returns the Oids of the backups
+// that are still listed, so that the caller can check they are the same
+// after reopening the pool.
+pub fn check_forget(pool: &mut ChunkSource) -> BTreeSet<Oid> {
+    let back = Kind::new("back").unwrap();
+    let backs: Vec<Chunk> = (0..10).map(|i| make_kinded_random_chunk(back, 64, i)).collect();
+    {
+        let mut wr = pool.writer().unwrap();
+        for ch in &backs {
+            wr.add(ch).unwrap();
+        }
+        wr.commit().unwrap();
+    }
+
+    pool.forget(&[backs[1].oid().clone(), backs[4].oid().clone()]).unwrap();
+    // Forgetting is cumulative, and forgetting again does nothing.
+    pool.forget(&[backs[4].oid().clone(), backs[7].oid().clone()]).unwrap();
+
+    let listed: BTreeSet<Oid> = pool.backups().unwrap().into_iter().collect();
+    let expected: BTreeSet<Oid> = backs.iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 1 && i != 4 && i != 7)
+        .map(|(_, ch)| ch.oid().clone())
+        .collect();
+    assert_eq!(listed, expected);
+    for ch in &backs {
+        assert!(pool.contains_key(ch.oid()).unwrap());
+    }
+    listed
+}
@@ -5,9 +5,8 @@ use std::io::Cursor;
 use flate2::{FlateReadExt, Compression};
 
 // The old flate library provided some useful routines.  These are more
-// taylored to the use by libpool.
-// TODO: These should return Result rather than Option to convey a more
-// meaningful error.
+// taylored to the use by libpool.  Callers know more about the data, and
+// are expected to turn a None into a meaningful error.
 
 /// Attempt to compress a single block of data.  Returns the data if it is
 /// compressible, otherwise, returns None.
@@ -23,11 +22,15 @@ pub fn deflate(buf: &[u8]) -> Option<Vec<u8>> {
 }
 
 /// Decompress the given buffer.  Returns None if there was some kind of error
-/// doing the decompression.
+/// doing the decompression, or if the data doesn't inflate to exactly
+/// `size_hint` bytes.  No more than one byte past `size_hint` is inflated,
+/// so damaged data can't use up memory.
 pub fn inflate(buf: &[u8], size_hint: usize) -> Option<Vec<u8>> {
     let src = Cursor::new(buf);
     let mut res = Vec::with_capacity(size_hint);
-    src.zlib_decode().read_to_end(&mut res).unwrap();
+    if src.zlib_decode().take(size_hint as u64 + 1).read_to_end(&mut res).is_err() {
+        return None;
+    }
     if res.len() == size_hint {
         Some(res)
     } else {
@@ -64,4 +67,24 @@ mod tests {
             check(size);
         }
     }
+
+    #[test]
+    fn corrupt() {
+        let text = make_random_string(4096, 1).into_bytes();
+        let mut ztext = deflate(&text[..]).unwrap();
+
+        // Wrong size.
+        assert!(inflate(&ztext[..], text.len() + 1).is_none());
+        assert!(inflate(&ztext[..], text.len() - 1).is_none());
+        assert!(inflate(&ztext[..], 10).is_none());
+
+        // Damaged data.
+        let mid = ztext.len() / 2;
+        ztext[mid] ^= 0x55;
+        assert!(inflate(&ztext[..], text.len()).is_none());
+
+        // Truncated data, and garbage.
+        assert!(inflate(&ztext[..mid], text.len()).is_none());
+        assert!(inflate(b"not zlib data", text.len()).is_none());
+    }
 }
@@ -1,36 +1,38 @@
-// Show a tree.
+// Show a tree, forget backups, copy backups to another pool, or with "gc",
+// remove everything no backup refers to.
 
 extern crate cas;
-extern crate byteorder;
+extern crate filer;
 
-use byteorder::{BigEndian, ReadBytesExt};
 use cas::{Kind, Oid};
-use cas::Result;
 use cas::pdump::HexDump;
-use cas::pool::{AdumpPool, ChunkSource};
-use std::collections::BTreeMap;
+use cas::pool::{self, AdumpPool, ChunkRead};
+use filer::props::Decode;
+use filer::reach;
+use filer::retain::{self, Retention};
+use filer::sync;
 use std::env;
-use std::io::Read;
 
 fn main() {
-    let mut argsi = env::args();
-
-    match argsi.next() {
-        None => panic!("No program name given"),
-        Some(_) => (),
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|x| &x[..]).collect();
+
+    if args.len() >= 2 {
+        match args[0] {
+            "gc" if args.len() == 2 => return collect(args[1]),
+            "forget" => return forget(args[1], &args[2..]),
+            "retain" if args.len() == 5 => return apply_retention(args[1], &args[2..]),
+            "sync" if args.len() == 3 => return copy(args[1], args[2]),
+            _ => (),
+        }
     }
 
-    let path = match argsi.next() {
-        Some(path) => path,
-        None => panic!("Expecting a single argument, of the pool name"),
-    };
-
-    match argsi.next() {
-        Some(_) => panic!("Unexpected extra argument"),
-        None => (),
+    if args.len() != 1 {
+        panic!("Usage: filer <pool> | filer gc <pool> | filer forget <pool> <backup>... | \
+                filer retain <pool> <daily> <weekly> <monthly> | filer sync <src> <dest>");
     }
 
-    let pool = AdumpPool::open(&path).unwrap();
+    let pool = pool::open_readonly(args[0]).unwrap();
 
     let walk = Walk { source: &pool };
 
@@ -40,8 +42,45 @@ fn main() {
     }
 }
 
+fn collect(path: &str) {
+    let mut pool = AdumpPool::open(path).unwrap();
+    let live = reach::live_chunks(&pool).unwrap();
+    println!("{} live chunks", live.len());
+    let report = pool.compact(&live).unwrap();
+    println!("{:#?}", report);
+}
+
+fn forget(path: &str, hexes: &[&str]) {
+    let mut pool = pool::open(path).unwrap();
+    let alg = pool.hash_alg();
+    let oids: Vec<Oid> = hexes.iter()
+        .map(|hex| Oid::from_hex_alg(alg, hex).expect("Invalid backup hash"))
+        .collect();
+    pool.forget(&oids).unwrap();
+}
+
+fn apply_retention(path: &str, counts: &[&str]) {
+    let counts: Vec<usize> = counts.iter().map(|x| x.parse().expect("Invalid count")).collect();
+    let rule = Retention {
+        daily: counts[0],
+        weekly: counts[1],
+        monthly: counts[2],
+    };
+    let mut pool = pool::open(path).unwrap();
+    for oid in retain::apply(&mut *pool, &rule).unwrap() {
+        println!("forgot: {}", oid.to_hex());
+    }
+}
+
+fn copy(src: &str, dest: &str) {
+    let src = pool::open_readonly(src).unwrap();
+    let mut dest = pool::open(dest).unwrap();
+    let report = sync::sync(&src, &mut *dest).unwrap();
+    println!("{:#?}", report);
+}
+
 struct Walk<'a> {
-    source: &'a ChunkSource,
+    source: &'a ChunkRead,
 }
 
 impl<'a> Walk<'a> {
@@ -57,7 +96,7 @@ impl<'a> Walk<'a> {
 
         // Get the backup hash.
         let hash = props.data.get("hash").unwrap();
-        let oid = Oid::from_hex(hash).unwrap();
+        let oid = Oid::from_hex_alg(self.source.hash_alg(), hash).unwrap();
         println!("root: {:?}", oid);
         self.show_node(&oid);
     }
@@ -71,11 +110,11 @@ impl<'a> Walk<'a> {
 
         if props.kind == "DIR" {
             let child_oid = props.data.get("children").unwrap();
-            let child_oid = Oid::from_hex(child_oid).unwrap();
+            let child_oid = Oid::from_hex_alg(self.source.hash_alg(), child_oid).unwrap();
             self.show_dir(&child_oid);
         } else if props.kind == "REG" {
             let data_oid = props.data.get("data").unwrap();
-            let data_oid = Oid::from_hex(data_oid).unwrap();
+            let data_oid = Oid::from_hex_alg(self.source.hash_alg(), data_oid).unwrap();
             self.show_data(&data_oid);
         }
     }
@@ -83,7 +122,7 @@ impl<'a> Walk<'a> {
     fn show_dir(&self, id: &Oid) {
         let ch = self.source.find(id).unwrap();
         // (&ch.data()[..]).dump();
-        let entries = (&ch.data()[..]).read_dir().unwrap();
+        let entries = (&ch.data()[..]).read_dir(self.source.hash_alg()).unwrap();
         println!("dir: {:#?}", entries);
 
         for child in &entries {
@@ -97,69 +136,3 @@ impl<'a> Walk<'a> {
         println!("data: {:#?}", ch.kind())
     }
 }
-
-trait Decode: Read {
-    fn read_string1(&mut self) -> Result<String> {
-        let len = try!(self.read_u8());
-        let mut buf = vec![0u8; len as usize];
-        try!(self.read_exact(&mut buf));
-        Ok(try!(String::from_utf8(buf)))
-    }
-
-    fn read_string2(&mut self) -> Result<String> {
-        let len = try!(self.read_u16::<BigEndian>());
-        let mut buf = vec![0u8; len as usize];
-        try!(self.read_exact(&mut buf));
-        Ok(try!(String::from_utf8(buf)))
-    }
-
-    fn read_props(&mut self) -> Result<Props> {
-        let kind = try!(self.read_string1());
-        let mut dict = BTreeMap::new();
-        loop {
-            let key = match self.read_string1() {
-                Ok(key) => key,
-                Err(ref err) if err.is_unexpected_eof() => break,
-                Err(e) => return Err(e),
-            };
-            let value = try!(self.read_string2());
-            dict.insert(key, value);
-        }
-        Ok(Props {
-            kind: kind,
-            data: dict,
-        })
-    }
-
-    fn read_dir(&mut self) -> Result<Vec<DirEntry>> {
-        let mut result = vec![];
-        loop {
-            let name = match self.read_string2() {
-                Ok(name) => name,
-                Err(ref err) if err.is_unexpected_eof() => break,
-                Err(e) => return Err(e),
-            };
-            let mut buf = [0u8; 20];
-            try!(self.read_exact(&mut buf));
-            result.push(DirEntry {
-                name: name,
-                oid: Oid::from_raw(&buf),
-            });
-        }
-        Ok(result)
-    }
-}
-
-impl<T: Read> Decode for T {}
-
-#[derive(Debug)]
-struct Props {
-    kind: String,
-    data: BTreeMap<String, String>,
-}
-
-#[derive(Debug)]
-struct DirEntry {
-    name: String,
-    oid: Oid,
-}
@@ -4,70 +4,128 @@
 
 use Result;
 use indirect;
-use std::cell::RefCell;
-use std::io;
-use std::io::ErrorKind;
-use std::iter;
+use std::cmp;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::Mutex;
 use cas;
-use cas::pool::ChunkSource;
-use cas::{Chunk, Kind, Oid};
+use cas::pool::ChunkWrite;
+use cas::{ChunkBuilder, Kind, Oid, Pipeline};
 
-pub struct DataWrite<'a> {
-    sink: &'a RefCell<ChunkSource>,
+/// Writes file data to a pool, through a writer shared with anything else
+/// writing to it.  Nothing written is permanent until the writer is
+/// committed.
+pub struct DataWrite<'a, 'p: 'a> {
+    sink: &'a Mutex<Box<ChunkWrite + 'p>>,
     limit: usize,
+
+    // Scratch space for reading from the source.  This is allocated once,
+    // and reused for every chunk.
+    buf: Vec<u8>,
+
+    // The number of threads used to hash and compress the data.  Zero
+    // does all of the work on the calling thread.
+    workers: usize,
 }
 
-impl<'a> DataWrite<'a> {
-    pub fn new<'b>(sink: &'b RefCell<ChunkSource>) -> DataWrite<'b> {
+impl<'a, 'p> DataWrite<'a, 'p> {
+    pub fn new(sink: &'a Mutex<Box<ChunkWrite + 'p>>) -> DataWrite<'a, 'p> {
         DataWrite::new_limit(sink, 256 * 1024)
     }
 
-    pub fn new_limit<'b>(sink: &'b RefCell<ChunkSource>, limit: usize) -> DataWrite<'b> {
+    pub fn new_limit(sink: &'a Mutex<Box<ChunkWrite + 'p>>, limit: usize) -> DataWrite<'a, 'p> {
         DataWrite {
             sink: sink,
             limit: limit,
+            buf: vec![0u8; cmp::min(limit, 64 * 1024)],
+            workers: 0,
         }
     }
 
+    /// Hash and compress the data with `workers` threads.  The chunks are
+    /// still added to the pool in order, from the calling thread, so the
+    /// result is the same as with no workers.
+    pub fn set_workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
     // Attempt to write all of the contents of `source` to the pool,
     // returning the hash of the data or an error.
     pub fn write<'b>(&mut self, source: &'b mut io::Read) -> cas::Result<Oid> {
+        if self.workers > 0 {
+            return self.write_parallel(source);
+        }
+
         let mut ind = indirect::Write::new(self.sink, self.limit, "IND".to_string());
+        let (alg, codec, policy) = {
+            let sink = self.sink.lock().unwrap();
+            let pool = sink.reader();
+            (pool.hash_alg(), pool.codec(), pool.compress_policy().clone())
+        };
         loop {
-            let buf = try!(self.fill(source));
-            if buf.len() == 0 {
+            let mut builder = ChunkBuilder::with_capacity(alg,
+                                                          Kind::new("blob").unwrap(),
+                                                          self.limit)
+                .set_codec(codec)
+                .set_policy(&policy);
+            try!(self.fill(source, &mut builder));
+            if builder.len() == 0 {
                 break;
             }
 
-            let ch = Chunk::new_plain(Kind::new("blob").unwrap(), buf);
-            try!(self.sink.borrow_mut().add(&ch));
-            try!(ind.add(ch.oid()));
-            // println!("write {} bytes", ch.data_len());
+            let oid = try!(builder.add_to(&mut **self.sink.lock().unwrap()));
+            try!(ind.add(&oid));
         }
 
         ind.finish()
     }
 
-    // Return a buffer filled with data.  Note that this will potentially
-    // discard data on error.
-    fn fill(&mut self, source: &mut io::Read) -> Result<Vec<u8>> {
-        let mut buf: Vec<u8> = iter::repeat(0).take(self.limit).collect();
-        let mut len = 0;
+    // Like `write`, but with the chunks built by a pipeline.
+    fn write_parallel(&mut self, source: &mut io::Read) -> cas::Result<Oid> {
+        let mut ind = indirect::Write::new(self.sink, self.limit, "IND".to_string());
+        let mut pipe = Pipeline::for_pool(self.workers, self.sink.lock().unwrap().reader());
+        let kind = Kind::new("blob").unwrap();
+        loop {
+            let mut data = Vec::with_capacity(self.limit);
+            try!((&mut *source).take(self.limit as u64).read_to_end(&mut data));
+            let eof = data.is_empty();
+            if !eof {
+                pipe.push(kind, data);
+            }
+
+            // Store chunks as the pipeline fills, and all of them at the
+            // end.
+            while pipe.is_full() || (eof && pipe.pending() > 0) {
+                let chunk = try!(pipe.pop().unwrap());
+                try!(self.sink.lock().unwrap().add(&chunk));
+                try!(ind.add(chunk.oid()));
+            }
+
+            if eof {
+                break;
+            }
+        }
+
+        ind.finish()
+    }
 
+    // Copy up to `limit` bytes from `source` into the builder.  Note that
+    // this will potentially discard data on error.
+    fn fill(&mut self, source: &mut io::Read, dest: &mut ChunkBuilder) -> Result<()> {
         loop {
-            if len == buf.len() {
+            let want = cmp::min(self.buf.len(), self.limit - dest.len());
+            if want == 0 {
                 break;
             }
 
-            match source.read(&mut buf[len..]) {
+            match source.read(&mut self.buf[..want]) {
                 Ok(0) => break,
-                Ok(n) => len += n,
+                Ok(n) => try!(dest.write_all(&self.buf[..n])),
                 Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
                 Err(e) => return Err(From::from(e)),
             }
         }
 
-        buf.truncate(len);
-        Ok(buf)
+        Ok(())
     }
 }
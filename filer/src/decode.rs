@@ -3,7 +3,7 @@
 #![allow(dead_code)]
 
 use cas;
-use cas::Chunk;
+use cas::{Chunk, Error, Kind};
 use cas::Oid;
 
 pub enum Node {
@@ -15,21 +15,46 @@ pub fn decode(chunk: Chunk) -> cas::Result<Node> {
     let kind = chunk.kind().to_string();
 
     if &kind[0..3] == "IND" {
-        let data = chunk.into_bytes();
-        let size = data.len() / Oid::size();
+        // The children are hashed the same way as the indirect block.
+        let alg = chunk.oid().alg();
+        let data = try!(chunk.try_into_bytes());
+        let size = data.len() / alg.size();
         let mut children = Vec::with_capacity(size);
         for i in 0..size {
-            let a = i * Oid::size();
-            let b = a + Oid::size();
-            children.push(Oid::from_raw(&data[a..b]));
+            let a = i * alg.size();
+            let b = a + alg.size();
+            children.push(Oid::from_raw_alg(alg, &data[a..b]));
         }
         return Ok(Node::Indirect {
             level: (kind.as_bytes()[3] as usize) - ('0' as usize),
             children: children,
         });
     } else if kind == "blob" {
-        return Ok(Node::Blob(chunk.into_bytes()));
+        return Ok(Node::Blob(try!(chunk.try_into_bytes())));
     } else {
         panic!("Unknown chunk type");
     }
 }
+
+/// The level of an indirect chunk, if the chunk is one.  Indirect chunks
+/// have kinds of a three character prefix, followed by the level, where
+/// the children of level 0 are the data itself.
+pub fn indirect_level(kind: Kind) -> Option<u8> {
+    let bytes = kind.bytes();
+    match bytes[3] {
+        b'0'...b'9' => Some(bytes[3] - b'0'),
+        _ => None,
+    }
+}
+
+/// The Oids held by an indirect chunk, which use the same hash as it does.
+pub fn children(chunk: &Chunk) -> cas::Result<Vec<Oid>> {
+    let alg = chunk.oid().alg();
+    let data = try!(chunk.try_data());
+    if data.len() % alg.size() != 0 {
+        return Err(Error::CorruptChunk(format!("{}: indirect chunk of {} bytes",
+                                               chunk.oid().to_hex(),
+                                               data.len())));
+    }
+    Ok(data.chunks(alg.size()).map(|raw| Oid::from_raw_alg(alg, raw)).collect())
+}
@@ -3,24 +3,29 @@
 #![allow(dead_code)]
 
 use Result;
-use cas::pool::ChunkSource;
+use cas::pool::ChunkWrite;
 use cas::Chunk;
+use cas::HashAlg;
 use cas::Kind;
 use cas::Oid;
-use std::cell::RefCell;
+use std::sync::Mutex;
 
 // Items that are larger than a single chunk are written in multiple chunks
 // and then use indirect chunks to store all of these.  The indirect chunks
 // work somewhat like a Merkle tree (which because of the hash-addressed
 // storage can also be used to find the data).
 
-pub struct Write<'a> {
+pub struct Write<'a, 'p: 'a> {
     // Maximum size (in bytes) to write to each indirection block.
     limit: usize,
 
     // Maximum number of Oids that fit within `limit` bytes.
     oid_limit: usize,
 
+    // The hash algorithm of the sink, which determines the size of each
+    // Oid.
+    alg: HashAlg,
+
     // Three character string prefix for the indirect block type.  The
     // lowest-level of indirection chunks will be prefix + "0", the next up
     // "1", and so on.
@@ -33,18 +38,24 @@ pub struct Write<'a> {
     level: usize,
 
     // The sink for the data.
-    sink: &'a RefCell<ChunkSource>,
+    sink: &'a Mutex<Box<ChunkWrite + 'p>>,
 }
 
-impl<'a> Write<'a> {
-    pub fn new<'b>(sink: &'b RefCell<ChunkSource>, limit: usize, prefix: String) -> Write<'b> {
+impl<'a, 'p> Write<'a, 'p> {
+    pub fn new(sink: &'a Mutex<Box<ChunkWrite + 'p>>,
+               limit: usize,
+               prefix: String)
+               -> Write<'a, 'p> {
         if prefix.as_bytes().len() != 3 {
             panic!("prefix must be 3 bytes");
         }
 
+        let alg = sink.lock().unwrap().reader().hash_alg();
+
         Write {
             limit: limit,
-            oid_limit: limit / Oid::size(),
+            oid_limit: limit / alg.size(),
+            alg: alg,
             prefix: prefix,
             buffers: Vec::new(),
             level: 0,
@@ -62,7 +73,7 @@ impl<'a> Write<'a> {
         if self.buffers.is_empty() {
             // If we're out of nodes, create and push one.
             self.push_buffer();
-        } else if self.buf().len() + Oid::size() > self.limit {
+        } else if self.buf().len() + self.alg.size() > self.limit {
             trace!("Past limit");
             let top = try!(self.collapse());
             try!(self.add_level(&top, level + 1));
@@ -70,7 +81,7 @@ impl<'a> Write<'a> {
             self.push_buffer();
         }
 
-        self.buf_mut().extend(oid.0.iter().map(|&x| x));
+        self.buf_mut().extend(oid.as_bytes().iter().map(|&x| x));
         /*
         unsafe {
             use std::ptr;
@@ -78,7 +89,7 @@ impl<'a> Write<'a> {
 
             let mut b = self.buf_mut();
             let mut blen = b.len();
-            b.set_len(blen + Oid::size());
+            b.set_len(blen + self.alg.size());
             let dest = mem::transmute(&mut b[blen]);
             ptr::copy(oid as *const Oid, dest, 1);
         }
@@ -88,7 +99,7 @@ impl<'a> Write<'a> {
 
     // Add a new empty buffer.
     fn push_buffer(&mut self) {
-        self.buffers.push(Vec::with_capacity(Oid::size() * self.oid_limit));
+        self.buffers.push(Vec::with_capacity(self.alg.size() * self.oid_limit));
         let len = self.buffers.len();
         if len > self.level {
             self.level = len;
@@ -101,16 +112,16 @@ impl<'a> Write<'a> {
     fn collapse(&mut self) -> Result<Oid> {
         let buf = self.buffers.pop().unwrap();
         assert!(buf.len() > 0);
-        if buf.len() == Oid::size() {
+        if buf.len() == self.alg.size() {
             trace!("collapse: single");
-            Ok(Oid::from_raw(&buf))
+            Ok(Oid::from_raw_alg(self.alg, &buf))
         } else {
             let blevel = self.buffers.len();
             trace!("Collapse: {}, {}, {}", self.prefix, blevel, self.level);
             let kind = Kind::new(&format!("{}{}", self.prefix, self.level - blevel - 1)).unwrap();
             // let kind = Kind::new(&format!("{}0", self.prefix)).unwrap();
-            let ch = Chunk::new_plain(kind, buf);
-            try!(self.sink.borrow_mut().add(&ch));
+            let ch = Chunk::new_plain_alg(self.alg, kind, buf);
+            try!(self.sink.lock().unwrap().add(&ch));
 
             // TODO: Implement a move out of the oid?
             trace!("collapsed: {}", ch.oid().to_hex());
@@ -125,8 +136,8 @@ impl<'a> Write<'a> {
                self.level);
         if self.buffers.is_empty() {
             // TODO: Make this more general.
-            let ch = Chunk::new_plain(Kind::new("NULL").unwrap(), vec![]);
-            try!(self.sink.borrow_mut().add(&ch));
+            let ch = Chunk::new_plain_alg(self.alg, Kind::new("NULL").unwrap(), vec![]);
+            try!(self.sink.lock().unwrap().add(&ch));
             Ok(ch.oid().clone())
         } else {
             loop {
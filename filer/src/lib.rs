@@ -1,6 +1,7 @@
 // Filer library.
 
 extern crate cas;
+extern crate byteorder;
 
 #[cfg(test)]
 extern crate uuid;
@@ -21,3 +22,7 @@ pub type Result<T> = cas::Result<T>;
 mod indirect;
 pub mod data;
 pub mod decode;
+pub mod props;
+pub mod reach;
+pub mod retain;
+pub mod sync;
@@ -0,0 +1,91 @@
+// Decoding of the properties and directories stored in backups.
+
+use byteorder::{BigEndian, ReadBytesExt};
+use cas::{Error, HashAlg, Oid};
+use Result;
+use std::collections::BTreeMap;
+use std::io::Read;
+
+/// Decoding of the encodings used by backup chunks.  Strings are prefixed
+/// by their length, in one (`read_string1`) or two (`read_string2`) bytes.
+/// A property list is a kind, followed by key/value pairs up to the end of
+/// the data.  A directory is a sequence of names, each followed by the raw
+/// Oid of the node for that name.
+pub trait Decode: Read {
+    fn read_string1(&mut self) -> Result<String> {
+        let len = try!(self.read_u8());
+        let mut buf = vec![0u8; len as usize];
+        try!(self.read_exact(&mut buf));
+        Ok(try!(String::from_utf8(buf)))
+    }
+
+    fn read_string2(&mut self) -> Result<String> {
+        let len = try!(self.read_u16::<BigEndian>());
+        let mut buf = vec![0u8; len as usize];
+        try!(self.read_exact(&mut buf));
+        Ok(try!(String::from_utf8(buf)))
+    }
+
+    fn read_props(&mut self) -> Result<Props> {
+        let kind = try!(self.read_string1());
+        let mut dict = BTreeMap::new();
+        loop {
+            let key = match self.read_string1() {
+                Ok(key) => key,
+                Err(ref err) if err.is_unexpected_eof() => break,
+                Err(e) => return Err(e),
+            };
+            let value = try!(self.read_string2());
+            dict.insert(key, value);
+        }
+        Ok(Props {
+            kind: kind,
+            data: dict,
+        })
+    }
+
+    fn read_dir(&mut self, alg: HashAlg) -> Result<Vec<DirEntry>> {
+        let mut result = vec![];
+        loop {
+            let name = match self.read_string2() {
+                Ok(name) => name,
+                Err(ref err) if err.is_unexpected_eof() => break,
+                Err(e) => return Err(e),
+            };
+            let mut buf = vec![0u8; alg.size()];
+            try!(self.read_exact(&mut buf));
+            result.push(DirEntry {
+                name: name,
+                oid: Oid::from_raw_alg(alg, &buf),
+            });
+        }
+        Ok(result)
+    }
+}
+
+impl<T: Read> Decode for T {}
+
+#[derive(Debug)]
+pub struct Props {
+    pub kind: String,
+    pub data: BTreeMap<String, String>,
+}
+
+impl Props {
+    /// Look up a property holding the Oid of another chunk, hashed with
+    /// `alg`.
+    pub fn oid(&self, key: &str, alg: HashAlg) -> Result<Oid> {
+        let text = try!(self.data.get(key).ok_or_else(|| {
+            Error::PropertyError(format!("{} node has no {:?} property", self.kind, key))
+        }));
+        Oid::from_hex_alg(alg, text).ok_or_else(|| {
+            Error::PropertyError(format!("{} node has invalid {:?}: {:?}", self.kind, key, text))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub oid: Oid,
+}
@@ -0,0 +1,122 @@
+// Reachability of the chunks in a pool.
+
+use cas::{HashAlg, Oid};
+use cas::pool::ChunkRead;
+use decode::{children, indirect_level};
+use props::{Decode, Props};
+use Result;
+use std::collections::HashSet;
+
+/// Find every chunk that can be reached from the backups in `pool`.  Each
+/// backup's properties name its root node.  `DIR` nodes name a directory
+/// listing, whose entries are nodes themselves, and `REG` nodes name the
+/// file's data.  Listings and data too large for a single chunk are stored
+/// as trees of indirect chunks.  The leaves of file data are never read,
+/// only marked.
+///
+/// Anything that can't be read or decoded fails the whole walk, as the
+/// chunks that should have been reached through it would otherwise be
+/// missing from the result.
+pub fn live_chunks(pool: &ChunkRead) -> Result<HashSet<Oid>> {
+    let mut walk = Walk {
+        pool: pool,
+        alg: pool.hash_alg(),
+        live: HashSet::new(),
+        walked_dirs: HashSet::new(),
+    };
+    for oid in try!(pool.backups()) {
+        try!(walk.backup(&oid));
+    }
+    Ok(walk.live)
+}
+
+struct Walk<'a> {
+    pool: &'a ChunkRead,
+    alg: HashAlg,
+    live: HashSet<Oid>,
+
+    // The listings whose entries have been walked.  This can't be told
+    // from `live`, as file data is marked there without being read, and a
+    // file can hold the same bytes as a listing.
+    walked_dirs: HashSet<Oid>,
+}
+
+impl<'a> Walk<'a> {
+    // Mark a chunk as live, returning true if it hadn't been already.
+    fn mark(&mut self, oid: &Oid) -> bool {
+        self.live.insert(oid.clone())
+    }
+
+    fn backup(&mut self, oid: &Oid) -> Result<()> {
+        if !self.mark(oid) {
+            return Ok(());
+        }
+        let props = try!(self.read_props(oid));
+        let root = try!(props.oid("hash", self.alg));
+        self.node(&root)
+    }
+
+    fn node(&mut self, oid: &Oid) -> Result<()> {
+        if !self.mark(oid) {
+            return Ok(());
+        }
+        let props = try!(self.read_props(oid));
+        if props.kind == "DIR" {
+            let children = try!(props.oid("children", self.alg));
+            try!(self.dir(&children));
+        } else if props.kind == "REG" {
+            let data = try!(props.oid("data", self.alg));
+            try!(self.data(&data));
+        }
+        Ok(())
+    }
+
+    fn dir(&mut self, oid: &Oid) -> Result<()> {
+        if !self.walked_dirs.insert(oid.clone()) {
+            return Ok(());
+        }
+        let mut listing = vec![];
+        try!(self.read_data(oid, &mut listing));
+        for ent in try!((&listing[..]).read_dir(self.alg)) {
+            try!(self.node(&ent.oid));
+        }
+        Ok(())
+    }
+
+    // Mark file data, reading only the indirect chunks.
+    fn data(&mut self, oid: &Oid) -> Result<()> {
+        if !self.mark(oid) {
+            return Ok(());
+        }
+        let chunk = try!(self.pool.find(oid));
+        if let Some(level) = indirect_level(chunk.kind()) {
+            for child in try!(children(&chunk)) {
+                if level == 0 {
+                    self.mark(&child);
+                } else {
+                    try!(self.data(&child));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Mark data, and append all of it to `buf`.
+    fn read_data(&mut self, oid: &Oid, buf: &mut Vec<u8>) -> Result<()> {
+        self.mark(oid);
+        let chunk = try!(self.pool.find(oid));
+        if indirect_level(chunk.kind()).is_some() {
+            for child in try!(children(&chunk)) {
+                try!(self.read_data(&child, buf));
+            }
+        } else {
+            buf.extend_from_slice(&try!(chunk.try_into_bytes()));
+        }
+        Ok(())
+    }
+
+    fn read_props(&self, oid: &Oid) -> Result<Props> {
+        let data = try!(try!(self.pool.find(oid)).try_into_bytes());
+        (&data[..]).read_props()
+    }
+}
@@ -0,0 +1,109 @@
+// Retention of backups.
+
+use cas::Oid;
+use cas::pool::{ChunkRead, ChunkSource};
+use props::Decode;
+use Result;
+use std::collections::HashSet;
+
+/// How many backups to keep, by their dates.  For each kind of period, the
+/// newest backup in each of that many of the most recent periods that have
+/// a backup is kept.  A backup kept for any of the periods is kept.  Days,
+/// weeks (starting on Monday) and months are in UTC.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+impl Retention {
+    /// Given each backup along with its date, in seconds since the Unix
+    /// epoch, return the backups this rule doesn't keep.  Backups with no
+    /// date are always kept.
+    pub fn expired(&self, backups: &[(Oid, Option<i64>)]) -> Vec<Oid> {
+        let mut dated: Vec<(&Oid, i64)> = backups.iter()
+            .filter_map(|&(ref oid, date)| date.map(|date| (oid, date)))
+            .collect();
+        dated.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let rules = [(self.daily, day as fn(i64) -> i64),
+                     (self.weekly, week as fn(i64) -> i64),
+                     (self.monthly, month as fn(i64) -> i64)];
+        let mut keep = HashSet::new();
+        for &(count, period) in &rules {
+            let mut last = None;
+            let mut kept = 0;
+            for &(oid, date) in &dated {
+                if kept >= count {
+                    break;
+                }
+                let this = period(date);
+                if last != Some(this) {
+                    keep.insert(oid);
+                    kept += 1;
+                    last = Some(this);
+                }
+            }
+        }
+
+        dated.iter()
+            .filter(|&&(oid, _)| !keep.contains(&oid))
+            .map(|&(oid, _)| oid.clone())
+            .collect()
+    }
+}
+
+/// The date of a backup, from the `_date` property of its props, in
+/// seconds since the Unix epoch.  Any fraction of a second is ignored.
+pub fn backup_date<P: ChunkRead + ?Sized>(pool: &P, oid: &Oid) -> Result<Option<i64>> {
+    let data = try!(try!(pool.find(oid)).try_into_bytes());
+    let props = try!((&data[..]).read_props());
+    Ok(props.data.get("_date").and_then(|text| {
+        let whole = text.split('.').next().unwrap_or("");
+        whole.parse::<i64>().ok()
+    }))
+}
+
+/// Forget every backup in `pool` that `rule` doesn't keep, returning the
+/// ones forgotten.  The data is only removed once the pool is compacted.
+pub fn apply(pool: &mut ChunkSource, rule: &Retention) -> Result<Vec<Oid>> {
+    let mut backups = vec![];
+    for oid in try!(pool.backups()) {
+        let date = try!(backup_date(&*pool, &oid));
+        backups.push((oid, date));
+    }
+    let expired = rule.expired(&backups);
+    try!(pool.forget(&expired));
+    Ok(expired)
+}
+
+fn div_floor(a: i64, b: i64) -> i64 {
+    if a < 0 { (a - b + 1) / b } else { a / b }
+}
+
+fn day(date: i64) -> i64 {
+    div_floor(date, 86400)
+}
+
+// The epoch was on a Thursday, so this makes weeks start on Monday.
+fn week(date: i64) -> i64 {
+    div_floor(day(date) + 3, 7)
+}
+
+// The months since the start of year 0, using the conversion from days to
+// the civil calendar from http://howardhinnant.github.io/date_algorithms.html
+fn month(date: i64) -> i64 {
+    let z = day(date) + 719468;
+    let era = div_floor(z, 146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let (year, month) = if mp < 10 {
+        (yoe + era * 400, mp + 3)
+    } else {
+        (yoe + era * 400 + 1, mp - 9)
+    };
+    year * 12 + month - 1
+}
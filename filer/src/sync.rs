@@ -0,0 +1,192 @@
+// Copying backups between pools.
+
+use cas::{Chunk, HashAlg, Oid};
+use cas::pool::{ChunkRead, ChunkSource, ChunkWrite};
+use decode::{children, indirect_level};
+use props::{Decode, Props};
+use Result;
+use std::collections::HashSet;
+
+/// The result of a sync.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SyncReport {
+    /// The number of backups copied.
+    pub backups: u64,
+    /// The number of backups the destination had already seen.
+    pub skipped: u64,
+    /// The number of chunks copied.
+    pub chunks: u64,
+    /// The number of bytes of data in the chunks copied.
+    pub bytes: u64,
+}
+
+// Chunks are added to the destination only after every chunk they refer to
+// is already there, so a chunk that is present has everything reachable
+// from it present as well, and whatever is found in the destination isn't
+// walked any further.  The copying is committed every `COMMIT_BYTES`, and
+// then started again from the backup, which skips everything already
+// committed.  A sync that is interrupted only loses the work since the
+// last commit.
+const COMMIT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Copy every backup in `src` to `dest`, other than the ones `dest` has
+/// already seen from `src`.  Only the chunks `dest` is missing are copied.
+/// Each backup is recorded as seen once it is copied, so that it isn't
+/// copied again, even if it is later forgotten in `dest`.  The pools can be
+/// of different types, but must use the same hash.
+pub fn sync(src: &ChunkRead, dest: &mut ChunkSource) -> Result<SyncReport> {
+    let peer = src.uuid().clone();
+    let seen: HashSet<Oid> = try!(dest.seen(&peer)).into_iter().collect();
+
+    let mut report = SyncReport::default();
+    for oid in try!(src.backups()) {
+        if seen.contains(&oid) {
+            report.skipped += 1;
+            continue;
+        }
+        try!(sync_backup(src, dest, &oid, &mut report));
+        try!(dest.add_seen(&peer, &[oid]));
+        report.backups += 1;
+    }
+    Ok(report)
+}
+
+/// Copy a single backup from `src` to `dest`, adding its chunks to
+/// `report`.
+pub fn sync_backup(src: &ChunkRead,
+                   dest: &mut ChunkSource,
+                   oid: &Oid,
+                   report: &mut SyncReport)
+                   -> Result<()> {
+    let mut copier = Copier {
+        src: src,
+        alg: src.hash_alg(),
+        added: 0,
+        report: report,
+    };
+    loop {
+        copier.added = 0;
+        let done = {
+            let mut wr = try!(dest.writer());
+            let done = try!(copier.backup(&mut *wr, oid));
+            try!(wr.commit());
+            done
+        };
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+// Each of the copying functions returns true once the chunk is in the
+// destination, or false if copying stopped to commit first.
+struct Copier<'a> {
+    src: &'a ChunkRead,
+    alg: HashAlg,
+    // The bytes added since the last commit.
+    added: u64,
+    report: &'a mut SyncReport,
+}
+
+impl<'a> Copier<'a> {
+    // Is the chunk already in the destination, or is it time to commit?
+    // Returns the answer to the first, or None for the second.
+    fn present(&self, wr: &ChunkWrite, oid: &Oid) -> Result<Option<bool>> {
+        if try!(wr.reader().contains_key(oid)) {
+            Ok(Some(true))
+        } else if self.added >= COMMIT_BYTES {
+            Ok(None)
+        } else {
+            Ok(Some(false))
+        }
+    }
+
+    fn backup(&mut self, wr: &mut ChunkWrite, oid: &Oid) -> Result<bool> {
+        match try!(self.present(wr, oid)) {
+            Some(true) => return Ok(true),
+            Some(false) => (),
+            None => return Ok(false),
+        }
+        let chunk = try!(self.src.find(oid));
+        let props = try!(read_props(&chunk));
+        if !try!(self.node(wr, &try!(props.oid("hash", self.alg)))) {
+            return Ok(false);
+        }
+        self.add(wr, &chunk)
+    }
+
+    fn node(&mut self, wr: &mut ChunkWrite, oid: &Oid) -> Result<bool> {
+        match try!(self.present(wr, oid)) {
+            Some(true) => return Ok(true),
+            Some(false) => (),
+            None => return Ok(false),
+        }
+        let chunk = try!(self.src.find(oid));
+        let props = try!(read_props(&chunk));
+        if props.kind == "DIR" {
+            let listing = try!(props.oid("children", self.alg));
+            if !try!(wr.reader().contains_key(&listing)) {
+                let mut buf = vec![];
+                try!(self.read_data(&listing, &mut buf));
+                for ent in try!((&buf[..]).read_dir(self.alg)) {
+                    if !try!(self.node(wr, &ent.oid)) {
+                        return Ok(false);
+                    }
+                }
+                if !try!(self.data(wr, &listing)) {
+                    return Ok(false);
+                }
+            }
+        } else if props.kind == "REG" {
+            if !try!(self.data(wr, &try!(props.oid("data", self.alg)))) {
+                return Ok(false);
+            }
+        }
+        self.add(wr, &chunk)
+    }
+
+    fn data(&mut self, wr: &mut ChunkWrite, oid: &Oid) -> Result<bool> {
+        match try!(self.present(wr, oid)) {
+            Some(true) => return Ok(true),
+            Some(false) => (),
+            None => return Ok(false),
+        }
+        let chunk = try!(self.src.find(oid));
+        if indirect_level(chunk.kind()).is_some() {
+            for child in try!(children(&chunk)) {
+                if !try!(self.data(wr, &child)) {
+                    return Ok(false);
+                }
+            }
+        }
+        self.add(wr, &chunk)
+    }
+
+    fn add(&mut self, wr: &mut ChunkWrite, chunk: &Chunk) -> Result<bool> {
+        try!(wr.add(chunk));
+        let len = chunk.data_len() as u64;
+        self.added += len;
+        self.report.chunks += 1;
+        self.report.bytes += len;
+        Ok(true)
+    }
+
+    // Append all of the data stored at `oid` in the source to `buf`.
+    fn read_data(&self, oid: &Oid, buf: &mut Vec<u8>) -> Result<()> {
+        let chunk = try!(self.src.find(oid));
+        if indirect_level(chunk.kind()).is_some() {
+            for child in try!(children(&chunk)) {
+                try!(self.read_data(&child, buf));
+            }
+        } else {
+            buf.extend_from_slice(&try!(chunk.try_into_bytes()));
+        }
+        Ok(())
+    }
+}
+
+fn read_props(chunk: &Chunk) -> Result<Props> {
+    let data = try!(chunk.try_data());
+    let props = try!((&data[..]).read_props());
+    Ok(props)
+}
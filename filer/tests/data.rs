@@ -4,12 +4,12 @@ use std::io;
 
 use cas::Oid;
 use cas::pool::RamPool;
-use cas::pool::ChunkSource;
+use cas::pool::{ChunkRead, ChunkSource};
 use filer::data::DataWrite;
 
 use rand::isaac::IsaacRng;
 use rand::Rng;
-use std::cell::RefCell;
+use std::sync::Mutex;
 
 extern crate cas;
 extern crate filer;
@@ -21,16 +21,16 @@ extern crate log;
 fn indirection() {
     let limit = 1 * 1024 * 1024 + 136;
 
-    let mut pool = RefCell::new(RamPool::new());
+    let mut pool = RamPool::new();
     let top;
     {
-        pool.borrow_mut().begin_writing().unwrap();
+        let sink = Mutex::new(pool.writer().unwrap());
         {
             let mut rd = FakeRead::new(limit);
-            let mut wr = DataWrite::new_limit(&mut pool, 256 * 1024);
+            let mut wr = DataWrite::new_limit(&sink, 256 * 1024);
             top = wr.write(&mut rd).unwrap();
         }
-        pool.borrow_mut().flush().unwrap();
+        sink.into_inner().unwrap().commit().unwrap();
     }
 
     // Read it back and make sure it is ok.
@@ -40,13 +40,37 @@ fn indirection() {
     }
 }
 
+// Writing with worker threads gives the same result as writing serially.
+#[test]
+fn parallel() {
+    let limit = 3 * 1024 * 1024 + 136;
+
+    let mut pool = RamPool::new();
+    let mut tops = vec![];
+    {
+        let sink = Mutex::new(pool.writer().unwrap());
+        for &workers in &[0, 1, 4] {
+            let mut rd = FakeRead::new(limit);
+            let mut wr = DataWrite::new_limit(&sink, 256 * 1024).set_workers(workers);
+            tops.push(wr.write(&mut rd).unwrap());
+        }
+        sink.into_inner().unwrap().commit().unwrap();
+    }
+
+    assert_eq!(tops[0], tops[1]);
+    assert_eq!(tops[0], tops[2]);
+
+    let mut w = Walker::new(&pool, limit);
+    w.walk(&tops[2]).unwrap();
+}
+
 struct Walker<'a> {
     reader: FakeRead,
-    pool: &'a RefCell<ChunkSource>,
+    pool: &'a ChunkRead,
 }
 
 impl<'a> Walker<'a> {
-    fn new<'b>(pool: &'b RefCell<ChunkSource>, limit: usize) -> Walker<'b> {
+    fn new<'b>(pool: &'b ChunkRead, limit: usize) -> Walker<'b> {
         Walker {
             reader: FakeRead::new(limit),
             pool: pool,
@@ -58,7 +82,7 @@ impl<'a> Walker<'a> {
         use filer::decode::Node;
         use std::io::prelude::*;
 
-        let ch = try!(self.pool.borrow().find(oid));
+        let ch = try!(self.pool.find(oid));
         trace!("Chunk: {}", ch.oid().to_hex());
         match try!(decode(ch)) {
             Node::Blob(data) => {
@@ -0,0 +1,146 @@
+// Test the reachability walk.
+
+use byteorder::{BigEndian, WriteBytesExt};
+use cas::{Chunk, Kind, Oid};
+use cas::pool::RamPool;
+use cas::pool::{ChunkRead, ChunkSource, ChunkWrite};
+use filer::data::DataWrite;
+use filer::decode::{decode, Node};
+use filer::reach;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+extern crate byteorder;
+extern crate cas;
+extern crate filer;
+
+#[test]
+fn live() {
+    let mut pool = RamPool::new();
+    let data: Vec<u8> = (0..5000).map(|i| (i * 7 % 251) as u8).collect();
+
+    let (back, top, garbage) = {
+        let sink = Mutex::new(pool.writer().unwrap());
+        // Small enough chunks that the data needs two levels of indirect
+        // chunks.
+        let top = DataWrite::new_limit(&sink, 256).write(&mut &data[..]).unwrap();
+
+        let (back, garbage) = {
+            let mut wr = sink.lock().unwrap();
+            let file = add(&mut **wr, "node", props("REG", &[("data", &top)]));
+            let mut listing = vec![];
+            put_string2(&mut listing, "file");
+            listing.extend_from_slice(file.as_bytes());
+            let children = add(&mut **wr, "dir ", listing);
+            let root = add(&mut **wr, "node", props("DIR", &[("children", &children)]));
+            let back = add(&mut **wr, "back", props("back", &[("hash", &root)]));
+
+            let garbage = add(&mut **wr, "node", props("LNK", &[("data", &top)]));
+            (back, garbage)
+        };
+        sink.into_inner().unwrap().commit().unwrap();
+        (back, top, garbage)
+    };
+
+    let live = reach::live_chunks(&pool).unwrap();
+    assert!(live.contains(&back));
+    assert!(!live.contains(&garbage));
+
+    // The backup, its two nodes and the listing, and all of the data.
+    let mut data_oids = HashSet::new();
+    collect(&pool, &top, &mut data_oids);
+    assert!(data_oids.len() > 20);
+    assert!(data_oids.iter().all(|oid| live.contains(oid)));
+    assert_eq!(live.len(), data_oids.len() + 5);
+}
+
+// A file whose contents are the same as a directory listing doesn't keep
+// the entries of that listing from being walked.
+#[test]
+fn file_like_listing() {
+    let mut pool = RamPool::new();
+    let (leaf, leaf_data) = {
+        let mut wr = pool.writer().unwrap();
+        let leaf_data = add(&mut *wr, "blob", b"leaf data".to_vec());
+        let leaf = add(&mut *wr, "node", props("REG", &[("data", &leaf_data)]));
+
+        // The inner listing, and a file holding exactly its bytes.
+        let mut listing = vec![];
+        put_string2(&mut listing, "leaf");
+        listing.extend_from_slice(leaf.as_bytes());
+        let inner = add(&mut *wr, "blob", listing);
+        let file = add(&mut *wr, "node", props("REG", &[("data", &inner)]));
+        let subdir = add(&mut *wr, "node", props("DIR", &[("children", &inner)]));
+
+        // The file comes first, so its data is marked before the
+        // directory is reached.
+        let mut listing = vec![];
+        put_string2(&mut listing, "a");
+        listing.extend_from_slice(file.as_bytes());
+        put_string2(&mut listing, "b");
+        listing.extend_from_slice(subdir.as_bytes());
+        let children = add(&mut *wr, "blob", listing);
+        let root = add(&mut *wr, "node", props("DIR", &[("children", &children)]));
+        add(&mut *wr, "back", props("back", &[("hash", &root)]));
+        wr.commit().unwrap();
+        (leaf, leaf_data)
+    };
+
+    let live = reach::live_chunks(&pool).unwrap();
+    assert!(live.contains(&leaf));
+    assert!(live.contains(&leaf_data));
+    // Every chunk written is reachable.
+    assert_eq!(live.len(), 8);
+}
+
+// A reference to a missing chunk fails the walk.
+#[test]
+fn missing() {
+    let mut pool = RamPool::new();
+    {
+        let mut wr = pool.writer().unwrap();
+        let root = Oid::from_u32(1);
+        add(&mut *wr, "back", props("back", &[("hash", &root)]));
+        wr.commit().unwrap();
+    }
+    assert!(reach::live_chunks(&pool).is_err());
+}
+
+fn add(wr: &mut ChunkWrite, kind: &str, data: Vec<u8>) -> Oid {
+    let chunk = Chunk::new_plain(Kind::new(kind).unwrap(), data);
+    wr.add(&chunk).unwrap();
+    chunk.oid().clone()
+}
+
+fn props(kind: &str, values: &[(&str, &Oid)]) -> Vec<u8> {
+    let mut buf = vec![];
+    put_string1(&mut buf, kind);
+    for &(key, oid) in values {
+        put_string1(&mut buf, key);
+        put_string2(&mut buf, &oid.to_hex());
+    }
+    buf
+}
+
+fn put_string1(buf: &mut Vec<u8>, text: &str) {
+    buf.push(text.len() as u8);
+    buf.extend_from_slice(text.as_bytes());
+}
+
+fn put_string2(buf: &mut Vec<u8>, text: &str) {
+    buf.write_u16::<BigEndian>(text.len() as u16).unwrap();
+    buf.extend_from_slice(text.as_bytes());
+}
+
+// Every chunk of the data stored at `oid`.
+fn collect(pool: &ChunkRead, oid: &Oid, oids: &mut HashSet<Oid>) {
+    oids.insert(oid.clone());
+    match decode(pool.find(oid).unwrap()).unwrap() {
+        Node::Blob(_) => (),
+        Node::Indirect { children, .. } => {
+            for child in &children {
+                collect(pool, child, oids);
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+// Test retention of backups.
+
+use cas::{Chunk, Kind, Oid};
+use cas::pool::RamPool;
+use cas::pool::{ChunkRead, ChunkSource};
+use filer::retain::{self, Retention};
+
+extern crate cas;
+extern crate filer;
+
+// 2016-06-15 12:00:00 UTC, a Wednesday.
+const START: i64 = 1465992000;
+
+#[test]
+fn expired() {
+    // A backup every day, for 400 days.
+    let backups: Vec<(Oid, Option<i64>)> = (0..400)
+        .map(|i| (Oid::from_u32(i), Some(START + i as i64 * 86400)))
+        .collect();
+    let rule = Retention {
+        daily: 7,
+        weekly: 4,
+        monthly: 12,
+    };
+    let expired = rule.expired(&backups);
+
+    // The last 7 days, the Sundays ending the 3 weeks before, and the last
+    // day of each of the 11 months before.
+    let kept = [77, 107, 138, 168, 199, 230, 258, 289, 319, 350, 380, 382, 389, 393, 394, 395,
+                396, 397, 398, 399];
+    assert_eq!(expired.len(), 400 - kept.len());
+    for i in 0..400 {
+        let oid = Oid::from_u32(i);
+        assert_eq!(expired.contains(&oid), !kept.contains(&i));
+    }
+
+    // Nothing is kept without a rule, other than backups with no date.
+    let mut backups = backups;
+    backups.push((Oid::from_u32(400), None));
+    let expired = Retention::default().expired(&backups);
+    assert_eq!(expired.len(), 400);
+    assert!(!expired.contains(&Oid::from_u32(400)));
+}
+
+#[test]
+fn apply() {
+    let mut pool = RamPool::new();
+    let backs: Vec<Chunk> = vec![back(Some(START)),
+                                 back(Some(START + 86400)),
+                                 back(Some(START + 2 * 86400)),
+                                 back(None)];
+    {
+        let mut wr = pool.writer().unwrap();
+        for ch in &backs {
+            wr.add(ch).unwrap();
+        }
+        wr.commit().unwrap();
+    }
+
+    let rule = Retention { daily: 1, ..Retention::default() };
+    let mut forgotten = retain::apply(&mut pool, &rule).unwrap();
+    forgotten.sort();
+    let mut expected = vec![backs[0].oid().clone(), backs[1].oid().clone()];
+    expected.sort();
+    assert_eq!(forgotten, expected);
+
+    let mut left = pool.backups().unwrap();
+    left.sort();
+    let mut expected = vec![backs[2].oid().clone(), backs[3].oid().clone()];
+    expected.sort();
+    assert_eq!(left, expected);
+}
+
+// A backup chunk, with the given date.
+fn back(date: Option<i64>) -> Chunk {
+    let mut data = vec![];
+    put(&mut data, "back", 1);
+    put(&mut data, "hash", 1);
+    put(&mut data, &Oid::from_u32(1).to_hex(), 2);
+    if let Some(date) = date {
+        put(&mut data, "_date", 1);
+        put(&mut data, &format!("{}.25", date), 2);
+    }
+    Chunk::new_plain(Kind::new("back").unwrap(), data)
+}
+
+// Append a string, with its length in `width` bytes (big endian).
+fn put(buf: &mut Vec<u8>, text: &str, width: usize) {
+    if width == 2 {
+        buf.push((text.len() >> 8) as u8);
+    }
+    buf.push(text.len() as u8);
+    buf.extend_from_slice(text.as_bytes());
+}
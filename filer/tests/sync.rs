@@ -0,0 +1,132 @@
+// Test copying backups between pools.
+
+use byteorder::{BigEndian, WriteBytesExt};
+use cas::{Chunk, Kind, Oid};
+use cas::pool::{AdumpPool, FilePool, RamPool};
+use cas::pool::{ChunkRead, ChunkSource, ChunkWrite};
+use filer::data::DataWrite;
+use filer::reach;
+use filer::sync;
+use std::sync::Mutex;
+use tempdir::TempDir;
+
+extern crate byteorder;
+extern crate cas;
+extern crate filer;
+extern crate tempdir;
+
+#[test]
+fn ram_to_adump() {
+    let tmp = TempDir::new("sync").unwrap();
+    let path = tmp.path().join("pool");
+    AdumpPool::new_builder(&path).create().unwrap();
+
+    let mut src = RamPool::new();
+    let second = check_sync(&mut src, &mut AdumpPool::open(&path).unwrap());
+
+    // What has been seen is kept in the pool.
+    let dest = AdumpPool::open_readonly(&path).unwrap();
+    assert_eq!(dest.seen(src.uuid()).unwrap().len(), 2);
+    assert_eq!(dest.backups().unwrap(), vec![second]);
+}
+
+#[test]
+fn ram_to_file() {
+    let tmp = TempDir::new("sync").unwrap();
+    let path = tmp.path().join("pool");
+    FilePool::create(&path).unwrap();
+
+    let mut src = RamPool::new();
+    let second = check_sync(&mut src, &mut FilePool::open(&path).unwrap());
+
+    let dest = FilePool::open_readonly(&path).unwrap();
+    assert_eq!(dest.seen(src.uuid()).unwrap().len(), 2);
+    assert_eq!(dest.backups().unwrap(), vec![second]);
+
+    // Syncing on from the file pool copies its live backup, and marks it
+    // seen in the destination.
+    let mut ram = RamPool::new();
+    let report = sync::sync(&dest, &mut ram).unwrap();
+    assert_eq!(report.backups, 1);
+    assert_eq!(ram.backups().unwrap(), dest.backups().unwrap());
+    assert_eq!(ram.seen(dest.uuid()).unwrap(), dest.backups().unwrap());
+    assert_eq!(reach::live_chunks(&ram).unwrap(), reach::live_chunks(&dest).unwrap());
+}
+
+// Sync two backups from `src` into the empty `dest`, forgetting the first
+// once both are copied, and return the second.
+fn check_sync<P: ChunkSource>(src: &mut RamPool, dest: &mut P) -> Oid {
+    let first = add_backup(src, 1);
+    let report = sync::sync(src, dest).unwrap();
+    assert_eq!(report.backups, 1);
+    assert_eq!(report.skipped, 0);
+    assert_eq!(dest.backups().unwrap(), vec![first.clone()]);
+    assert_eq!(reach::live_chunks(dest).unwrap(), reach::live_chunks(src).unwrap());
+    assert_eq!(report.chunks as usize, reach::live_chunks(src).unwrap().len());
+
+    // A second backup shares its file and its data with the first, which
+    // aren't copied again.
+    let second = add_backup(src, 2);
+    let report = sync::sync(src, dest).unwrap();
+    assert_eq!(report.backups, 1);
+    assert_eq!(report.skipped, 1);
+    assert_eq!(report.chunks, 3);
+    assert_eq!(reach::live_chunks(dest).unwrap(), reach::live_chunks(src).unwrap());
+
+    // Backups that have been seen aren't copied again, even once they
+    // have been forgotten.
+    dest.forget(&[first.clone()]).unwrap();
+    let report = sync::sync(src, dest).unwrap();
+    assert_eq!(report.backups, 0);
+    assert_eq!(report.skipped, 2);
+    assert_eq!(report.chunks, 0);
+    assert_eq!(dest.backups().unwrap(), vec![second.clone()]);
+    second
+}
+
+// Add a backup of a directory holding a single file to `pool`.  The file's
+// data is the same for every backup, and the backups differ by `num`.
+fn add_backup(pool: &mut RamPool, num: u32) -> Oid {
+    let data: Vec<u8> = (0..5000).map(|i| (i * 7 % 251) as u8).collect();
+
+    let sink = Mutex::new(pool.writer().unwrap());
+    let top = DataWrite::new_limit(&sink, 256).write(&mut &data[..]).unwrap();
+    let back = {
+        let mut wr = sink.lock().unwrap();
+        let num = num.to_string();
+        let file = add(&mut **wr, "node", props("REG", &[("data", &top.to_hex())]));
+        let mut listing = vec![];
+        put_string2(&mut listing, &format!("file{}", num));
+        listing.extend_from_slice(file.as_bytes());
+        let children = add(&mut **wr, "dir ", listing);
+        let root = add(&mut **wr, "node", props("DIR", &[("children", &children.to_hex())]));
+        add(&mut **wr,
+            "back",
+            props("back", &[("hash", &root.to_hex()), ("num", &num)]))
+    };
+    sink.into_inner().unwrap().commit().unwrap();
+    back
+}
+
+fn add(wr: &mut ChunkWrite, kind: &str, data: Vec<u8>) -> Oid {
+    let chunk = Chunk::new_plain(Kind::new(kind).unwrap(), data);
+    wr.add(&chunk).unwrap();
+    chunk.oid().clone()
+}
+
+fn props(kind: &str, values: &[(&str, &str)]) -> Vec<u8> {
+    let mut buf = vec![];
+    buf.push(kind.len() as u8);
+    buf.extend_from_slice(kind.as_bytes());
+    for &(key, value) in values {
+        buf.push(key.len() as u8);
+        buf.extend_from_slice(key.as_bytes());
+        put_string2(&mut buf, value);
+    }
+    buf
+}
+
+fn put_string2(buf: &mut Vec<u8>, text: &str) {
+    buf.write_u16::<BigEndian>(text.len() as u16).unwrap();
+    buf.extend_from_slice(text.as_bytes());
+}